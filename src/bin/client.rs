@@ -1,14 +1,265 @@
-use repyh_proof_of_work::{Message, Transaction, Transactions};
+use repyh_proof_of_work::{
+    Address, B58Encode, Block, Hash, Hashable, HexEncode, Message, Transaction, Transactions,
+    GENESIS_TX,
+};
 use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::io;
 
+// TODO: the client only proposes dummy transactions, always with the nonces 1 to 10, so nodes
+//  reject them as replays once committed. To send real ones with a [Wallet], the client needs to
+//  follow a node's committed blocks, which nodes only announce to their peers.
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let nodes: Vec<SocketAddr> = std::env::args().filter_map(|s| s.parse().ok()).collect();
+    let args: Vec<String> = std::env::args().collect();
+    let nodes: Vec<SocketAddr> = args.iter().filter_map(|s| s.parse().ok()).collect();
 
-    Message::Tx(Transactions(Transaction::dummy_txs(10)))
-        .broadcast(nodes.iter())
-        .await?;
+    // `--verify-tx=<hex>` checks a raw transaction offline, without any node
+    if let Some(raw) = args.iter().find_map(|a| a.strip_prefix("--verify-tx=")) {
+        match Transaction::from_hex(raw) {
+            Ok(tx) => println!(
+                "Transaction {}: structure {}, signature {}",
+                tx.hash().encode(),
+                if is_well_formed(&tx) { "ok" } else { "invalid" },
+                if tx.verify_signature() {
+                    "valid"
+                } else {
+                    "invalid"
+                }
+            ),
+            Err(e) => println!("Not a transaction: {:?}", e),
+        }
+        return Ok(());
+    }
+
+    // `--import=<file>` asks the (local) nodes to import a saved chain instead
+    if let Some(file) = args.iter().find_map(|a| a.strip_prefix("--import=")) {
+        let request = Message::Import(Path::new(file).canonicalize()?);
+        for node in &nodes {
+            match request.request(node).await? {
+                Message::Imported(Ok((tip, height))) => println!(
+                    "{} imported {}, its main chain now ends at {} (height {})",
+                    node,
+                    file,
+                    tip.encode(),
+                    height - 1
+                ),
+                Message::Imported(Err(e)) => println!("{} failed to import {}: {}", node, file, e),
+                other => println!("{} answered unexpectedly: {:?}", node, other),
+            }
+        }
+        return Ok(());
+    }
+
+    // `--balances=<address>,...` prints the balances of the (base58) addresses
+    if let Some(list) = args.iter().find_map(|a| a.strip_prefix("--balances=")) {
+        let addresses = (list.split(','))
+            .map(|address| {
+                let bytes = bs58::decode(address).into_vec().unwrap_or_default();
+                Address::try_from(bytes).map_err(invalid_input)
+            })
+            .collect::<io::Result<Vec<Address>>>()?;
+        let request = Message::GetBalances(addresses.clone());
+        for node in &nodes {
+            match request.request(node).await? {
+                Message::Balances(balances) => {
+                    for (address, balance) in addresses.iter().zip(balances) {
+                        println!("{}: {} has {}", node, address.encode(), balance);
+                    }
+                }
+                other => println!("{} answered unexpectedly: {:?}", node, other),
+            }
+        }
+        return Ok(());
+    }
+
+    // `--mine-block` mines a (test) block on the first node's tip and proposes it to all nodes
+    if args.iter().any(|a| a == "--mine-block") {
+        let Some(node) = nodes.first() else {
+            return Err(io::Error::other("no node to mine on"));
+        };
+        let Message::Tip {
+            hash,
+            timestamp,
+            difficulty,
+            ..
+        } = Message::GetTip.request(node).await?
+        else {
+            return Err(io::Error::other(format!(
+                "{} didn't answer with its tip",
+                node
+            )));
+        };
+        let block = mine_on_tip(hash, timestamp, difficulty);
+        println!(
+            "Mined {} at difficulty {}",
+            block.hash().encode(),
+            difficulty
+        );
+        if let Err(failures) = Message::NewBlock(block).broadcast(nodes.iter()).await {
+            for (node, e) in failures {
+                println!("Failed to propose the block to {}: {}", node, e);
+            }
+        }
+        return Ok(());
+    }
+
+    // `--difficulty-history` prints the difficulty of each of the nodes' main-chain blocks
+    if args.iter().any(|a| a == "--difficulty-history") {
+        for node in &nodes {
+            match Message::GetDifficultyHistory.request(node).await? {
+                Message::DifficultyHistory(history) => {
+                    for (height, difficulty) in history {
+                        println!("{}: block {} at difficulty {}", node, height, difficulty);
+                    }
+                }
+                other => println!("{} answered unexpectedly: {:?}", node, other),
+            }
+        }
+        return Ok(());
+    }
+
+    // `--sync` prints how far the nodes got syncing their last batch of blocks
+    if args.iter().any(|a| a == "--sync") {
+        for node in &nodes {
+            match Message::GetSyncProgress.request(node).await? {
+                Message::SyncProgress(progress) => println!(
+                    "{} synced {} of {} blocks at {:.1} blocks/s, {}s left",
+                    node,
+                    progress.current,
+                    progress.target,
+                    progress.blocks_per_sec,
+                    progress
+                        .eta_secs
+                        .map_or("?".to_string(), |s| format!("{:.0}", s))
+                ),
+                other => println!("{} answered unexpectedly: {:?}", node, other),
+            }
+        }
+        return Ok(());
+    }
+
+    // `--block=<hex hash>` and `--tx=<hex hash>` print the raw block or transaction, as hex
+    if let Some(hash) = args.iter().find_map(|a| a.strip_prefix("--block=")) {
+        let request = Message::GetBlock(parse_hash(hash)?);
+        for node in &nodes {
+            match request.request(node).await? {
+                Message::BlockFound(Ok(Some(block))) => println!("{}: {}", node, block.to_hex()),
+                Message::BlockFound(Ok(None)) => println!("{} doesn't know block {}", node, hash),
+                Message::BlockFound(Err(e)) => println!("{} pruned block {}: {}", node, hash, e),
+                other => println!("{} answered unexpectedly: {:?}", node, other),
+            }
+        }
+        return Ok(());
+    }
+    if let Some(hash) = args.iter().find_map(|a| a.strip_prefix("--tx=")) {
+        let request = Message::GetTransaction(parse_hash(hash)?);
+        for node in &nodes {
+            match request.request(node).await? {
+                Message::TransactionFound(Some((tx, Some(height)))) => {
+                    println!("{} (height {}): {}", node, height - 1, tx.to_hex())
+                }
+                Message::TransactionFound(Some((tx, None))) => {
+                    println!("{} (pending): {}", node, tx.to_hex())
+                }
+                Message::TransactionFound(None) => {
+                    println!("{} doesn't know transaction {}", node, hash)
+                }
+                other => println!("{} answered unexpectedly: {:?}", node, other),
+            }
+        }
+        return Ok(());
+    }
+
+    // `--send-raw=<hex>` proposes a raw transaction, e.g. signed by another tool
+    if let Some(raw) = args.iter().find_map(|a| a.strip_prefix("--send-raw=")) {
+        let tx = Transaction::from_hex(raw).map_err(invalid_input)?;
+        let proposal = Message::Tx(Transactions(vec![tx]));
+        if let Err(failures) = proposal.broadcast(nodes.iter()).await {
+            for (node, e) in failures {
+                println!("Failed to propose the transaction to {}: {}", node, e);
+            }
+        }
+        return Ok(());
+    }
+
+    let proposal = Message::Tx(Transactions(Transaction::signed_dummy_txs(10)));
+    if let Err(failures) = proposal.broadcast(nodes.iter()).await {
+        for (node, e) in failures {
+            println!("Failed to propose transactions to {}: {}", node, e);
+        }
+    }
     println!("Done proposing transactions to {:?}", nodes);
     Ok(())
 }
+
+/// Mines a block with only its coinbase on top of the given tip, at the difficulty the next
+/// block must have. The reward goes to the test key receiving the [GENESIS_TX], like for nodes.
+fn mine_on_tip(tip: Hash, tip_timestamp: u64, difficulty: u32) -> Block {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Current time is after unix epoch")
+        .as_secs();
+    // blocks must be strictly later than their parent
+    let time = now.max(tip_timestamp + 1);
+    let miner = *GENESIS_TX.receiver();
+    Block::mine_at(tip, difficulty, Transactions(vec![]), miner, time)
+}
+
+/// Whether the transaction could be proposed to nodes regardless of the chain: only miners
+/// create "coin" (see [Transaction::coinbase]) and nonces start at 1.
+fn is_well_formed(tx: &Transaction) -> bool {
+    !tx.is_coinbase() && tx.nonce() > 0
+}
+
+fn parse_hash(hex: &str) -> io::Result<Hash> {
+    Hash::from_hex(hex).map_err(invalid_input)
+}
+
+fn invalid_input(e: impl std::fmt::Debug) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{is_well_formed, mine_on_tip};
+    use repyh_proof_of_work::{
+        AddResult, BlockChain, ChainParams, Hashable, HexEncode, Transaction, GENESIS_TX,
+    };
+
+    #[test]
+    fn verify_raw_tx() {
+        let tx = Transaction::signed_dummy_txs(1).remove(0);
+        let decoded = Transaction::from_hex(&tx.to_hex()).unwrap();
+        assert!(is_well_formed(&decoded) && decoded.verify_signature());
+
+        // raising the fee after signing invalidates the signature, not the structure
+        let tampered = Transaction::from_hex(&tx.with_fee(5).to_hex()).unwrap();
+        assert!(is_well_formed(&tampered) && !tampered.verify_signature());
+        // only miners create "coin"
+        let coinbase = Transaction::from_hex(&GENESIS_TX.to_hex()).unwrap();
+        assert!(!is_well_formed(&coinbase));
+    }
+
+    #[test]
+    fn mined_block_is_accepted() {
+        // with proof-of-work, which TESTING skips
+        let params = ChainParams {
+            difficulty: 8,
+            ..ChainParams::TESTING
+        };
+        let mut chain = BlockChain::with_params(params);
+        for _ in 0..3 {
+            // like a node answering a tip request
+            let tip = chain.highest_block();
+            let difficulty = chain.difficulty_for_height(chain.main_chain_length());
+            let block = mine_on_tip(tip.hash(), tip.header.timestamp(), difficulty);
+            assert_eq!(block.header.difficulty(), difficulty);
+            assert_eq!(
+                chain.add_block(&block),
+                Ok(AddResult::New(vec![block.hash()]))
+            );
+        }
+    }
+}
@@ -1,12 +1,22 @@
 use crate::MiningCommand::{Keep, Restart, Start};
+use ed25519_dalek::VerifyingKey;
 use repyh_proof_of_work::*;
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::future::Future;
 use std::io;
+use std::io::ErrorKind;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, RwLock};
 use tokio::task;
 use tokio::task::JoinHandle;
 
@@ -14,14 +24,222 @@ use tokio::task::JoinHandle;
 pub struct Node {
     /// The node's own address
     address: SocketAddr,
-    /// The known network peers
-    peers: HashSet<SocketAddr>,
-    /// Transactions proposed for inclusion in a block
-    mempool: HashMap<Hash, Transaction>,
+    /// The peers given at startup, used to re-join the network if all peers are lost
+    seeds: Vec<SocketAddr>,
+    /// The known network peers.
+    /// Ordered, such that the peers we announce on the wire are deterministic.
+    peers: BTreeSet<SocketAddr>,
+    /// When each peer was last announced, as a count of sightings, see [Node::add_peer]
+    last_seen: HashMap<SocketAddr, u64>,
+    /// The number of peer sightings so far, a logical clock for [Node::last_seen]
+    sightings: u64,
+    /// How many broadcasts in a row failed to reach each peer, see [Node::record_broadcast]
+    failures: HashMap<SocketAddr, u32>,
+    /// Peers that are never added (again), see [Node::ban_peer]
+    banned: BTreeSet<SocketAddr>,
+    /// How many over-limit messages each host sent, see [Node::record_oversized]
+    oversized: HashMap<IpAddr, u32>,
+    /// When each host announced its last [BURST_BLOCKS] blocks (in seconds since the unix
+    /// epoch), see [Node::record_block_arrival]
+    block_arrivals: HashMap<IpAddr, VecDeque<u64>>,
+    /// How many blocks each host announced that look withheld, see [Node::record_block_arrival]
+    withholding_suspicions: HashMap<IpAddr, u32>,
+    /// Transactions proposed for inclusion in a block.
+    /// Ordered by hash, such that the transactions selected for mining are deterministic.
+    // TODO: only transactions with their spender's next nonce are accepted, so none waits for a
+    //  missing predecessor. To accept such orphan transactions, they need a per-spender and a
+    //  global limit (evicting the oldest), otherwise they can be spammed to fill the memory.
+    mempool: BTreeMap<Hash, Transaction>,
+    /// The maximum number of transactions in the mempool, see [MAX_MEMPOOL]
+    max_mempool: usize,
+    /// The maximum serialized size of all transactions in the mempool, see [MAX_MEMPOOL_BYTES]
+    max_mempool_bytes: usize,
+    /// The serialized size of all transactions in the mempool, see [Node::mempool_bytes]
+    pending_bytes: usize,
+    /// When each transaction in the mempool was added (in seconds since the unix epoch),
+    /// see [Node::is_transaction_stuck]
+    received: HashMap<Hash, u64>,
     /// The local copy of the blockchain
     chain: BlockChain,
+    /// The address the coinbase of blocks mined by this node pays the reward to
+    miner: Address,
+    /// The message the coinbase of blocks mined by this node carries, see [COINBASE_DATA_FLAG]
+    coinbase_data: Vec<u8>,
+    /// The keys whose checkpoints the node adopts, see [Message::Checkpoint]
+    trusted_keys: Vec<VerifyingKey>,
+    /// Whether synchronised blocks are trusted to have a valid proof-of-work, see
+    /// [TRUST_PEERS_FLAG]
+    trust_peers_pow: bool,
+    /// Publishes every change of the mempool to its subscribers
+    mempool_events: broadcast::Sender<MempoolEvent>,
+    /// Publishes the hash of every new block added to the chain to its subscribers
+    block_events: broadcast::Sender<Hash>,
+    /// When this node is allowed to mine
+    policy: MiningPolicy,
+    /// What the node's mining process is doing
+    mining: MiningStatus,
+    /// Set to stop solving the current block, e.g. because a new highest block arrived
+    mining_cancelled: Arc<AtomicBool>,
+    /// Once set, stops adding synchronised blocks, see [Node::sync_canceller]
+    sync_cancelled: Arc<AtomicBool>,
+    /// How far the node got adding the last batch of synchronised blocks
+    sync_progress: SyncProgress,
+    /// Whether the node is catching up from a peer, such that further orphans don't start
+    /// another catch-up, see [Node::start_catch_up]
+    catching_up: bool,
 }
 
+/// When a node is allowed to mine.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MiningPolicy {
+    /// Only mine while connected to at least one peer. Mining alone would likely fork the
+    /// network once the node reconnects.
+    Networked,
+    /// Always mine, even without peers.
+    Solo,
+    /// Never mine, only validate and relay.
+    Observer,
+}
+
+/// Introspection of a node's mining process.
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct MiningStatus {
+    /// Whether a block is currently being mined
+    pub active: bool,
+    /// The parent of the block being (or last) mined
+    pub parent_hash: Option<Hash>,
+    /// When mining of the current (or last) block started
+    pub started_at: Option<Instant>,
+    /// The number of nonces it took to mine the last block
+    pub attempts: u64,
+}
+
+impl Debug for MiningStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "MiningStatus {{ active: {}, parent_hash: {}, running for: {:?}, attempts: {} }}",
+            self.active,
+            self.parent_hash.map_or("-".to_string(), |h| h.encode()),
+            self.started_at.map(|t| t.elapsed()),
+            self.attempts
+        )
+    }
+}
+
+/// The fees a group of transactions pays per byte of their serialized size.
+/// Compared exactly, i.e. without rounding.
+#[derive(Clone, Copy, Debug, Default)]
+struct FeeRate {
+    fees: u64,
+    bytes: u64,
+}
+
+impl FeeRate {
+    /// The fee rate of the given transactions together.
+    fn of<'a, I: IntoIterator<Item = &'a Transaction>>(txs: I) -> Self {
+        txs.into_iter().fold(FeeRate::default(), FeeRate::with)
+    }
+
+    /// This fee rate with the given transaction added to the group.
+    fn with(self, tx: &Transaction) -> Self {
+        FeeRate {
+            fees: self.fees + tx.fee() as u64,
+            bytes: self.bytes + serialized_size(tx) as u64,
+        }
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let ours = self.fees as u128 * other.bytes as u128;
+        ours.cmp(&(other.fees as u128 * self.bytes as u128))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for FeeRate {}
+
+/// What mining a group of transactions (a single one or a batch) needs, see [package].
+enum Package<'a> {
+    /// The group's ancestors that aren't taken yet and the group itself, in nonce order.
+    Ready(Vec<&'a Transaction>),
+    /// An ancestor is part of another batch, so the group needs to wait until the given
+    /// spender's transaction with the given nonce is next.
+    Waiting(Address, u64),
+    /// The group can't be mined, as an ancestor is missing or a nonce is already taken.
+    Unminable,
+}
+
+/// Collects the package of the group (sorted by nonce), given the nonce of each spender's next
+/// transaction to take and the pending transactions of each spender by nonce.
+fn package<'a, F: Fn(&Address) -> u64>(
+    group: &[&'a Transaction],
+    next: F,
+    pending: &HashMap<&Address, BTreeMap<u64, &'a Transaction>>,
+) -> Package<'a> {
+    let mut package = Vec::new();
+    let mut group_next: HashMap<&Address, u64> = HashMap::new();
+    for tx in group {
+        let spender = tx.spender();
+        let from = (group_next.get(spender).copied()).unwrap_or_else(|| next(spender));
+        if tx.nonce() < from {
+            return Package::Unminable;
+        }
+        for nonce in from..tx.nonce() {
+            match pending
+                .get(spender)
+                .and_then(|by_nonce| by_nonce.get(&nonce))
+            {
+                None => return Package::Unminable,
+                Some(ancestor) if ancestor.batch().is_some() => {
+                    return Package::Waiting(*spender, nonce + 1)
+                }
+                Some(ancestor) => package.push(*ancestor),
+            }
+        }
+        package.push(*tx);
+        group_next.insert(spender, tx.nonce() + 1);
+    }
+    Package::Ready(package)
+}
+
+/// A change of the mempool, e.g. for wallets or explorers to track pending transactions.
+#[derive(Clone, Eq, PartialEq)]
+pub enum MempoolEvent {
+    /// A new transaction was added to the mempool.
+    TxAdded(Hash),
+    /// A transaction left the mempool, e.g. because it got included in a block.
+    TxRemoved(Hash),
+}
+
+impl Debug for MempoolEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MempoolEvent::TxAdded(hash) => write!(f, "TxAdded({})", hash.encode()),
+            MempoolEvent::TxRemoved(hash) => write!(f, "TxRemoved({})", hash.encode()),
+        }
+    }
+}
+
+/// How many mempool events a slow subscriber may lag behind before missing some.
+const MEMPOOL_EVENTS_CAPACITY: usize = 1024;
+
+/// How many new blocks a slow subscriber may lag behind before missing some.
+const BLOCK_EVENTS_CAPACITY: usize = 64;
+
+#[derive(Debug, Eq, PartialEq)]
 enum MiningCommand {
     Start,
     Restart,
@@ -31,23 +249,92 @@ enum MiningCommand {
 impl Node {
     pub fn new(address: SocketAddr, peers: &[SocketAddr]) -> Self {
         Node {
-            mempool: HashMap::new(),
+            mempool: BTreeMap::new(),
+            max_mempool: MAX_MEMPOOL,
+            max_mempool_bytes: MAX_MEMPOOL_BYTES,
+            pending_bytes: 0,
+            received: HashMap::new(),
             chain: BlockChain::new(),
+            miner: *GENESIS_TX.receiver(),
+            coinbase_data: Vec::new(),
+            trusted_keys: Vec::new(),
+            trust_peers_pow: false,
             peers: peers.iter().cloned().collect(),
+            last_seen: HashMap::new(),
+            sightings: 0,
+            failures: HashMap::new(),
+            banned: BTreeSet::new(),
+            oversized: HashMap::new(),
+            block_arrivals: HashMap::new(),
+            withholding_suspicions: HashMap::new(),
+            seeds: peers.to_vec(),
             address,
+            mempool_events: broadcast::channel(MEMPOOL_EVENTS_CAPACITY).0,
+            block_events: broadcast::channel(BLOCK_EVENTS_CAPACITY).0,
+            policy: MiningPolicy::Networked,
+            mining: MiningStatus::default(),
+            mining_cancelled: Arc::new(AtomicBool::new(false)),
+            sync_cancelled: Arc::new(AtomicBool::new(false)),
+            sync_progress: SyncProgress::default(),
+            catching_up: false,
+        }
+    }
+
+    /// Creates a new node that mines according to the given policy.
+    pub fn with_policy(address: SocketAddr, peers: &[SocketAddr], policy: MiningPolicy) -> Self {
+        Node {
+            policy,
+            ..Node::new(address, peers)
         }
     }
 
+    /// Returns what the node's mining process is doing.
+    pub fn mining_status(&self) -> &MiningStatus {
+        &self.mining
+    }
+
+    /// Returns a flag that, once set, stops synchronising blocks (see [Message::Blocks]) before
+    /// the next block. The chain then stays valid, only shorter. Syncing resumes once it is reset.
+    /// The flag can be set without locking the node, e.g. while it is busy syncing.
+    pub fn sync_canceller(&self) -> Arc<AtomicBool> {
+        self.sync_cancelled.clone()
+    }
+
+    /// The number of pending transactions.
+    pub fn mempool_len(&self) -> usize {
+        self.mempool.len()
+    }
+
+    /// The serialized size of all pending transactions, i.e. roughly the memory they occupy.
+    pub fn mempool_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+
+    /// Subscribes to all future changes of the mempool.
+    pub fn subscribe_mempool(&self) -> broadcast::Receiver<MempoolEvent> {
+        self.mempool_events.subscribe()
+    }
+
+    /// Subscribes to the hashes of all blocks added to the chain from now on.
+    pub fn subscribe_blocks(&self) -> broadcast::Receiver<Hash> {
+        self.block_events.subscribe()
+    }
+
+    /// Publishes a mempool change. Nobody listening is fine.
+    fn publish(&self, event: MempoolEvent) {
+        let _ = self.mempool_events.send(event);
+    }
+
     /// Handles the state transitions of the node in response to the given message.
     /// Returns an optional reply to broadcast back to the network and instructions whether
     /// a mining process should be (re-)started.
     fn handle(&mut self, message: Message) -> (Option<Message>, MiningCommand) {
-        match message {
+        let (reply, cmd) = match message {
             // if a new peer connects
             Message::Connect(addr) if addr == self.address => (None, Keep),
             // ... and is not ourselves, add it to the peers and broadcast some known peers
             Message::Connect(addr) => (
-                self.peers.insert(addr).then(|| {
+                self.add_peer(addr).then(|| {
                     Message::Addr(
                         self.peers
                             .iter()
@@ -57,31 +344,34 @@ impl Node {
                             .collect(),
                     )
                 }),
-                Keep,
+                Start, // in case mining was paused for lack of peers
             ),
 
             // add broadcast peer addresses to addresses (except ourselves)
             Message::Addr(addrs) => {
-                self.peers
-                    .extend(addrs.into_iter().filter(|a| a != &self.address));
-                (None, Keep)
+                for addr in addrs {
+                    self.add_peer(addr);
+                }
+                (None, Start)
             }
 
             // add broadcast txs to mempool and rebroadcast new ones
             Message::Tx(txs) => {
-                // filter only the unknown transactions
-                // TODO: this currently only looks at the mempool. However, it should also check
-                //  whether a transaction was already added/comitted to the chain.
-                let new_txs = txs
-                    .0
-                    .into_iter()
-                    .map(|t| (t.hash(), t))
-                    .filter(|(h, _)| !self.mempool.contains_key(h))
-                    .collect::<Vec<_>>();
-
-                // TODO: once transactions get more integrity (like signing/ordering etc. see also
-                //  [Transaction], we need to validate them here before adding them to the mempool
-                self.mempool.extend(new_txs.clone());
+                // drop transactions repeated within the message, they are relayed once (even if
+                // evicted from the mempool meanwhile)
+                //
+                // only accept (and relay) transactions the mempool admits, see [Self::admits]
+                let mut new_txs = Vec::new();
+                let mut seen = HashSet::new();
+                for tx in txs.0 {
+                    let hash = tx.hash();
+                    if !seen.insert(hash) || !self.admits(&hash, &tx) {
+                        continue;
+                    }
+                    if self.add_to_mempool(hash, tx.clone()) {
+                        new_txs.push((hash, tx));
+                    }
+                }
 
                 // rebroadcast transactions we didn't yet know about
                 (
@@ -94,146 +384,2939 @@ impl Node {
 
             // adds a new block to chain, if valid and rebroadcasts if valid & new
             Message::NewBlock(block) => {
+                let previous_tip = self.chain.highest_block().hash();
+                let is_orphan = !self.chain.contains_block(&block.header.prev_block_hash);
                 let is_new = self.add_block(&block);
-                // if the main chain has updated, we need to restart the mining with the
-                // new highest block as parent
-                let cmd = if self.chain.highest_block() == &block {
-                    Restart
+                let reply = if is_new {
+                    Some(Message::NewBlock(block))
+                } else if is_orphan {
+                    // we missed some blocks, ask a peer for them (see [catch_up])
+                    Some(Message::GetBlocks {
+                        from_height: self.chain.main_chain_length(),
+                    })
                 } else {
-                    Start // otherwise, we still start mining if we were done
+                    None
+                };
+                (reply, self.tip_command(previous_tip))
+            }
+
+            // add synchronised blocks in order, without relaying them
+            Message::Blocks(blocks) => {
+                let previous_tip = self.chain.highest_block().hash();
+                self.sync_blocks(blocks);
+                (None, self.tip_command(previous_tip))
+            }
+
+            // adopt and relay new checkpoints signed by a trusted key, ignore all others
+            checkpoint @ Message::Checkpoint { height, hash, .. } => {
+                let trusted =
+                    (self.trusted_keys.iter()).any(|key| checkpoint.is_checkpoint_signed_by(key));
+                let adopted = trusted && self.chain.add_checkpoint(height, hash);
+                (adopted.then_some(checkpoint), Keep)
+            }
+
+            // requests are answered on their connection (see [Node::answer]) and their answers
+            // are meant for the requesting tool, neither is handled as a broadcast
+            Message::GetBlocks { .. }
+            | Message::Import(_)
+            | Message::Imported(_)
+            | Message::GetBlock(_)
+            | Message::BlockFound(_)
+            | Message::GetTransaction(_)
+            | Message::TransactionFound(_)
+            | Message::GetTip
+            | Message::Tip { .. }
+            | Message::GetDifficultyHistory
+            | Message::DifficultyHistory(_)
+            | Message::GetBalances(_)
+            | Message::Balances(_)
+            | Message::GetSyncProgress
+            | Message::SyncProgress(_)
+            | Message::GetOrphans
+            | Message::Orphans(_)
+            | Message::GetMempool
+            | Message::Mempool { .. } => (None, Keep),
+        };
+        (reply, if self.may_mine() { cmd } else { Keep })
+    }
+
+    /// Adds synchronised blocks in order until done or cancelled, see [Self::sync_canceller].
+    /// If the node trusts its peers (see [TRUST_PEERS_FLAG]), only the proof-of-work of the
+    /// last block is verified. Returns how many blocks were processed (whether they were new
+    /// or not), which is also tracked as the [Self::sync_progress].
+    fn sync_blocks<I>(&mut self, blocks: I) -> usize
+    where
+        I: IntoIterator<Item = Block, IntoIter: ExactSizeIterator>,
+    {
+        let started = Instant::now();
+        let mut processed = 0;
+        let mut blocks = blocks.into_iter().peekable();
+        let target = blocks.len();
+        self.sync_progress = SyncProgress::new(0, target, Duration::ZERO);
+        while let Some(block) = blocks.next() {
+            if self.sync_cancelled.load(Ordering::Relaxed) {
+                println!("Sync cancelled after {} blocks", processed);
+                break;
+            }
+            let trust_pow = self.trust_peers_pow && blocks.peek().is_some();
+            self.add_block_trusting(&block, trust_pow);
+            processed += 1;
+            self.sync_progress = SyncProgress::new(processed, target, started.elapsed());
+            if processed % SYNC_LOG_INTERVAL == 0 {
+                println!("Synced {:?}", self.sync_progress);
+            }
+        }
+        processed
+    }
+
+    /// Returns how far the node got adding the last batch of synchronised (or imported)
+    /// blocks. Batches are added while holding the node, so requests for it are only answered
+    /// in between, see [Message::GetSyncProgress]. The node logs it every [SYNC_LOG_INTERVAL]
+    /// blocks instead.
+    pub fn sync_progress(&self) -> SyncProgress {
+        self.sync_progress
+    }
+
+    /// Adds the blocks of a chain saved by [BlockChain::save_to_path], e.g. a snapshot of
+    /// another node, like synchronised ones. The file may also hold only a segment of a chain
+    /// that connects to ours. Returns the new highest block and the main chain length.
+    pub fn import_chain(&mut self, path: &Path) -> io::Result<(Hash, usize)> {
+        let blocks = BlockChain::read_blocks(path)?;
+        let previous_tip = self.chain.highest_block().hash();
+        self.sync_blocks(blocks);
+        // stops mining on an outdated tip, the next message starts it again
+        self.tip_command(previous_tip);
+        let tip = self.chain.highest_block().hash();
+        Ok((tip, self.chain.main_chain_length()))
+    }
+
+    /// The main-chain blocks from the given height on that answer a [Message::GetBlocks]: at
+    /// most [MAX_SYNC_BLOCKS] of them, and only as many as fit into a message of
+    /// [MAX_MESSAGE_LEN]. The requester pages through the rest, see [catch_up]. None if the
+    /// height is pruned, see [BlockChain::pruned_height].
+    fn sync_batch(&self, from_height: usize) -> Vec<Block> {
+        if from_height < self.chain.pruned_height() {
+            return Vec::new();
+        }
+        let blocks = self.chain.main_chain_from(from_height);
+        leading_blocks(blocks, MAX_SYNC_BLOCKS, MAX_MESSAGE_LEN)
+    }
+
+    /// Starts catching up from a peer unless already doing so. Returns the peer, i.e. the one
+    /// seen last, see [Node::add_peer], or nothing if there is no peer or a catch-up is running.
+    fn start_catch_up(&mut self) -> Option<SocketAddr> {
+        let peer = (self.peers.iter()).max_by_key(|peer| self.last_seen.get(peer));
+        let peer = peer.filter(|_| !self.catching_up).cloned();
+        self.catching_up |= peer.is_some();
+        peer
+    }
+
+    /// Answers a request of a local tool or a peer, see [Message::request]. Returns nothing
+    /// for messages that aren't requests.
+    fn answer(&mut self, request: Message) -> Option<Message> {
+        match request {
+            Message::GetBlocks { from_height } => {
+                Some(Message::Blocks(self.sync_batch(from_height)))
+            }
+            Message::Import(path) => {
+                let imported = self.import_chain(&path).map_err(|e| e.to_string());
+                Some(Message::Imported(imported))
+            }
+            Message::GetBlock(hash) => {
+                let found = self.chain.block(&hash).map(|block| block.cloned());
+                Some(Message::BlockFound(found))
+            }
+            Message::GetTransaction(hash) => {
+                let committed = (self.chain.find_transaction(&hash))
+                    .map(|(tx, height)| (tx.clone(), Some(height)));
+                let pending = || self.mempool.get(&hash).map(|tx| (tx.clone(), None));
+                Some(Message::TransactionFound(committed.or_else(pending)))
+            }
+            Message::GetTip => {
+                let tip = self.chain.highest_block();
+                let next = self.chain.main_chain_length();
+                Some(Message::Tip {
+                    hash: tip.hash(),
+                    height: next - 1,
+                    timestamp: tip.header.timestamp(),
+                    difficulty: self.chain.difficulty_for_height(next),
+                })
+            }
+            Message::GetDifficultyHistory => {
+                Some(Message::DifficultyHistory(self.chain.difficulty_history()))
+            }
+            Message::GetBalances(addresses) => {
+                Some(Message::Balances(self.chain.balances(&addresses)))
+            }
+            Message::GetSyncProgress => Some(Message::SyncProgress(self.sync_progress)),
+            Message::GetOrphans => {
+                let orphans = self.chain.orphan_blocks().into_iter().cloned().collect();
+                Some(Message::Orphans(orphans))
+            }
+            Message::GetMempool => Some(Message::Mempool {
+                txs: Transactions(self.mempool.values().cloned().collect()),
+                commitment: self.mempool_commitment(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// If the main chain has updated (by new blocks or orphans they connected), we need to
+    /// restart the mining with the new highest block as parent. Otherwise, we still start
+    /// mining if we were done.
+    fn tip_command(&self, previous_tip: Hash) -> MiningCommand {
+        if self.chain.highest_block().hash() != previous_tip {
+            // the block being mined is outdated, stop solving it right away
+            self.mining_cancelled.store(true, Ordering::Relaxed);
+            Restart
+        } else {
+            Start
+        }
+    }
+
+    /// Whether the spender's committed balance covers the transaction and all of its pending
+    /// ones, including fees, without immature coinbase "coin", see
+    /// [BlockChain::spendable_balance_of]. Only coinbase transactions create "coin", and not when proposed by
+    /// peers.
+    fn can_afford(&self, tx: &Transaction) -> bool {
+        let pending: u64 = (self.mempool.values())
+            .filter(|t| t.spender() == tx.spender())
+            .map(Transaction::cost)
+            .sum();
+        !tx.is_coinbase()
+            && pending + tx.cost() <= self.chain.spendable_balance_of(tx.spender()) as u64
+    }
+
+    /// The nonce the spender's next transaction must have, i.e. the one after their last
+    /// committed or pending transaction.
+    fn next_nonce(&self, spender: &Address) -> u64 {
+        let pending = (self.mempool.values())
+            .filter(|tx| tx.spender() == spender)
+            .map(Transaction::nonce);
+        pending.fold(self.chain.nonce_of(spender), u64::max) + 1
+    }
+
+    /// Whether a transaction may enter the mempool: it is neither pending nor committed, signed
+    /// by its spender, who can afford it on top of what they already spend in the mempool, and
+    /// numbered with the spender's next nonce, which rejects replays and gaps. It must also pay
+    /// at least the chain's minimum relay fee, see [ChainParams::min_relay_fee].
+    fn admits(&self, hash: &Hash, tx: &Transaction) -> bool {
+        tx.fee() >= self.chain.params().min_relay_fee
+            && !self.mempool.contains_key(hash)
+            && !self.chain.contains_transaction(hash)
+            && tx.verify_signature()
+            && tx.has_valid_data()
+            && self.can_afford(tx)
+            && tx.nonce() == self.next_nonce(tx.spender())
+    }
+
+    /// Whether the transaction's nonce is still unused, i.e. neither committed nor taken by a
+    /// pending transaction of the same spender.
+    fn has_fresh_nonce(&self, tx: &Transaction) -> bool {
+        tx.nonce() > self.chain.nonce_of(tx.spender())
+            && !(self.mempool.values())
+                .any(|pending| pending.spender() == tx.spender() && pending.nonce() == tx.nonce())
+    }
+
+    /// Adds a transaction to the mempool. If the mempool is full (see [MAX_MEMPOOL] and
+    /// [MAX_MEMPOOL_BYTES]), transactions of other spenders are evicted to make room, unless the
+    /// new one doesn't pay a higher fee rate. Only the last pending transaction of a spender is
+    /// evicted, such that no spender is left with a gap in their nonces, and fee rates are those
+    /// of packages, i.e. of the transaction together with its pending ancestors.
+    /// Returns whether it was added.
+    fn add_to_mempool(&mut self, hash: Hash, tx: Transaction) -> bool {
+        let size = serialized_size(&tx);
+        let (max_count, max_bytes) = (self.max_mempool, self.max_mempool_bytes);
+        let fits = |count: usize, bytes: usize| count < max_count && bytes + size <= max_bytes;
+
+        let (mut count, mut bytes) = (self.mempool.len(), self.pending_bytes);
+        let mut evicted = Vec::new();
+        if !fits(count, bytes) {
+            // the package of a spender's last transaction contains all their pending ones
+            let mut packages: BTreeMap<&Address, (FeeRate, &Hash, &Transaction)> = BTreeMap::new();
+            for (hash, pending) in self.mempool.iter() {
+                if pending.spender() == tx.spender() {
+                    continue;
+                }
+                let (rate, last_hash, last) = (packages.entry(pending.spender())).or_insert((
+                    FeeRate::default(),
+                    hash,
+                    pending,
+                ));
+                *rate = rate.with(pending);
+                if pending.nonce() > last.nonce() {
+                    (*last_hash, *last) = (hash, pending);
+                }
+            }
+            let rate = self.package_fee_rate(&tx);
+            // stable, such that ties are evicted by spender
+            let mut by_rate: Vec<_> = packages.into_values().collect();
+            by_rate.sort_by_key(|(rate, ..)| *rate);
+            for (lowest_rate, &lowest, pending) in by_rate {
+                if fits(count, bytes) || lowest_rate >= rate {
+                    break;
+                }
+                evicted.push(lowest);
+                count -= 1;
+                bytes -= serialized_size(pending);
+            }
+        }
+        if !fits(count, bytes) {
+            return false;
+        }
+
+        for lowest in evicted {
+            self.remove_from_mempool(&lowest);
+        }
+        self.mempool.insert(hash, tx);
+        self.pending_bytes += size;
+        self.received.insert(hash, unix_time());
+        self.publish(MempoolEvent::TxAdded(hash));
+        true
+    }
+
+    /// The fee rate of the transaction together with its pending ancestors, i.e. the spender's
+    /// pending transactions with lower nonces, which must be mined first.
+    fn package_fee_rate(&self, tx: &Transaction) -> FeeRate {
+        let ancestors = (self.mempool.values())
+            .filter(|pending| pending.spender() == tx.spender() && pending.nonce() < tx.nonce());
+        FeeRate::of(ancestors.chain([tx]))
+    }
+
+    /// Removes a transaction from the mempool, if pending. Returns whether it was.
+    fn remove_from_mempool(&mut self, hash: &Hash) -> bool {
+        let Some(tx) = self.mempool.remove(hash) else {
+            return false;
+        };
+        self.pending_bytes -= serialized_size(&tx);
+        self.received.remove(hash);
+        self.publish(MempoolEvent::TxRemoved(*hash));
+        true
+    }
+
+    /// Whether the pending transaction is unlikely to be mined, such that its spender may want to
+    /// replace it with one paying a higher fee. That is, if it has been pending for more than
+    /// [STUCK_BLOCKS] block intervals and pays a lower fee rate (together with its pending
+    /// ancestors) than the cheapest transaction of most of the last [STUCK_BLOCKS] blocks.
+    pub fn is_transaction_stuck(&self, tx_hash: &Hash) -> bool {
+        let (Some(tx), Some(received)) = (self.mempool.get(tx_hash), self.received.get(tx_hash))
+        else {
+            return false;
+        };
+        let patience = STUCK_BLOCKS as u64 * self.chain.params().target_block_interval;
+        if unix_time().saturating_sub(*received) <= patience {
+            return false;
+        }
+        // the lowest fee rate of each recent block (but genesis) with transactions
+        let from = self
+            .chain
+            .main_chain_length()
+            .saturating_sub(STUCK_BLOCKS)
+            .max(1);
+        let mut lowest_rates: Vec<FeeRate> = (self.chain.main_chain_from(from).into_iter())
+            .filter_map(|block| {
+                let txs = block.transactions.0.iter().filter(|tx| !tx.is_coinbase());
+                txs.map(|tx| FeeRate::of([tx])).min()
+            })
+            .collect();
+        lowest_rates.sort();
+        // if no transactions were mined recently, the fee isn't what keeps it pending
+        (lowest_rates.get(lowest_rates.len() / 2))
+            .is_some_and(|&median| self.package_fee_rate(tx) < median)
+    }
+
+    /// Whether the node's [MiningPolicy] currently allows it to mine.
+    fn may_mine(&self) -> bool {
+        match self.policy {
+            MiningPolicy::Networked => !self.peers.is_empty(),
+            MiningPolicy::Solo => true,
+            MiningPolicy::Observer => false,
+        }
+    }
+
+    /// If the node has lost (almost) all of its peers, e.g. after a network blip, falls back
+    /// to its seeds. Returns whether the node needs to re-announce itself.
+    fn reconnect(&mut self) -> bool {
+        if self.peers.len() >= MIN_PEERS || self.seeds.is_empty() {
+            return false;
+        }
+        for seed in self.seeds.clone() {
+            self.add_peer(seed);
+        }
+        true
+    }
+
+    /// Whether the node would add the given peer, i.e. it is neither ourselves nor banned.
+    /// A full node (see [MAX_PEERS]) still accepts new peers, they displace the least recently
+    /// seen one (see [Self::add_peer]), such that the peers follow the live network rather than
+    /// sticking to the first ones heard of.
+    pub fn would_accept_peer(&self, addr: &SocketAddr) -> bool {
+        addr != &self.address && !self.banned.contains(addr)
+    }
+
+    /// Adds a peer if the node accepts it, see [Self::would_accept_peer]. Known peers are only
+    /// marked as seen, such that they are not announced again. If the node already knows
+    /// [MAX_PEERS] peers, the least recently seen one is dropped to make room.
+    /// Returns whether the peer was added.
+    // TODO: once peers are scored (e.g. lowered by [Self::oversized_messages]), displace a
+    //  low-scored peer rather than the stalest one.
+    fn add_peer(&mut self, addr: SocketAddr) -> bool {
+        if !self.would_accept_peer(&addr) {
+            return false;
+        }
+        self.sightings += 1;
+        self.last_seen.insert(addr, self.sightings);
+        if self.peers.contains(&addr) {
+            return false;
+        }
+        if self.peers.len() >= MAX_PEERS {
+            // peers never seen, i.e. given at startup, count as the stalest
+            let stalest = (self.peers.iter()).min_by_key(|peer| self.last_seen.get(peer));
+            if let Some(stalest) = stalest.cloned() {
+                self.drop_peer(&stalest);
+            }
+        }
+        self.peers.insert(addr)
+    }
+
+    /// Drops the peer and never adds it again, e.g. because it misbehaved.
+    pub fn ban_peer(&mut self, addr: SocketAddr) {
+        self.drop_peer(&addr);
+        self.banned.insert(addr);
+    }
+
+    /// Records a message longer than [MAX_MESSAGE_LEN] sent from the host, a hint at a
+    /// misbehaving or incompatible peer. Counted per host, as peers connect from other ports
+    /// than they listen on. Returns how many the host sent so far.
+    fn record_oversized(&mut self, host: IpAddr) -> u32 {
+        let count = self.oversized.entry(host).or_default();
+        *count += 1;
+        *count
+    }
+
+    /// Returns how many messages longer than [MAX_MESSAGE_LEN] the host sent.
+    pub fn oversized_messages(&self, host: &IpAddr) -> u32 {
+        self.oversized.get(host).copied().unwrap_or(0)
+    }
+
+    /// Records that the host announced the block at the given time (in seconds since the unix
+    /// epoch). A selfish miner withholds the blocks it mines and only releases them once others
+    /// catch up, so the block looks withheld if it arrives more than one target block interval
+    /// after its timestamp, or if the host announced [BURST_BLOCKS] blocks within one interval.
+    /// This is a heuristic for monitoring: hosts running several nodes also relay bursts.
+    /// Returns whether the block looks withheld.
+    fn record_block_arrival(&mut self, host: IpAddr, block: &Block, now: u64) -> bool {
+        let interval = self.chain.params().target_block_interval;
+        let arrivals = self.block_arrivals.entry(host).or_default();
+        arrivals.push_back(now);
+        if arrivals.len() > BURST_BLOCKS {
+            arrivals.pop_front();
+        }
+        let is_late = now.saturating_sub(block.header.timestamp()) > interval;
+        let is_burst = arrivals.len() == BURST_BLOCKS
+            && arrivals.back().unwrap() - arrivals.front().unwrap() <= interval;
+        if is_late || is_burst {
+            *self.withholding_suspicions.entry(host).or_default() += 1;
+        }
+        is_late || is_burst
+    }
+
+    /// Returns how many blocks announced by the host looked withheld, see
+    /// [Self::record_block_arrival].
+    pub fn withholding_suspicions(&self, host: &IpAddr) -> u32 {
+        self.withholding_suspicions.get(host).copied().unwrap_or(0)
+    }
+
+    /// Forgets everything about the peer, it is added again once announced.
+    fn drop_peer(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        self.last_seen.remove(addr);
+        self.failures.remove(addr);
+    }
+
+    /// Records which of the peers a broadcast to them failed to reach. Peers that couldn't be
+    /// reached by [MAX_PEER_FAILURES] broadcasts in a row are dropped (but not banned, so they
+    /// are added again once announced). Returns the dropped peers.
+    fn record_broadcast(&mut self, peers: &[SocketAddr], failed: &[SocketAddr]) -> Vec<SocketAddr> {
+        let mut dropped = Vec::new();
+        for peer in peers {
+            if !failed.contains(peer) {
+                self.failures.remove(peer);
+                continue;
+            }
+            let failures = self.failures.entry(*peer).or_default();
+            *failures += 1;
+            if *failures >= MAX_PEER_FAILURES && self.peers.contains(peer) {
+                self.drop_peer(peer);
+                dropped.push(*peer);
+            }
+        }
+        dropped
+    }
+
+    /// Commitment to the current mempool contents, see [merkle_commitment]. Sent along with
+    /// them to tools requesting the mempool, see [Message::GetMempool].
+    fn mempool_commitment(&self) -> Hash {
+        merkle_commitment(self.mempool.keys().cloned())
+    }
+
+    /// Prepares mining a new block on top of the highest block and records it in the
+    /// mining status. Returns the unsolved header and the transactions to mine, starting with
+    /// the coinbase, if there are any pending ones.
+    fn begin_mining(&mut self) -> Option<(BlockHeader, Vec<Transaction>)> {
+        if self.mempool.is_empty() {
+            return None;
+        }
+        let parent = self.chain.highest_block();
+        let parent_hash = parent.hash();
+        // blocks must be strictly later than their parent, even if mined in the same second
+        let time = unix_time().max(parent.header.timestamp() + 1);
+        let height = self.chain.main_chain_length();
+        let difficulty = self.chain.difficulty_for_height(height);
+        let txs = self.mining_candidates();
+        let fees: u64 = txs.iter().map(|tx| tx.fee() as u64).sum();
+        let reward = u32::try_from(block_reward(height) as u64 + fees).ok()?;
+        let coinbase =
+            Transaction::coinbase(self.miner, reward, time).with_data(self.coinbase_data.clone());
+        let mut merkle = MerkleAccumulator::new();
+        merkle.push(&coinbase);
+        txs.iter().for_each(|tx| merkle.push(tx));
+        let header = BlockHeader::new(parent_hash, merkle.root()?, difficulty, time);
+        // only now that the block is built, a miner task gets started
+        self.mining = MiningStatus {
+            active: true,
+            parent_hash: Some(parent_hash),
+            started_at: Some(Instant::now()),
+            attempts: 0,
+        };
+        self.mining_cancelled = Arc::new(AtomicBool::new(false));
+        Some((header, [vec![coinbase], txs].concat()))
+    }
+
+    /// Takes the transactions paying the highest fee rates from the pool for inclusion in the
+    /// next block, leaving room for the coinbase. Batches are only taken if they are complete
+    /// and fit into the block as a whole. The transactions of a spender are taken in nonce
+    /// order, so each is ranked by the fee rate of its package, i.e. together with its pending
+    /// ancestors, which it takes along. A transaction paying a high fee thus also gets its
+    /// ancestors paying low fees mined.
+    // TODO: packages are only ranked once, as if none of their ancestors were taken yet.
+    fn mining_candidates(&self) -> Vec<Transaction> {
+        // the pending transactions of each spender by nonce, the one paying most for each nonce
+        let mut pending: HashMap<&Address, BTreeMap<u64, &Transaction>> = HashMap::new();
+        let mut batches: HashMap<Hash, Vec<&Transaction>> = HashMap::new();
+        for tx in self.mempool.values() {
+            let same_nonce = (pending.entry(tx.spender()).or_default())
+                .entry(tx.nonce())
+                .or_insert(tx);
+            if tx.fee() > same_nonce.fee() {
+                *same_nonce = tx;
+            }
+            if let Some(batch) = tx.batch() {
+                batches.entry(batch.id).or_default().push(tx);
+            }
+        }
+
+        // the nonce of each spender's next transaction to take
+        let mut next: HashMap<Address, u64> = HashMap::new();
+        let next_of = |next: &HashMap<Address, u64>, spender: &Address| {
+            (next.get(spender).copied()).unwrap_or_else(|| self.chain.nonce_of(spender) + 1)
+        };
+
+        // single transactions and complete batches, ranked by the fee rate of their package
+        let mut groups: Vec<(FeeRate, Vec<&Transaction>)> = Vec::new();
+        for tx in self.mempool.values() {
+            let mut group = match tx.batch() {
+                None => vec![tx],
+                // take the whole batch when encountering its first transaction
+                Some(batch) => match batches.remove(&batch.id) {
+                    Some(group) if group.len() == batch.size as usize => group,
+                    _ => continue,
+                },
+            };
+            group.sort_by_key(|tx| tx.nonce());
+            let rate = match package(&group, |spender| next_of(&next, spender), &pending) {
+                Package::Ready(package) => FeeRate::of(package),
+                Package::Waiting(..) => FeeRate::of(group.iter().copied()),
+                Package::Unminable => continue,
+            };
+            groups.push((rate, group));
+        }
+        // stable, i.e. groups paying the same rate stay ordered by hash
+        groups.sort_by_key(|(rate, _)| Reverse(*rate));
+
+        // groups encountered before a batched ancestor was taken, by the spender and nonce
+        // following that ancestor
+        let mut waiting: HashMap<(Address, u64), Vec<Vec<&Transaction>>> = HashMap::new();
+        let mut candidates = Vec::new();
+        for (_, group) in groups {
+            // the group and those waiting for it, in the order they are taken
+            let mut ready = vec![group];
+            while let Some(group) = ready.pop() {
+                let package = match package(&group, |spender| next_of(&next, spender), &pending) {
+                    Package::Ready(package) => package,
+                    Package::Waiting(spender, nonce) => {
+                        waiting.entry((spender, nonce)).or_default().push(group);
+                        continue;
+                    }
+                    Package::Unminable => continue,
                 };
-                (is_new.then_some(Message::NewBlock(block)), cmd)
+                // the coinbase takes one of the MAX_TXS
+                if candidates.len() + package.len() >= MAX_TXS {
+                    continue;
+                }
+                for tx in &package {
+                    next.insert(*tx.spender(), tx.nonce() + 1);
+                    // the first one waiting pays the most, so take it first
+                    let key = (*tx.spender(), tx.nonce() + 1);
+                    let successors = waiting.remove(&key).unwrap_or_default();
+                    ready.extend(successors.into_iter().rev());
+                }
+                candidates.extend(package.into_iter().cloned());
+            }
+        }
+        candidates
+    }
+
+    /// Writes the pending transactions to the given file, such that they survive a restart.
+    /// They are written in the order of their nonces, such that each spender's are re-added
+    /// one after the other.
+    pub fn save_mempool(&self, path: &Path) -> io::Result<()> {
+        let mut txs: Vec<&Transaction> = self.mempool.values().collect();
+        txs.sort_by_key(|tx| tx.nonce());
+        let bytes =
+            bincode::serialize(&txs).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        fs::write(path, bytes)
+    }
+
+    /// Writes the known peers to the given file, such that they survive a restart.
+    pub fn save_peers(&self, path: &Path) -> io::Result<()> {
+        let peers: Vec<&SocketAddr> = self.peers.iter().collect();
+        let bytes =
+            bincode::serialize(&peers).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        fs::write(path, bytes)
+    }
+
+    /// Reads peers saved by [Self::save_peers] and adds them like announced ones, see
+    /// [Self::add_peer]. Returns how many were added.
+    pub fn load_peers(&mut self, path: &Path) -> io::Result<usize> {
+        let peers: Vec<SocketAddr> = bincode::deserialize(&fs::read(path)?)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(peers
+            .into_iter()
+            .filter(|peer| self.add_peer(*peer))
+            .count())
+    }
+
+    /// Writes the chain, the mempool and the peers to their files in the data directory, see
+    /// [CHAIN_FILE], [MEMPOOL_FILE] and [PEERS_FILE]. Each is first written to a temporary file
+    /// and synced to disk, and only renamed once all of them are written, such that a crash
+    /// while writing leaves the previous files intact.
+    ///
+    /// The renames themselves are not atomic together: a crash between them leaves a newer
+    /// chain next to an older mempool or peer list. Restoring tolerates that, as the mempool is
+    /// checked against the chain again (see [Self::load_mempool]) and peers are mere hints.
+    pub fn persist(&self, data_dir: &Path) -> io::Result<()> {
+        let temp = |file: &str| data_dir.join(format!("{}.tmp", file));
+        self.chain.save_to_path(&temp(CHAIN_FILE))?;
+        self.save_mempool(&temp(MEMPOOL_FILE))?;
+        self.save_peers(&temp(PEERS_FILE))?;
+        for file in [CHAIN_FILE, MEMPOOL_FILE, PEERS_FILE] {
+            fs::File::open(temp(file))?.sync_all()?;
+        }
+        for file in [CHAIN_FILE, MEMPOOL_FILE, PEERS_FILE] {
+            fs::rename(temp(file), data_dir.join(file))?;
+        }
+        Ok(())
+    }
+
+    /// Reads transactions saved by [Self::save_mempool] back into the mempool, checking them
+    /// like received ones (see [Self::admits]): those committed to the chain (or whose nonce
+    /// was) in the meantime, that became unaffordable or don't fit into the mempool (see
+    /// [Self::add_to_mempool]) are dropped. Returns how many were added.
+    pub fn load_mempool(&mut self, path: &Path) -> io::Result<usize> {
+        let txs: Vec<Transaction> = bincode::deserialize(&fs::read(path)?)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let mut added = 0;
+        for tx in txs {
+            let hash = tx.hash();
+            if !self.admits(&hash, &tx) {
+                continue;
+            }
+            if self.add_to_mempool(hash, tx) {
+                added += 1;
             }
         }
+        Ok(added)
     }
 
-    /// Adds a block to the chain and if valid, removes the transactions
-    /// that it includes from the mempool.
+    /// Adds a block to the chain and if valid, removes the transactions it commits to the main
+    /// chain (along with any orphans it connected) from the mempool. Should the block cause a
+    /// reorganisation, the transactions of the blocks taken off the main chain return to the
+    /// mempool, unless the new main chain commits them (or their nonce) too or they became
+    /// unaffordable. Pending transactions whose nonce got committed are dropped as replays.
+    /// Returns whether the block was new.
     fn add_block(&mut self, block: &Block) -> bool {
-        let is_new = self.chain.add_block(block);
-        if is_new {
-            let txs: HashSet<Hash> = block.transactions.0.iter().map(|t| t.hash()).collect();
-            self.mempool.retain(|h, _| !txs.contains(h));
+        self.add_block_trusting(block, false)
+    }
+
+    /// Like [Self::add_block], but without verifying the block's proof-of-work if `trust_pow`,
+    /// see [BlockChain::add_block_trusting_pow].
+    fn add_block_trusting(&mut self, block: &Block, trust_pow: bool) -> bool {
+        let previous_tip = self.chain.highest_block().hash();
+        let previous_length = self.chain.main_chain_length();
+        let added = if trust_pow {
+            self.chain.add_block_trusting_pow(block)
+        } else {
+            self.chain.add_block(block)
+        };
+        let Ok(AddResult::New(connected)) = added else {
+            return false;
+        };
+        // with a reorganisation, blocks of the new main chain may have been added before
+        let stale = self.chain.stale_branch(&previous_tip);
+        let fork_height = previous_length - 1 - stale.len();
+        let stale_txs: Vec<Transaction> = (stale.iter())
+            .flat_map(|block| block.transactions.0.iter())
+            .filter(|tx| !tx.is_coinbase())
+            .cloned()
+            .collect();
+        let committed: Vec<Hash> = (self.chain.main_chain_from(fork_height + 1).iter())
+            .flat_map(|block| block.transactions.0.iter().map(Hashable::hash))
+            .collect();
+
+        for hash in committed {
+            self.remove_from_mempool(&hash);
+        }
+        // pending transactions whose nonce another one committed meanwhile are replays now
+        let replays: Vec<Hash> = (self.mempool.iter())
+            .filter(|(_, tx)| tx.nonce() <= self.chain.nonce_of(tx.spender()))
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in replays {
+            self.remove_from_mempool(&hash);
+        }
+        for tx in stale_txs {
+            let hash = tx.hash();
+            if !self.chain.contains_transaction(&hash)
+                && !self.mempool.contains_key(&hash)
+                && self.has_fresh_nonce(&tx)
+                && self.can_afford(&tx)
+            {
+                self.add_to_mempool(hash, tx);
+            }
+        }
+        for block_hash in connected {
+            // no subscribers is fine
+            let _ = self.block_events.send(block_hash);
+        }
+        true
+    }
+}
+
+/// Resolves once the transaction has (at least) `n` confirmations on the main chain, see
+/// [BlockChain::confirmations]. Fails if the transaction is neither pending nor committed
+/// anymore, e.g. because it was dropped or its block was orphaned.
+pub async fn wait_for_confirmations(
+    node_state: Arc<RwLock<Node>>,
+    tx_hash: Hash,
+    n: usize,
+) -> io::Result<()> {
+    // subscribe before checking, such that no block is missed in between
+    let mut blocks = node_state.read().await.subscribe_blocks();
+    loop {
+        {
+            let node = node_state.read().await;
+            match node.chain.confirmations(&tx_hash) {
+                Some(confirmations) if confirmations >= n => return Ok(()),
+                None if !node.mempool.contains_key(&tx_hash) => {
+                    return Err(io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("transaction {} is unknown", tx_hash.encode()),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        match blocks.recv().await {
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => {
+                return Err(io::Error::from(ErrorKind::BrokenPipe))
+            }
         }
-        is_new
     }
 }
 
 /// Start a new mining process.
 async fn start_mining(node_state: Arc<RwLock<Node>>) -> io::Result<()> {
-    let (prev_hash, txs) = {
-        let node = node_state.read().await;
-        if node.mempool.is_empty() {
+    let (header, txs, cancelled) = {
+        let mut node = node_state.write().await;
+        let Some((header, txs)) = node.begin_mining() else {
             println!("No txs to mine.");
             return Ok(());
         };
-        let prev_hash = node.chain.highest_block().hash();
-        // Take "some" transactions from the pool
-        let txs = node
-            .mempool
-            .iter()
-            .take(MAX_TXS)
-            .map(|(_, t)| t.clone())
-            .collect::<Vec<_>>();
-        (prev_hash, txs)
+        println!(
+            "Mining on mempool {} ({} txs, {} bytes)",
+            node.mempool_commitment().encode(),
+            node.mempool_len(),
+            node.mempool_bytes()
+        );
+        (header, txs, node.mining_cancelled.clone())
     };
+    let prev_hash = header.prev_block_hash;
 
     // Start the mining process (blocking because CPU-bound)
     // Note that no lock is kept during the mining.
-    let mined_block = task::spawn_blocking(move || {
-        Block::mine_new(prev_hash, GLOBAL_DIFFICULTY, Transactions(txs))
+    let mined_block = run_blocking(move || {
+        let mut header = header;
+        // the threads split the nonces evenly, so the nonce is about the number of attempts
+        header.nonce = header.solve_parallel(mining_threads(), &cancelled)?;
+        Some(Block {
+            header,
+            transactions: Transactions(txs),
+        })
     })
-    .await?;
+    .await;
+    let Some(Some(mined_block)) = mined_block else {
+        // a failed mining task is logged already, the node keeps running either way
+        if mined_block.is_some() {
+            println!("Mining on {} cancelled", prev_hash.encode());
+        }
+        node_state.write().await.mining.active = false;
+        return Ok(());
+    };
     println!("Mined {:?}", mined_block.header);
     let valid = {
         let mut node = node_state.write().await;
-        node.add_block(&mined_block)
+        node.mining.active = false;
+        node.mining.attempts = mined_block.header.nonce as u64 + 1;
+        println!("{:?}", node.mining);
+        let valid = node.add_block(&mined_block);
+        println!(
+            "Chain length {}, {} committed transactions, orphan rate {:.2}, {} future blocks rejected",
+            node.chain.main_chain_length(),
+            node.chain.total_transactions(),
+            node.chain.orphan_rate(),
+            node.chain.future_block_rejections()
+        );
+        valid
     };
     if valid {
-        broadcast(node_state, &Message::NewBlock(mined_block)).await
-    } else {
-        Ok(())
+        broadcast(node_state, &Message::NewBlock(mined_block)).await;
     }
+    Ok(())
 }
 
-async fn broadcast(node_state: Arc<RwLock<Node>>, message: &Message) -> io::Result<()> {
-    println!("Send {:?}", &message);
-    let node = node_state.read().await;
-    message.broadcast(node.peers.iter()).await
+/// Runs CPU-bound work in the blocking pool. Should it panic, logs that and returns `None`
+/// instead of propagating the failure to the caller.
+async fn run_blocking<T, F>(work: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match task::spawn_blocking(work).await {
+        Ok(result) => Some(result),
+        Err(e) => {
+            println!("Blocking task failed: {}", e);
+            None
+        }
+    }
 }
 
-// TODO: there is no limit (in the protocol) on the message size. The receiving will break for
-//  too long messages. Implement either a limit or continuous reading on the socket to fix this.
-const RCV_BUFFER_LEN: usize = 1024;
-
-async fn accept_message(listener: &TcpListener) -> io::Result<Message> {
-    let (mut socket, _) = listener.accept().await?;
-    let mut buf = [0; RCV_BUFFER_LEN];
-    let n = socket.read(&mut buf).await?;
-    Message::try_from(&buf[..n])
+/// The number of threads to mine on, i.e. one per CPU.
+fn mining_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
 }
 
-/// By default, a node will try to bind itself to `localhost:7000`.
-const DEFAULT_SOCKET: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 7000);
+/// Owns the node's mining task and makes sure there is at most one at a time.
+#[derive(Default)]
+struct Miner {
+    task: Option<JoinHandle<io::Result<()>>>,
+}
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    // read initial peer address from the CLI arguments
-    let initial_peers: Vec<SocketAddr> = std::env::args().filter_map(|s| s.parse().ok()).collect();
+impl Miner {
+    /// Whether a mining task is currently running.
+    fn is_running(&self) -> bool {
+        self.task.as_ref().is_some_and(|t| !t.is_finished())
+    }
 
-    // Try to bind to default port or take random port if already in use
-    let listener = match TcpListener::bind(DEFAULT_SOCKET).await {
-        Ok(l) => l,
-        Err(_) => TcpListener::bind("127.0.0.1:0").await?,
-    };
-    let address = listener.local_addr()?;
-    println!(
-        "Node started at {} with initial peers: {:?}",
-        address, initial_peers
-    );
+    /// Starts mining unless a mining task is already running.
+    async fn start<F>(&mut self, mining: F)
+    where
+        F: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        if !self.is_running() {
+            self.restart(mining).await
+        }
+    }
 
-    // The entire (thread-safe) state of the node
-    let node_state = Arc::new(RwLock::new(Node::new(address, &initial_peers)));
-    let mining_task = Arc::new(RwLock::new(None::<JoinHandle<_>>));
+    /// Aborts the running mining task, if any, and only once it is gone, starts a new one.
+    // Note that aborting cannot interrupt a block being solved in the blocking pool. That stops
+    // on its own when the node cancels it (see [Node::tip_command]), otherwise it runs to
+    // completion but its result is dropped with the aborted task.
+    async fn restart<F>(&mut self, mining: F)
+    where
+        F: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            let _ = task.await;
+        }
+        self.task = Some(task::spawn(mining));
+    }
+}
 
-    // Announce ourselves to network
-    broadcast(node_state.clone(), &Message::Connect(address)).await?;
+/// Sends the message to all peers, logging those that couldn't be reached. Peers unreachable
+/// for [MAX_PEER_FAILURES] broadcasts in a row are dropped, see [Node::record_broadcast].
+async fn broadcast(node_state: Arc<RwLock<Node>>, message: &Message) {
+    println!("Send {:?}", &message);
+    // don't keep the node locked while sending
+    let peers: Vec<SocketAddr> = node_state.read().await.peers.iter().cloned().collect();
+    let failed: Vec<SocketAddr> = match message.broadcast(peers.iter()).await {
+        Ok(()) => Vec::new(),
+        Err(failures) => (failures.into_iter())
+            .map(|(peer, e)| {
+                println!("Failed to send to {}: {}", peer, e);
+                peer
+            })
+            .collect(),
+    };
+    let dropped = node_state.write().await.record_broadcast(&peers, &failed);
+    for peer in dropped {
+        println!("Dropped unreachable peer {}", peer);
+    }
+}
 
-    println!("Starting to process...");
-    while let Ok(message) = accept_message(&listener).await {
+/// Accepts connections until the listener fails. Each connection is read and its message
+/// handled in a task of its own, such that a slow or stalling peer doesn't hold up the others.
+async fn serve(
+    listener: TcpListener,
+    node_state: Arc<RwLock<Node>>,
+    miner: Arc<RwLock<Miner>>,
+) -> io::Result<()> {
+    loop {
+        let (mut socket, peer) = listener.accept().await?;
         let node_handle = node_state.clone();
-        let task_handle = mining_task.clone();
+        let miner_handle = miner.clone();
         tokio::spawn(async move {
-            println!("Got {:?}", message);
-
-            let (reply, mining_command) = {
-                let mut node = node_handle.write().await;
-                node.handle(message)
-            };
-
-            {
-                let mut task = task_handle.write().await;
-                match mining_command {
-                    Restart => {
-                        println!("Restart mining");
-                        if let Some(t) = task.as_ref() {
-                            t.abort()
+            match Message::read_from(&mut socket).await {
+                Ok(request) if request.is_request() => {
+                    answer(node_handle, &mut socket, peer, request).await
+                }
+                Ok(message) => {
+                    if let Message::NewBlock(block) = &message {
+                        let mut node = node_handle.write().await;
+                        if node.record_block_arrival(peer.ip(), block, unix_time()) {
+                            let suspicions = node.withholding_suspicions(&peer.ip());
+                            println!(
+                                "Block {} from {} looks withheld ({} so far)",
+                                block.hash().encode(),
+                                peer.ip(),
+                                suspicions
+                            );
                         }
-                        *task = Some(task::spawn(start_mining(node_handle.clone())));
                     }
-                    Start if task.is_none() || task.as_ref().is_some_and(|t| t.is_finished()) => {
-                        println!("Start mining");
-                        *task = Some(task::spawn(start_mining(node_handle.clone())));
-                    }
-                    _ => {}
+                    process(node_handle, miner_handle, message).await
+                }
+                // a single peer sending garbage must not affect the node
+                Err(e) if MessageTooLong::is_cause_of(&e) => {
+                    let count = node_handle.write().await.record_oversized(peer.ip());
+                    println!(
+                        "Dropped invalid message: {} ({} so far from {})",
+                        e,
+                        count,
+                        peer.ip()
+                    );
                 }
+                Err(e) => println!("Dropped invalid message: {}", e),
+            }
+        });
+    }
+}
+
+/// Answers a request on its connection, see [Node::answer]. Imports read a file on the node's
+/// machine, so they are only answered for tools on the same machine.
+async fn answer(
+    node_state: Arc<RwLock<Node>>,
+    socket: &mut TcpStream,
+    peer: SocketAddr,
+    request: Message,
+) {
+    if matches!(request, Message::Import(_)) && !peer.ip().is_loopback() {
+        println!("Refused {:?} from {}", request, peer);
+        return;
+    }
+    println!("Got {:?}", request);
+    let reply = node_state.write().await.answer(request);
+    if let Some(reply) = reply {
+        if let Err(e) = reply.write_to(socket).await {
+            println!("Failed to answer {}: {}", peer, e);
+        }
+    }
+}
+
+/// Handles a message received from the network: updates the node, (re-)starts mining if
+/// needed and sends the reply, if any.
+async fn process(node_state: Arc<RwLock<Node>>, miner: Arc<RwLock<Miner>>, message: Message) {
+    println!("Got {:?}", message);
+
+    let (reply, mining_command) = {
+        let mut node = node_state.write().await;
+        node.handle(message)
+    };
+    command_miner(node_state.clone(), &miner, mining_command).await;
+
+    // Send replies to the network if needed, but catch up from a single peer
+    match reply {
+        Some(Message::GetBlocks { from_height }) => catch_up(node_state, miner, from_height).await,
+        Some(r) => broadcast(node_state, &r).await,
+        None => {}
+    }
+}
+
+/// Asks a single peer for its main-chain blocks from the given height on, one batch after the
+/// other (see [Node::sync_batch]), and adds them. Only one catch-up runs at a time, the node
+/// ignores orphans' requests for more meanwhile, see [Node::start_catch_up].
+async fn catch_up(node_state: Arc<RwLock<Node>>, miner: Arc<RwLock<Miner>>, from_height: usize) {
+    let Some(peer) = node_state.write().await.start_catch_up() else {
+        return;
+    };
+    let mut from_height = from_height;
+    loop {
+        let request = Message::GetBlocks { from_height };
+        println!("Request {:?} from {}", request, peer);
+        let blocks = match request.request(&peer).await {
+            Ok(Message::Blocks(blocks)) if !blocks.is_empty() => blocks,
+            Ok(Message::Blocks(_)) => break,
+            Ok(other) => {
+                println!("Unexpected answer from {}: {:?}", peer, other);
+                break;
             }
+            Err(e) => {
+                println!("Failed to catch up from {}: {}", peer, e);
+                break;
+            }
+        };
+        from_height += blocks.len();
+        let (_, mining_command) = node_state.write().await.handle(Message::Blocks(blocks));
+        command_miner(node_state.clone(), &miner, mining_command).await;
+    }
+    node_state.write().await.catching_up = false;
+}
 
-            // Send replies to the network if needed
-            if let Some(r) = reply {
-                broadcast(node_handle, &r).await.unwrap();
+/// (Re-)starts mining as commanded, see [Node::handle].
+async fn command_miner(
+    node_state: Arc<RwLock<Node>>,
+    miner: &RwLock<Miner>,
+    mining_command: MiningCommand,
+) {
+    // Keep the miner locked while (re)starting, such that concurrent messages
+    // cannot spawn overlapping mining tasks
+    {
+        let mut miner = miner.write().await;
+        match mining_command {
+            Restart => {
+                println!("Restart mining");
+                miner.restart(start_mining(node_state.clone())).await;
+            }
+            Start if !miner.is_running() => {
+                println!("Start mining");
+                miner.start(start_mining(node_state.clone())).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The default maximum number of pending transactions of a node, such that a flood of
+/// transactions can't exhaust its memory. Can be changed with the [MAX_MEMPOOL_FLAG].
+const MAX_MEMPOOL: usize = 10_000;
+
+/// The default maximum serialized size of all pending transactions of a node, 4 MiB.
+/// Can be changed with the [MAX_MEMPOOL_BYTES_FLAG].
+const MAX_MEMPOOL_BYTES: usize = 4 << 20;
+
+/// The number of block intervals after which a pending transaction is considered stuck if it
+/// pays less than recent blocks' transactions, see [Node::is_transaction_stuck].
+const STUCK_BLOCKS: usize = 6;
+
+/// How often a node checks for stuck transactions, see [Node::is_transaction_stuck].
+const STUCK_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How many blocks a host must announce within one target block interval to look like it
+/// withheld them, see [Node::record_block_arrival].
+const BURST_BLOCKS: usize = 3;
+
+/// How many synchronised blocks a node adds between logging its progress, see
+/// [Node::sync_progress].
+const SYNC_LOG_INTERVAL: usize = 1000;
+
+/// The maximum number of blocks a node answers a [Message::GetBlocks] with, see
+/// [Node::sync_batch].
+const MAX_SYNC_BLOCKS: usize = 500;
+
+/// The current time in seconds since the unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Current time is after unix epoch")
+        .as_secs()
+}
+
+/// The first of the given blocks, at most `max_blocks` of them, that fit into a
+/// [Message::Blocks] of at most `max_len` bytes.
+fn leading_blocks(blocks: Vec<&Block>, max_blocks: usize, max_len: usize) -> Vec<Block> {
+    let mut len = Vec::<u8>::from(&Message::Blocks(Vec::new())).len();
+    let mut batch = Vec::new();
+    for block in blocks.into_iter().take(max_blocks) {
+        len += bincode::serialized_size(block).expect("can always serialize a block") as usize;
+        if len > max_len {
+            break;
+        }
+        batch.push(block.clone());
+    }
+    batch
+}
+
+/// The size of a transaction as sent on the wire and accounted for in the mempool.
+fn serialized_size(tx: &Transaction) -> usize {
+    bincode::serialized_size(tx).expect("can always serialize a transaction") as usize
+}
+
+/// The maximum number of peers a node keeps track of (and broadcasts to).
+const MAX_PEERS: usize = 64;
+
+/// After this many broadcasts in a row failed to reach a peer, a node drops it.
+const MAX_PEER_FAILURES: u32 = 3;
+
+/// Below this number of known peers, a node re-announces itself to its seeds.
+const MIN_PEERS: usize = 1;
+
+/// How often a node checks whether it needs to re-announce itself.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// By default, a node will try to bind itself to `localhost` on one of these ports (in order),
+/// such that peers can find it with well-known addresses, before falling back to a random port.
+const DEFAULT_PORTS: Range<u16> = 7000..7010;
+
+/// Binds to the first free port of the given range on `localhost`, or to a random port if
+/// all of them are in use.
+async fn bind_default(ports: Range<u16>) -> io::Result<TcpListener> {
+    for port in ports {
+        if let Ok(listener) = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await {
+            return Ok(listener);
+        }
+    }
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await
+}
+
+/// Environment variable overriding the address a node listens on, e.g. for containers.
+const LISTEN_ADDR_VAR: &str = "POW_LISTEN_ADDR";
+
+/// CLI flag overriding the address a node listens on, e.g. `--listen=0.0.0.0:7000`.
+const LISTEN_FLAG: &str = "--listen=";
+
+/// Resolves an explicitly requested listening address: the CLI flag wins over the
+/// environment variable. Returns `None` if neither is given, i.e. for [DEFAULT_PORTS].
+fn listen_address(args: &[String], env: Option<String>) -> io::Result<Option<SocketAddr>> {
+    let (source, value) = match args.iter().find_map(|a| a.strip_prefix(LISTEN_FLAG)) {
+        Some(flag) => ("--listen", flag.to_string()),
+        None => match env {
+            Some(var) => (LISTEN_ADDR_VAR, var),
+            None => return Ok(None),
+        },
+    };
+    value.parse().map(Some).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid {} address '{}': {}", source, value, e),
+        )
+    })
+}
+
+/// CLI flag setting the (base58) address the rewards of mined blocks go to, e.g.
+/// `--miner=<address>`. Without it, they go to the test key receiving the [GENESIS_TX].
+const MINER_FLAG: &str = "--miner=";
+
+/// Parses the address given by the [MINER_FLAG], if any.
+fn miner_address(args: &[String]) -> io::Result<Option<Address>> {
+    let Some(value) = args.iter().find_map(|a| a.strip_prefix(MINER_FLAG)) else {
+        return Ok(None);
+    };
+    let bytes = bs58::decode(value).into_vec().unwrap_or_default();
+    let address = Address::try_from(bytes).map_err(|_| {
+        let reason = format!("invalid --miner address '{}'", value);
+        io::Error::new(ErrorKind::InvalidInput, reason)
+    })?;
+    Ok(Some(address))
+}
+
+/// CLI flag setting the message the coinbase of mined blocks carries, e.g. to tag them with
+/// `--coinbase-data=<text>`. At most [MAX_COINBASE_DATA] bytes.
+const COINBASE_DATA_FLAG: &str = "--coinbase-data=";
+
+/// Parses the message given by the [COINBASE_DATA_FLAG], empty if there is none.
+fn coinbase_data(args: &[String]) -> io::Result<Vec<u8>> {
+    let Some(value) = args.iter().find_map(|a| a.strip_prefix(COINBASE_DATA_FLAG)) else {
+        return Ok(Vec::new());
+    };
+    if value.len() > MAX_COINBASE_DATA {
+        let reason = format!("--coinbase-data exceeds {} bytes", MAX_COINBASE_DATA);
+        return Err(io::Error::new(ErrorKind::InvalidInput, reason));
+    }
+    Ok(value.as_bytes().to_vec())
+}
+
+/// CLI flag adding a (base58) public key whose checkpoints the node adopts, e.g.
+/// `--trusted-key=<key>`. Can be given multiple times.
+const TRUSTED_KEY_FLAG: &str = "--trusted-key=";
+
+/// Parses the keys given by the [TRUSTED_KEY_FLAG].
+fn trusted_keys(args: &[String]) -> io::Result<Vec<VerifyingKey>> {
+    (args.iter().filter_map(|a| a.strip_prefix(TRUSTED_KEY_FLAG)))
+        .map(|value| {
+            let bytes = bs58::decode(value).into_vec().unwrap_or_default();
+            <[u8; 32]>::try_from(bytes)
+                .ok()
+                .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+                .ok_or_else(|| {
+                    let reason = format!("invalid --trusted-key '{}'", value);
+                    io::Error::new(ErrorKind::InvalidInput, reason)
+                })
+        })
+        .collect()
+}
+
+/// CLI flag making the node trust its peers to send only blocks with a valid proof-of-work,
+/// such that syncing (and importing) verifies it only for the last block, e.g. for a node
+/// connected exclusively to the other nodes of its operator. All other rules are still checked.
+/// Only use it with trusted peers: any peer can then extend the chain with blocks mined at no
+/// cost, as long as it sends them along with a properly mined one.
+const TRUST_PEERS_FLAG: &str = "--trust-peers";
+
+/// CLI flag overriding the maximum number of pending transactions, e.g. `--max-mempool=500`.
+const MAX_MEMPOOL_FLAG: &str = "--max-mempool=";
+
+/// CLI flag overriding the maximum size of all pending transactions in bytes,
+/// e.g. `--max-mempool-bytes=1048576`.
+const MAX_MEMPOOL_BYTES_FLAG: &str = "--max-mempool-bytes=";
+
+/// Parses the number given by the flag, e.g. the [MAX_MEMPOOL_FLAG], if any.
+fn number_flag(args: &[String], flag: &str) -> io::Result<Option<usize>> {
+    let Some(value) = args.iter().find_map(|a| a.strip_prefix(flag)) else {
+        return Ok(None);
+    };
+    value.parse().map(Some).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid {}'{}': {}", flag, value, e),
+        )
+    })
+}
+
+/// CLI flag setting the directory in which a node keeps state across restarts,
+/// e.g. `--data-dir=./node-1`. Without it, nothing is persisted.
+const DATA_DIR_FLAG: &str = "--data-dir=";
+
+/// CLI flag switching the chain to pruning mode, in which blocks only keep their transactions
+/// for the given number of blocks on top, e.g. `--keep-depth=1000`, see
+/// [BlockChain::set_keep_depth]. A restored chain keeps pruning as it did when saved.
+const KEEP_DEPTH_FLAG: &str = "--keep-depth=";
+
+/// CLI flag naming a chain saved by [BlockChain::save_to_path] to import once the node runs,
+/// e.g. `--import=./snapshot.bin`, see [Node::import_chain]. A running node imports on
+/// [Message::Import].
+const IMPORT_FLAG: &str = "--import=";
+
+/// The file in the data directory holding the pending transactions.
+const MEMPOOL_FILE: &str = "mempool.bin";
+
+/// The file in the data directory holding all blocks received so far.
+const CHAIN_FILE: &str = "chain.bin";
+
+/// The file in the data directory holding the known peers.
+const PEERS_FILE: &str = "peers.bin";
+
+/// How often a node with a data directory persists its state, see [Node::persist].
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    // read initial peer address from the CLI arguments
+    let args: Vec<String> = std::env::args().collect();
+    let initial_peers: Vec<SocketAddr> = args.iter().filter_map(|s| s.parse().ok()).collect();
+    let policy = if args.iter().any(|a| a == "--observer") {
+        MiningPolicy::Observer
+    } else if args.iter().any(|a| a == "--solo") {
+        MiningPolicy::Solo
+    } else {
+        MiningPolicy::Networked
+    };
+
+    // Bind to the requested address, or try the default ports and take a random port
+    // if they are all in use
+    let listener = match listen_address(&args, std::env::var(LISTEN_ADDR_VAR).ok())? {
+        Some(addr) => TcpListener::bind(addr).await?,
+        None => bind_default(DEFAULT_PORTS).await?,
+    };
+    // Always announce the address we actually got
+    let address = listener.local_addr()?;
+    println!(
+        "Node started at {} with initial peers: {:?}",
+        address, initial_peers
+    );
+
+    // The entire (thread-safe) state of the node
+    println!("Mining policy: {:?}", policy);
+    let mut node = Node::with_policy(address, &initial_peers, policy);
+    if let Some(miner) = miner_address(&args)? {
+        node.miner = miner;
+    }
+    println!("Mining rewards go to {}", node.miner.encode());
+    node.coinbase_data = coinbase_data(&args)?;
+    if let Some(max) = number_flag(&args, MAX_MEMPOOL_FLAG)? {
+        node.max_mempool = max;
+    }
+    if let Some(max) = number_flag(&args, MAX_MEMPOOL_BYTES_FLAG)? {
+        node.max_mempool_bytes = max;
+    }
+    node.trusted_keys = trusted_keys(&args)?;
+    node.trust_peers_pow = args.iter().any(|a| a == TRUST_PEERS_FLAG);
+    if node.trust_peers_pow {
+        println!("Trusting peers: only the last synchronised block's proof-of-work is verified");
+    }
+
+    // Restore the chain, the transactions that were pending and the peers known at the last
+    // shutdown
+    let data_dir = match args.iter().find_map(|a| a.strip_prefix(DATA_DIR_FLAG)) {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            Some(Path::new(dir).to_path_buf())
+        }
+        None => None,
+    };
+    let data_file = |file: &str| data_dir.as_ref().map(|dir| dir.join(file));
+    if let Some(file) = data_file(CHAIN_FILE).filter(|f| f.exists()) {
+        // an empty or corrupt file must not keep the node from starting, but must not be
+        // overwritten by the next persist either: move it aside (or refuse to start if that fails)
+        match BlockChain::load_from_path(&file, ChainParams::MAINNET) {
+            Ok(chain) => {
+                println!(
+                    "Restored {} blocks from {}",
+                    chain.main_chain_length(),
+                    file.display()
+                );
+                node.chain = chain;
+            }
+            Err(e) => {
+                let aside = file.with_extension("bin.bad");
+                fs::rename(&file, &aside)?;
+                println!(
+                    "Failed to restore chain, moved it to {} and starting from genesis: {}",
+                    aside.display(),
+                    e
+                );
+            }
+        }
+    }
+    if let Some(depth) = number_flag(&args, KEEP_DEPTH_FLAG)? {
+        println!("Pruning blocks with more than {} on top", depth);
+        node.chain.set_keep_depth(Some(depth));
+    }
+    if let Some(file) = data_file(MEMPOOL_FILE).filter(|f| f.exists()) {
+        match node.load_mempool(&file) {
+            Ok(count) => println!("Restored {} txs from {}", count, file.display()),
+            Err(e) => println!("Failed to restore mempool: {}", e),
+        }
+    }
+    if let Some(file) = data_file(PEERS_FILE).filter(|f| f.exists()) {
+        match node.load_peers(&file) {
+            Ok(count) => println!("Restored {} peers from {}", count, file.display()),
+            Err(e) => println!("Failed to restore peers: {}", e),
+        }
+    }
+    let node_state = Arc::new(RwLock::new(node));
+    let miner = Arc::new(RwLock::new(Miner::default()));
+
+    // Announce ourselves to network and catch up with the blocks mined so far
+    broadcast(node_state.clone(), &Message::Connect(address)).await;
+    let from_height = node_state.read().await.chain.main_chain_length();
+    tokio::spawn(catch_up(node_state.clone(), miner.clone(), from_height));
+
+    // Log all changes of the mempool
+    let mut mempool_events = node_state.read().await.subscribe_mempool();
+    tokio::spawn(async move {
+        loop {
+            match mempool_events.recv().await {
+                Ok(event) => println!("Mempool {:?}", event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // Log the state of the chain whenever a block is added
+    let status_handle = node_state.clone();
+    let mut block_events = node_state.read().await.subscribe_blocks();
+    tokio::spawn(async move {
+        loop {
+            match block_events.recv().await {
+                Ok(_) => {
+                    let node = status_handle.read().await;
+                    println!(
+                        "Chain at {} (height {}, {} blocks incl. forks, {} orphans)",
+                        node.chain.highest_block_hash().encode(),
+                        node.chain.main_chain_length() - 1,
+                        node.chain.total_blocks(),
+                        node.chain.orphan_count()
+                    );
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // Periodically re-announce ourselves to the seeds in case we lost all peers
+    let reconnect_handle = node_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RECONNECT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if reconnect_handle.write().await.reconnect() {
+                println!("Lost all peers, reconnecting to seeds");
+                let message = Message::Connect(address);
+                broadcast(reconnect_handle.clone(), &message).await;
+            }
+        }
+    });
+
+    // Periodically warn about pending transactions that are unlikely to be mined
+    let stuck_handle = node_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STUCK_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let node = stuck_handle.read().await;
+            let stuck = (node.mempool.keys()).filter(|hash| node.is_transaction_stuck(hash));
+            for hash in stuck {
+                println!(
+                    "Transaction {} looks stuck, its fee is too low",
+                    hash.encode()
+                );
+            }
+        }
+    });
+
+    // Save the chain, mempool and peers periodically and on Ctrl-C, such that they survive
+    // a restart (or crash)
+    if let Some(dir) = data_dir {
+        let persist_handle = node_state.clone();
+        let persist_dir = dir.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+            // the first tick completes immediately, but nothing changed yet
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = persist_handle.read().await.persist(&persist_dir) {
+                    println!("Failed to persist state: {}", e);
+                }
+            }
+        });
+
+        let shutdown_handle = node_state.clone();
+        let sync_canceller = node_state.read().await.sync_canceller();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                // don't wait for a long sync to finish
+                sync_canceller.store(true, Ordering::Relaxed);
+                if let Err(e) = shutdown_handle.read().await.persist(&dir) {
+                    println!("Failed to persist state: {}", e);
+                }
+                std::process::exit(0);
             }
         });
     }
-    Ok(())
+
+    // Import a snapshot while already processing messages, as it may take a while
+    if let Some(file) = args.iter().find_map(|a| a.strip_prefix(IMPORT_FLAG)) {
+        let file = Path::new(file).to_path_buf();
+        let import_handle = node_state.clone();
+        tokio::spawn(async move {
+            match import_handle.write().await.import_chain(&file) {
+                Ok((tip, height)) => println!(
+                    "Imported {}, the main chain now ends at {} (height {})",
+                    file.display(),
+                    tip.encode(),
+                    height - 1
+                ),
+                Err(e) => println!("Failed to import {}: {}", file.display(), e),
+            }
+        });
+    }
+
+    println!("Starting to process...");
+    serve(listener, node_state, miner).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::MiningCommand::{Keep, Restart, Start};
+    use crate::{
+        bind_default, coinbase_data, leading_blocks, listen_address, miner_address, number_flag,
+        serialized_size, serve, trusted_keys, unix_time, MempoolEvent, Miner, MiningPolicy, Node,
+        BURST_BLOCKS, CHAIN_FILE, MAX_MEMPOOL_BYTES_FLAG, MAX_PEERS, MAX_PEER_FAILURES,
+        MAX_SYNC_BLOCKS, MEMPOOL_FILE, PEERS_FILE,
+    };
+    use repyh_proof_of_work::{
+        address_of, block_reward, AddResult, Address, B58Encode, Batch, Block, BlockChain,
+        ChainParams, Hashable, HexEncode, Message, MessageTooLong, Pruned, Transaction,
+        Transactions, GLOBAL_DIFFICULTY, MAX_COINBASE_DATA, MAX_MESSAGE_LEN, MAX_TXS,
+    };
+    use std::io;
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpSocket, TcpStream};
+    use tokio::sync::RwLock;
+
+    /// The address test blocks pay their reward to.
+    const DUMMY_MINER: Address = [0; 32];
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn wire_payloads_are_deterministic() {
+        let peers: Vec<SocketAddr> = (7001..7020).map(addr).collect();
+        let reversed: Vec<SocketAddr> = peers.iter().rev().cloned().collect();
+        let txs = Transaction::signed_dummy_txs(13);
+
+        let mut node = Node::new(addr(7000), &peers);
+        let mut other = Node::new(addr(7000), &reversed);
+        // the nonces fix the order of a spender's transactions, but not how they arrive
+        node.handle(Message::Tx(Transactions(txs.clone())));
+        for chunk in txs.chunks(4) {
+            other.handle(Message::Tx(Transactions(chunk.to_vec())));
+        }
+
+        let (reply, _) = node.handle(Message::Connect(addr(8000)));
+        let (other_reply, _) = other.handle(Message::Connect(addr(8000)));
+        assert_eq!(
+            Vec::<u8>::from(&reply.unwrap()),
+            Vec::<u8>::from(&other_reply.unwrap())
+        );
+
+        let candidates = Message::Tx(Transactions(node.mining_candidates()));
+        let other_candidates = Message::Tx(Transactions(other.mining_candidates()));
+        assert_eq!(
+            Vec::<u8>::from(&candidates),
+            Vec::<u8>::from(&other_candidates)
+        );
+    }
+
+    #[test]
+    fn reconnects_to_seeds() {
+        let seeds = [addr(7001), addr(7002)];
+        let mut node = Node::new(addr(7000), &seeds);
+        assert!(!node.reconnect());
+
+        node.peers.clear();
+        assert!(node.reconnect());
+        assert_eq!(node.peers, seeds.into_iter().collect());
+
+        // without seeds, there is nobody to reconnect to
+        let mut lonely = Node::new(addr(7000), &[]);
+        assert!(!lonely.reconnect());
+    }
+
+    #[test]
+    fn mempool_events() {
+        let mut node = Node::new(addr(7000), &[]);
+        let mut events = node.subscribe_mempool();
+        let txs = Transactions(Transaction::signed_dummy_txs(1));
+        let hash = txs.0[0].hash();
+
+        node.handle(Message::Tx(txs.clone()));
+        assert_eq!(events.try_recv(), Ok(MempoolEvent::TxAdded(hash)));
+
+        let block = Block::mine_new(
+            node.chain.highest_block().hash(),
+            GLOBAL_DIFFICULTY,
+            txs,
+            DUMMY_MINER,
+        );
+        node.handle(Message::NewBlock(block));
+        assert_eq!(events.try_recv(), Ok(MempoolEvent::TxRemoved(hash)));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn reorg_returns_txs_to_mempool() {
+        let txs = Transaction::signed_dummy_txs(3);
+        let mut node = Node::new(addr(7000), &[]);
+        node.handle(Message::Tx(Transactions(txs.clone())));
+        let genesis = Block::genesis(&ChainParams::MAINNET);
+        let time = genesis.header.timestamp() + 10;
+        let mine = |parent: &Block, txs: &[Transaction], miner: Address, time: u64| {
+            let txs = Transactions(txs.to_vec());
+            Block::mine_at(parent.hash(), GLOBAL_DIFFICULTY, txs, miner, time)
+        };
+        let pending = |node: &Node| node.mempool.values().cloned().collect::<Vec<_>>();
+
+        let a = mine(&genesis, &txs[..2], DUMMY_MINER, time);
+        assert!(node.add_block(&a));
+        assert_eq!(pending(&node), vec![txs[2].clone()]);
+
+        // a longer fork takes over, only committing the first transaction
+        let other_a = mine(&genesis, &txs[..1], [1; 32], time);
+        assert!(node.add_block(&other_a));
+        assert_eq!(pending(&node), vec![txs[2].clone()]);
+        let other_b = mine(&other_a, &[], [1; 32], time + 10);
+        assert!(node.add_block(&other_b));
+        assert_eq!(node.chain.highest_block(), &other_b);
+        let mut expected = vec![txs[1].clone(), txs[2].clone()];
+        expected.sort_by_key(|tx| tx.hash());
+        assert_eq!(pending(&node), expected);
+
+        // ... until the original chain overtakes it again
+        let b = mine(&a, &txs[2..], DUMMY_MINER, time + 10);
+        let c = mine(&b, &[], DUMMY_MINER, time + 20);
+        assert!(node.add_block(&b));
+        assert!(node.add_block(&c));
+        assert_eq!(node.chain.highest_block(), &c);
+        assert!(pending(&node).is_empty());
+    }
+
+    #[test]
+    fn observer_never_mines() {
+        let mut node = Node::new(addr(7000), &[addr(7001)]);
+        let mut observer = Node::with_policy(addr(7001), &[addr(7000)], MiningPolicy::Observer);
+        let txs = Transactions(Transaction::signed_dummy_txs(2));
+        let block = Block::mine_new(
+            Block::genesis(&ChainParams::MAINNET).hash(),
+            GLOBAL_DIFFICULTY,
+            txs.clone(),
+            DUMMY_MINER,
+        );
+
+        assert_eq!(node.handle(Message::Tx(txs.clone())).1, Start);
+        let (reply, cmd) = observer.handle(Message::Tx(txs));
+        assert!(matches!(reply, Some(Message::Tx(_))));
+        assert_eq!(cmd, Keep);
+
+        node.begin_mining().unwrap();
+        let cancelled = node.mining_cancelled.clone();
+        assert_eq!(node.handle(Message::NewBlock(block.clone())).1, Restart);
+        // the outdated block stops being solved
+        assert!(cancelled.load(Ordering::Relaxed));
+        let (reply, cmd) = observer.handle(Message::NewBlock(block.clone()));
+        assert!(matches!(reply, Some(Message::NewBlock(b)) if b == block));
+        assert_eq!(cmd, Keep);
+        assert_eq!(observer.chain.highest_block(), &block);
+        assert!(observer.mempool.is_empty());
+    }
+
+    #[test]
+    fn peers_are_bounded() {
+        let mut node = Node::new(addr(7000), &[addr(7001)]);
+        node.handle(Message::Addr((8000..9000).map(addr).collect()));
+        assert_eq!(node.peers.len(), MAX_PEERS);
+        // the most recently announced peers displaced the older ones
+        let latest = 9000 - MAX_PEERS as u16;
+        assert_eq!(node.peers, (latest..9000).map(addr).collect());
+
+        // known peers are refreshed, but not announced again
+        for port in latest..9000 {
+            assert!(node.handle(Message::Connect(addr(port))).0.is_none());
+        }
+        node.handle(Message::Addr((latest..9000).map(addr).collect()));
+        assert_eq!(node.peers.len(), MAX_PEERS);
+
+        let (reply, _) = node.handle(Message::Connect(addr(9000)));
+        assert!(matches!(reply, Some(Message::Addr(_))));
+        assert_eq!(node.peers.len(), MAX_PEERS);
+        assert!(node.peers.contains(&addr(9000)));
+        assert!(!node.peers.contains(&addr(latest)));
+    }
+
+    #[test]
+    fn would_accept_peer() {
+        let mut node = Node::new(addr(7000), &[addr(7001)]);
+        assert!(!node.would_accept_peer(&addr(7000)));
+        assert!(node.would_accept_peer(&addr(7002)));
+
+        node.ban_peer(addr(7001));
+        assert!(!node.would_accept_peer(&addr(7001)));
+        assert!(!node.peers.contains(&addr(7001)));
+        node.handle(Message::Addr(vec![addr(7001), addr(7002)]));
+        assert_eq!(node.peers, [addr(7002)].into_iter().collect());
+
+        // a full node accepts a new peer in place of the stalest one
+        node.handle(Message::Addr((8000..).map(addr).take(MAX_PEERS).collect()));
+        assert_eq!(node.peers.len(), MAX_PEERS);
+        assert!(node.would_accept_peer(&addr(9000)));
+        assert!(node.add_peer(addr(9000)));
+        assert_eq!(node.peers.len(), MAX_PEERS);
+        assert!(!node.peers.contains(&addr(8000)));
+        assert!(!node.would_accept_peer(&addr(7001)));
+    }
+
+    #[test]
+    fn checkpoints_need_a_trusted_signature() {
+        let trusted = Transaction::dummy_key(7);
+        let mut node = Node::new(addr(7000), &[]);
+        node.trusted_keys = vec![trusted.verifying_key()];
+        let genesis = Block::genesis(&ChainParams::MAINNET).hash();
+        let txs = Transactions(Transaction::signed_dummy_txs(1));
+        let pinned = Block::mine_new(genesis, GLOBAL_DIFFICULTY, txs.clone(), DUMMY_MINER);
+        let other = Block::mine_new(genesis, GLOBAL_DIFFICULTY, txs, [1; 32]);
+
+        let untrusted = Message::checkpoint(1, pinned.hash(), &Transaction::dummy_key(8));
+        assert!(node.handle(untrusted).0.is_none());
+        let Message::Checkpoint { signature, .. } = Message::checkpoint(1, pinned.hash(), &trusted)
+        else {
+            unreachable!()
+        };
+        let tampered = Message::Checkpoint {
+            height: 1,
+            hash: other.hash(),
+            signature,
+        };
+        assert!(node.handle(tampered).0.is_none());
+
+        let checkpoint = Message::checkpoint(1, pinned.hash(), &trusted);
+        let (reply, _) = node.handle(checkpoint);
+        assert!(matches!(reply, Some(Message::Checkpoint { hash, .. }) if hash == pinned.hash()));
+        // known checkpoints are not relayed again
+        let checkpoint = Message::checkpoint(1, pinned.hash(), &trusted);
+        assert!(node.handle(checkpoint).0.is_none());
+
+        assert!(!node.add_block(&other));
+        assert!(node.add_block(&pinned));
+    }
+
+    #[test]
+    fn trusted_keys_flag() {
+        let key = Transaction::dummy_key(7).verifying_key();
+        let flag = format!(
+            "--trusted-key={}",
+            bs58::encode(key.as_bytes()).into_string()
+        );
+        assert_eq!(trusted_keys(&[flag]).unwrap(), vec![key]);
+        assert!(trusted_keys(&["--trusted-key=abc".to_string()]).is_err());
+    }
+
+    #[test]
+    fn mining_candidates_keep_batches_whole() {
+        let batch = Batch {
+            id: [3; 32],
+            size: 3,
+        };
+        let txs: Vec<Transaction> = Transaction::signed_dummy_txs(5)
+            .into_iter()
+            .enumerate()
+            .map(|(i, tx)| match i {
+                // re-sign, as joining the batch changes the transaction
+                2..5 => {
+                    let mut tx = tx.with_batch(batch);
+                    tx.sign(&Transaction::dummy_key(0));
+                    tx
+                }
+                _ => tx,
+            })
+            .collect();
+
+        // an incomplete batch is not mined at all
+        let mut node = Node::new(addr(7000), &[]);
+        node.handle(Message::Tx(Transactions(txs[..4].to_vec())));
+        let candidates = node.mining_candidates();
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates.iter().all(|tx| tx.batch().is_none()));
+
+        // ... once complete, it is mined as a whole
+        node.handle(Message::Tx(Transactions(txs[4..].to_vec())));
+        let candidates = Transactions(node.mining_candidates());
+        assert_eq!(candidates.0.len(), 5);
+        assert!(candidates.has_complete_batches());
+    }
+
+    /// Funds the dummy keys 1 to 4 with 1 to 4 "coin" in a block on top of genesis.
+    fn fund_dummy_keys(node: &mut Node) {
+        let genesis = Block::genesis(&ChainParams::MAINNET);
+        let funding = Transactions(Transaction::signed_dummy_txs(4));
+        let time = genesis.header.timestamp() + 1;
+        let block = Block::mine_at(
+            genesis.hash(),
+            GLOBAL_DIFFICULTY,
+            funding,
+            DUMMY_MINER,
+            time,
+        );
+        assert!(node.add_block(&block));
+    }
+
+    /// The first transaction of dummy key `i`, sending 1 "coin" and paying the given fee.
+    fn dummy_tx_with_fee(i: u32, fee: u32) -> Transaction {
+        let tx = Transaction::signed_dummy_txs_from(i, 1).remove(0);
+        let mut tx = tx.with_fee(fee);
+        tx.sign(&Transaction::dummy_key(i));
+        tx
+    }
+
+    #[test]
+    fn mempool_evicts_lowest_fees() {
+        let mut node = Node::new(addr(7000), &[]);
+        fund_dummy_keys(&mut node);
+        // key `i` sends 1 "coin" and pays a fee of `i - 1`
+        let txs: Vec<Transaction> = (1..=4).map(|i| dummy_tx_with_fee(i, i - 1)).collect();
+        node.max_mempool = 3;
+        let mut events = node.subscribe_mempool();
+
+        node.handle(Message::Tx(Transactions(txs.clone())));
+        assert_eq!(node.mempool_len(), 3);
+        let fees: Vec<u32> = node.mining_candidates().iter().map(|tx| tx.fee()).collect();
+        assert_eq!(fees, vec![3, 2, 1]);
+        let evicted: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| matches!(event, MempoolEvent::TxRemoved(_)))
+            .collect();
+        assert_eq!(evicted, vec![MempoolEvent::TxRemoved(txs[0].hash())]);
+
+        // a transaction paying less than all pending ones doesn't get in
+        let (reply, _) = node.handle(Message::Tx(Transactions(txs[..1].to_vec())));
+        assert!(reply.is_none());
+        assert!(!node.mempool.contains_key(&txs[0].hash()));
+        assert_eq!(node.mempool_len(), 3);
+
+        // the miner collects the fees
+        let (mut header, txs) = node.begin_mining().unwrap();
+        header.nonce = header.solve_parallel(1, &AtomicBool::new(false)).unwrap();
+        let block = Block {
+            header,
+            transactions: Transactions(txs),
+        };
+        assert!(node.add_block(&block));
+        assert_eq!(
+            node.chain.balance_of(&node.miner),
+            100 - (1 + 2 + 3 + 4) + 100 + (1 + 2 + 3)
+        );
+    }
+
+    #[test]
+    fn nonces_reject_replays_and_gaps() {
+        let txs = Transaction::signed_dummy_txs(3);
+        let mut node = Node::new(addr(7000), &[]);
+        // the third transaction can't skip the second
+        let gap = Transactions(vec![txs[0].clone(), txs[2].clone()]);
+        let (reply, _) = node.handle(Message::Tx(gap));
+        assert!(matches!(reply, Some(Message::Tx(relayed)) if relayed.0 == txs[..1]));
+        node.handle(Message::Tx(Transactions(txs[1..].to_vec())));
+        assert_eq!(node.mempool_len(), 3);
+
+        let genesis = Block::genesis(&ChainParams::MAINNET);
+        let time = genesis.header.timestamp() + 1;
+        let committed = Transactions(txs[..2].to_vec());
+        let block = Block::mine_at(
+            genesis.hash(),
+            GLOBAL_DIFFICULTY,
+            committed,
+            DUMMY_MINER,
+            time,
+        );
+        assert!(node.add_block(&block));
+        assert_eq!(node.chain.nonce_of(txs[0].spender()), 2);
+
+        // a committed nonce can't be used again, even by an otherwise different transaction
+        let mut replay = txs[0].clone().with_fee(1);
+        replay.sign(&Transaction::dummy_key(0));
+        let replays = Transactions(vec![txs[0].clone(), replay]);
+        assert!(node.handle(Message::Tx(replays)).0.is_none());
+        assert_eq!(node.mempool_len(), 1);
+
+        // a pending transaction whose nonce gets committed by another one is dropped
+        let mut other = txs[2].clone().with_fee(1);
+        other.sign(&Transaction::dummy_key(0));
+        let committed = Transactions(vec![other]);
+        let next = Block::mine_at(
+            block.hash(),
+            GLOBAL_DIFFICULTY,
+            committed,
+            DUMMY_MINER,
+            time + 1,
+        );
+        assert!(node.add_block(&next));
+        assert_eq!(node.mempool_len(), 0);
+    }
+
+    #[test]
+    fn mining_candidates_follow_nonces() {
+        let mut txs = Transaction::signed_dummy_txs(3);
+        // the later transactions pay more, but must wait for the first
+        for (i, tx) in txs.iter_mut().enumerate() {
+            *tx = tx.clone().with_fee(i as u32);
+            tx.sign(&Transaction::dummy_key(0));
+        }
+        let mut node = Node::new(addr(7000), &[]);
+        node.handle(Message::Tx(Transactions(txs.clone())));
+        assert_eq!(node.mining_candidates(), txs);
+    }
+
+    #[test]
+    fn high_fee_child_lifts_low_fee_parent() {
+        let mut node = Node::new(addr(7000), &[]);
+        fund_dummy_keys(&mut node);
+        // the parent pays nothing, but its child pays more than any other transaction
+        let mut family = Transaction::signed_dummy_txs(6).split_off(4);
+        for (tx, fee) in family.iter_mut().zip([0, 10]) {
+            *tx = tx.clone().with_fee(fee);
+            tx.sign(&Transaction::dummy_key(0));
+        }
+        let others = vec![dummy_tx_with_fee(2, 1), dummy_tx_with_fee(3, 2)];
+        node.handle(Message::Tx(Transactions(others.clone())));
+        node.handle(Message::Tx(Transactions(family.clone())));
+
+        let expected = [&family[..], &[others[1].clone(), others[0].clone()]].concat();
+        assert_eq!(node.mining_candidates(), expected);
+
+        // without the child, the parent is taken last
+        node.remove_from_mempool(&family[1].hash());
+        let expected = vec![others[1].clone(), others[0].clone(), family[0].clone()];
+        assert_eq!(node.mining_candidates(), expected);
+
+        // the child also keeps the parent from being evicted
+        node.handle(Message::Tx(Transactions(family[1..].to_vec())));
+        node.max_mempool = 4;
+        node.handle(Message::Tx(Transactions(vec![dummy_tx_with_fee(4, 3)])));
+        assert!(node.mempool.contains_key(&family[0].hash()));
+        assert!(!node.mempool.contains_key(&others[0].hash()));
+    }
+
+    #[test]
+    fn stuck_transactions() {
+        let mut node = Node::new(addr(7000), &[]);
+        fund_dummy_keys(&mut node);
+        // the funding block's transactions pay nothing, the next block's a fee of 1
+        let mut paying = Transaction::signed_dummy_txs(5).remove(4).with_fee(1);
+        paying.sign(&Transaction::dummy_key(0));
+        let parent = node.chain.highest_block();
+        let time = parent.header.timestamp() + 1;
+        let block = Block::mine_at(
+            parent.hash(),
+            GLOBAL_DIFFICULTY,
+            Transactions(vec![paying]),
+            DUMMY_MINER,
+            time,
+        );
+        assert!(node.add_block(&block));
+
+        let (low, high) = (dummy_tx_with_fee(2, 0), dummy_tx_with_fee(3, 2));
+        node.handle(Message::Tx(Transactions(vec![low.clone(), high.clone()])));
+        assert!(!node.is_transaction_stuck(&low.hash()));
+        assert!(!node.is_transaction_stuck(&high.hash()));
+
+        // a day later, only the one paying less than recent blocks is stuck
+        let day_ago = unix_time() - 24 * 60 * 60;
+        node.received
+            .values_mut()
+            .for_each(|received| *received = day_ago);
+        assert!(node.is_transaction_stuck(&low.hash()));
+        assert!(!node.is_transaction_stuck(&high.hash()));
+        assert!(!node.is_transaction_stuck(&[0; 32]));
+
+        node.remove_from_mempool(&low.hash());
+        assert!(!node.is_transaction_stuck(&low.hash()));
+        assert_eq!(node.received.len(), 1);
+    }
+
+    #[test]
+    fn mining_status() {
+        let mut node = Node::new(addr(7000), &[]);
+        assert!(!node.mining_status().active);
+        assert!(node.begin_mining().is_none());
+        assert!(!node.mining_status().active);
+
+        // fees overflowing the coinbase's amount don't make a block, so nothing is mined
+        let key = Transaction::dummy_key(0);
+        let mut greedy = Transaction::signed_dummy_txs(1)
+            .remove(0)
+            .with_fee(u32::MAX);
+        greedy.sign(&key);
+        node.mempool.insert(greedy.hash(), greedy.clone());
+        assert!(node.begin_mining().is_none());
+        assert!(!node.mining_status().active);
+        node.mempool.remove(&greedy.hash());
+
+        node.handle(Message::Tx(Transactions(Transaction::signed_dummy_txs(3))));
+        let (header, txs) = node.begin_mining().unwrap();
+        assert_eq!(
+            header.prev_block_hash,
+            Block::genesis(&ChainParams::MAINNET).hash()
+        );
+        assert_eq!(txs.len(), 4);
+        let coinbase = &txs[0];
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.receiver(), &node.miner);
+        assert_eq!(coinbase.amount(), block_reward(1));
+        assert_eq!(coinbase.timestamp(), header.timestamp());
+        let status = node.mining_status();
+        assert!(status.active);
+        assert_eq!(status.parent_hash, Some(header.prev_block_hash));
+        assert!(status.started_at.is_some());
+    }
+
+    #[test]
+    fn mempool_bytes() {
+        let txs = Transaction::signed_dummy_txs(4);
+        let size = |txs: &[Transaction]| txs.iter().map(serialized_size).sum::<usize>();
+        let mut node = Node::new(addr(7000), &[]);
+        assert_eq!(node.mempool_bytes(), 0);
+
+        node.handle(Message::Tx(Transactions(txs[..2].to_vec())));
+        assert_eq!(node.mempool_bytes(), size(&txs[..2]));
+        assert!(node.mempool_bytes() > 0);
+
+        // without fees, no pending transaction gets evicted to make room
+        node.max_mempool_bytes = size(&txs[..3]);
+        node.handle(Message::Tx(Transactions(txs.clone())));
+        assert_eq!(node.mempool_len(), 3);
+        assert_eq!(node.mempool_bytes(), size(&txs[..3]));
+
+        let block = Block::mine_new(
+            Block::genesis(&ChainParams::MAINNET).hash(),
+            GLOBAL_DIFFICULTY,
+            Transactions(txs[..1].to_vec()),
+            DUMMY_MINER,
+        );
+        assert!(node.add_block(&block));
+        assert_eq!(node.mempool_bytes(), size(&txs[1..3]));
+
+        assert_eq!(number_flag(&[], MAX_MEMPOOL_BYTES_FLAG).unwrap(), None);
+        let flag = "--max-mempool-bytes=1024".to_string();
+        assert_eq!(
+            number_flag(&[flag], MAX_MEMPOOL_BYTES_FLAG).unwrap(),
+            Some(1024)
+        );
+        let flag = "--max-mempool-bytes=1MiB".to_string();
+        assert!(number_flag(&[flag], MAX_MEMPOOL_BYTES_FLAG).is_err());
+    }
+
+    #[test]
+    fn import_chain() {
+        let path = std::env::temp_dir().join(format!("pow-import-{}.bin", std::process::id()));
+        let txs = Transaction::signed_dummy_txs(4);
+        let genesis = Block::genesis(&ChainParams::MAINNET);
+        let time = genesis.header.timestamp() + 1;
+        let committed = Transactions(txs[..2].to_vec());
+        let first = Block::mine_at(
+            genesis.hash(),
+            GLOBAL_DIFFICULTY,
+            committed,
+            DUMMY_MINER,
+            time,
+        );
+        let committed = Transactions(txs[2..].to_vec());
+        let second = Block::mine_at(
+            first.hash(),
+            GLOBAL_DIFFICULTY,
+            committed,
+            DUMMY_MINER,
+            time + 1,
+        );
+        let mut snapshot = Node::new(addr(7001), &[]);
+        assert!(snapshot.add_block(&first) && snapshot.add_block(&second));
+        snapshot.chain.save_to_path(&path).unwrap();
+
+        // the live node already has the first block and pending transactions
+        let mut node = Node::new(addr(7000), &[]);
+        node.handle(Message::Tx(Transactions(txs.clone())));
+        assert!(node.add_block(&first));
+        assert_eq!(node.import_chain(&path).unwrap(), (second.hash(), 3));
+        assert_eq!(node.chain.highest_block(), &second);
+        assert_eq!(node.mempool_len(), 0);
+
+        std::fs::write(&path, b"garbage").unwrap();
+        let err = node.import_chain(&path).err();
+        assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import_on_request() {
+        let path = std::env::temp_dir().join(format!("pow-request-{}.bin", std::process::id()));
+        let genesis = Block::genesis(&ChainParams::MAINNET);
+        let txs = Transactions(Transaction::signed_dummy_txs(2));
+        let time = genesis.header.timestamp() + 1;
+        let block = Block::mine_at(genesis.hash(), GLOBAL_DIFFICULTY, txs, DUMMY_MINER, time);
+        let mut snapshot = Node::new(addr(7001), &[]);
+        assert!(snapshot.add_block(&block));
+        snapshot.chain.save_to_path(&path).unwrap();
+
+        let listener = TcpListener::bind(addr(0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let node_state = Arc::new(RwLock::new(Node::new(address, &[])));
+        let server = tokio::spawn(serve(listener, node_state.clone(), Default::default()));
+
+        let reply = Message::Import(path.clone()).request(&address).await;
+        let Ok(Message::Imported(imported)) = reply else {
+            panic!("imports are answered");
+        };
+        assert_eq!(imported, Ok((block.hash(), 2)));
+        assert_eq!(node_state.read().await.chain.highest_block(), &block);
+
+        std::fs::remove_file(&path).unwrap();
+        let reply = Message::Import(path).request(&address).await;
+        assert!(matches!(reply, Ok(Message::Imported(Err(_)))));
+        server.abort();
+    }
+
+    #[test]
+    fn miner_address_flag() {
+        assert_eq!(miner_address(&["node".to_string()]).unwrap(), None);
+        let flag = format!("--miner={}", DUMMY_MINER.encode());
+        assert_eq!(miner_address(&[flag]).unwrap(), Some(DUMMY_MINER));
+        assert!(miner_address(&["--miner=abc".to_string()]).is_err());
+        assert!(miner_address(&["--miner=0OIl".to_string()]).is_err());
+    }
+
+    #[test]
+    fn coinbase_data_flag() {
+        assert_eq!(coinbase_data(&["node".to_string()]).unwrap(), b"");
+        let flag = ["--coinbase-data=mined by us".to_string()];
+        assert_eq!(coinbase_data(&flag).unwrap(), b"mined by us");
+        let too_long = format!("--coinbase-data={}", "x".repeat(MAX_COINBASE_DATA + 1));
+        assert!(coinbase_data(&[too_long]).is_err());
+
+        // blocks mined by the node carry it
+        let mut node = Node::new(addr(7000), &[]);
+        node.coinbase_data = coinbase_data(&flag).unwrap();
+        node.handle(Message::Tx(Transactions(Transaction::signed_dummy_txs(1))));
+        let (_, txs) = node.begin_mining().unwrap();
+        assert_eq!(txs[0].data(), b"mined by us");
+    }
+
+    #[test]
+    fn listen_address_precedence() {
+        let flag = vec!["node".to_string(), "--listen=127.0.0.1:7100".to_string()];
+        let env = Some("127.0.0.1:7200".to_string());
+        assert_eq!(
+            listen_address(&flag, env.clone()).unwrap(),
+            Some(addr(7100))
+        );
+        assert_eq!(listen_address(&flag, None).unwrap(), Some(addr(7100)));
+        assert_eq!(listen_address(&flag[..1], env).unwrap(), Some(addr(7200)));
+        assert_eq!(listen_address(&flag[..1], None).unwrap(), None);
+
+        assert!(listen_address(&flag[..1], Some("localhost".to_string())).is_err());
+        let bad_flag = vec!["--listen=7000".to_string()];
+        assert!(listen_address(&bad_flag, None).is_err());
+    }
+
+    #[test]
+    fn mining_paused_without_peers() {
+        let txs = Transactions(Transaction::signed_dummy_txs(2));
+        let mut node = Node::new(addr(7000), &[]);
+        assert_eq!(node.handle(Message::Tx(txs.clone())).1, Keep);
+        assert_eq!(node.handle(Message::Connect(addr(7001))).1, Start);
+
+        let mut solo = Node::with_policy(addr(7000), &[], MiningPolicy::Solo);
+        assert_eq!(solo.handle(Message::Tx(txs)).1, Start);
+    }
+
+    #[tokio::test]
+    async fn bind_next_free_default_port() {
+        // occupy the first port of the range
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let listener = bind_default(port..port.saturating_add(10)).await.unwrap();
+        let bound = listener.local_addr().unwrap().port();
+        assert_ne!(bound, port);
+
+        // with the whole range taken, fall back to a random port
+        let listener = bind_default(port..port + 1).await.unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn rapid_restarts_keep_one_mining_task() {
+        /// Counts the mining tasks alive, and the most that ever were at the same time.
+        struct Alive(Arc<(AtomicUsize, AtomicUsize)>);
+        impl Alive {
+            fn new(counts: &Arc<(AtomicUsize, AtomicUsize)>) -> Self {
+                let alive = counts.0.fetch_add(1, Ordering::SeqCst) + 1;
+                counts.1.fetch_max(alive, Ordering::SeqCst);
+                Alive(counts.clone())
+            }
+        }
+        impl Drop for Alive {
+            fn drop(&mut self) {
+                self.0 .0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let mining = |counts: Arc<_>| async move {
+            let _alive = Alive::new(&counts);
+            std::future::pending::<io::Result<()>>().await
+        };
+
+        let mut miner = Miner::default();
+        miner.start(mining(counts.clone())).await;
+        for i in 0..50 {
+            miner.restart(mining(counts.clone())).await;
+            if i % 2 == 0 {
+                tokio::task::yield_now().await;
+            }
+            // starting while running is a no-op
+            miner.start(mining(counts.clone())).await;
+        }
+        tokio::task::yield_now().await;
+        assert!(miner.is_running());
+        assert_eq!(counts.0.load(Ordering::SeqCst), 1);
+        assert_eq!(counts.1.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn mempool_survives_restart() {
+        let path = std::env::temp_dir().join(format!("pow-mempool-{}.bin", std::process::id()));
+        let txs = Transaction::signed_dummy_txs(4);
+        let mut node = Node::new(addr(7000), &[]);
+        node.handle(Message::Tx(Transactions(txs.clone())));
+        node.save_mempool(&path).unwrap();
+
+        // the restarted node has meanwhile seen a block committing the first two txs
+        let mut restarted = Node::new(addr(7000), &[]);
+        let committed = Transactions(txs[..2].to_vec());
+        let block = Block::mine_new(
+            Block::genesis(&ChainParams::MAINNET).hash(),
+            GLOBAL_DIFFICULTY,
+            committed,
+            DUMMY_MINER,
+        );
+        assert!(restarted.add_block(&block));
+        assert_eq!(restarted.load_mempool(&path).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut pending: Vec<_> = restarted.mempool.into_values().collect();
+        pending.sort_by_key(|t| t.hash());
+        let mut expected = txs[2..].to_vec();
+        expected.sort_by_key(|t| t.hash());
+        assert_eq!(pending, expected);
+    }
+
+    #[test]
+    fn min_relay_fee() {
+        let mut node = Node::new(addr(7000), &[]);
+        let params = ChainParams {
+            min_relay_fee: 2,
+            ..ChainParams::MAINNET
+        };
+        node.chain = BlockChain::with_params(params);
+        fund_dummy_keys(&mut node);
+
+        let (reply, _) = node.handle(Message::Tx(Transactions(vec![dummy_tx_with_fee(1, 1)])));
+        assert!(reply.is_none());
+        assert_eq!(node.mempool_len(), 0);
+
+        let at_minimum = dummy_tx_with_fee(3, 2);
+        let (reply, _) = node.handle(Message::Tx(Transactions(vec![at_minimum.clone()])));
+        let Some(Message::Tx(relayed)) = reply else {
+            panic!("txs paying the minimum fee are relayed");
+        };
+        assert_eq!(relayed.0, vec![at_minimum]);
+        assert_eq!(node.mempool_len(), 1);
+    }
+
+    #[test]
+    fn unaffordable_txs_dropped_on_restart() {
+        let path =
+            std::env::temp_dir().join(format!("pow-unaffordable-{}.bin", std::process::id()));
+        let mut node = Node::new(addr(7000), &[]);
+        fund_dummy_keys(&mut node);
+        node.handle(Message::Tx(Transactions(vec![dummy_tx_with_fee(1, 0)])));
+        assert_eq!(node.mempool_len(), 1);
+        node.save_mempool(&path).unwrap();
+
+        // without the funding block, the spender can no longer afford the transaction
+        let mut restarted = Node::new(addr(7000), &[]);
+        assert_eq!(restarted.load_mempool(&path).unwrap(), 0);
+        assert_eq!(restarted.mempool_len(), 0);
+
+        fund_dummy_keys(&mut restarted);
+        assert_eq!(restarted.load_mempool(&path).unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn persist() {
+        let dir = std::env::temp_dir().join(format!("pow-persist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut node = Node::new(addr(7000), &[addr(7001), addr(7002)]);
+        fund_dummy_keys(&mut node);
+        node.handle(Message::Tx(Transactions(vec![dummy_tx_with_fee(1, 0)])));
+        node.persist(&dir).unwrap();
+        for file in [CHAIN_FILE, MEMPOOL_FILE, PEERS_FILE] {
+            assert!(dir.join(file).exists());
+        }
+
+        let restore = |dir: &Path| {
+            let mut restored = Node::new(addr(7000), &[]);
+            let chain = BlockChain::load_from_path(&dir.join(CHAIN_FILE), ChainParams::MAINNET);
+            restored.chain = chain.unwrap();
+            restored.load_mempool(&dir.join(MEMPOOL_FILE)).unwrap();
+            restored.load_peers(&dir.join(PEERS_FILE)).unwrap();
+            restored
+        };
+        let restored = restore(&dir);
+        assert_eq!(restored.chain.highest_block(), node.chain.highest_block());
+        assert_eq!(restored.mempool, node.mempool);
+        assert_eq!(restored.peers, node.peers);
+
+        // a write failing halfway (here the mempool's) leaves all previous files in place
+        node.handle(Message::Tx(Transactions(vec![dummy_tx_with_fee(2, 0)])));
+        node.add_peer(addr(7003));
+        std::fs::create_dir(dir.join(format!("{}.tmp", MEMPOOL_FILE))).unwrap();
+        assert!(node.persist(&dir).is_err());
+        let previous = restore(&dir);
+        assert_eq!(previous.mempool, restored.mempool);
+        assert_eq!(previous.peers, restored.peers);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unsigned_txs_dropped() {
+        let mut node = Node::new(addr(7000), &[]);
+        let mut txs = Transaction::signed_dummy_txs(2);
+        txs.extend(Transaction::dummy_txs(1));
+        let (reply, _) = node.handle(Message::Tx(Transactions(txs.clone())));
+        assert_eq!(node.mempool.len(), 2);
+        assert!(!node.mempool.contains_key(&txs[2].hash()));
+        // only the valid ones are relayed
+        let Some(Message::Tx(relayed)) = reply else {
+            panic!("valid txs are relayed");
+        };
+        assert_eq!(relayed.0, txs[..2]);
+    }
+
+    #[test]
+    fn repeated_txs_relayed_once() {
+        let mut node = Node::new(addr(7000), &[]);
+        let txs = Transaction::signed_dummy_txs(2);
+        let repeated = [&txs[..], &txs[..], &txs[..1]].concat();
+        let (reply, _) = node.handle(Message::Tx(Transactions(repeated)));
+        assert_eq!(node.mempool_len(), 2);
+        let Some(Message::Tx(relayed)) = reply else {
+            panic!("new txs are relayed");
+        };
+        assert_eq!(relayed.0, txs);
+    }
+
+    #[tokio::test]
+    async fn wait_for_confirmations() {
+        let txs = Transactions(Transaction::signed_dummy_txs(1));
+        let tx_hash = txs.0[0].hash();
+        let mut node = Node::new(addr(7000), &[]);
+        node.handle(Message::Tx(txs.clone()));
+        let node_state = Arc::new(RwLock::new(node));
+
+        let unknown = crate::wait_for_confirmations(node_state.clone(), [9; 32], 1);
+        assert!(unknown.await.is_err());
+        let waiting = tokio::spawn(crate::wait_for_confirmations(
+            node_state.clone(),
+            tx_hash,
+            3,
+        ));
+
+        // bury the transaction under two more blocks
+        let mut parent = Block::genesis(&ChainParams::MAINNET);
+        let others = [Transactions(vec![]), Transactions(vec![])];
+        for (i, txs) in [txs].into_iter().chain(others).enumerate() {
+            tokio::task::yield_now().await;
+            assert!(!waiting.is_finished());
+            let time = parent.header.timestamp() + 1;
+            let block = Block::mine_at(parent.hash(), GLOBAL_DIFFICULTY, txs, DUMMY_MINER, time);
+            assert!(node_state.write().await.add_block(&block));
+            assert_eq!(
+                node_state.read().await.chain.confirmations(&tx_hash),
+                Some(i + 1)
+            );
+            parent = block;
+        }
+        let confirmed = tokio::time::timeout(Duration::from_secs(5), waiting).await;
+        assert!(confirmed.unwrap().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn sync_blocks_over_loopback() {
+        let mut node = Node::new(addr(7000), &[]);
+        let mut parent = Block::genesis(&ChainParams::MAINNET);
+        for _ in 0..3 {
+            let txs = Transactions(vec![]);
+            let time = parent.header.timestamp() + 1;
+            let block = Block::mine_at(parent.hash(), GLOBAL_DIFFICULTY, txs, DUMMY_MINER, time);
+            assert!(node.add_block(&block));
+            parent = block;
+        }
+
+        // a late joiner asks for everything after genesis
+        let listener = TcpListener::bind(addr(0)).await.unwrap();
+        let late_addr = listener.local_addr().unwrap();
+        let (reply, _) = node.handle(Message::GetBlocks { from_height: 1 });
+        assert!(
+            reply.is_none(),
+            "requests are only answered on their connection"
+        );
+        let reply = node.answer(Message::GetBlocks { from_height: 1 }).unwrap();
+        assert!(matches!(&reply, Message::Blocks(blocks) if blocks.len() == 3));
+        reply.broadcast([late_addr].iter()).await.unwrap();
+
+        let mut late = Node::new(late_addr, &[addr(7000)]);
+        let (reply, cmd) = late.handle(accept_message(&listener).await.unwrap());
+        assert!(reply.is_none());
+        assert!(matches!(cmd, Restart));
+        assert_eq!(late.chain.highest_block(), node.chain.highest_block());
+
+        let beyond = node.chain.main_chain_length();
+        let reply = node.answer(Message::GetBlocks {
+            from_height: beyond,
+        });
+        assert!(matches!(reply, Some(Message::Blocks(blocks)) if blocks.is_empty()));
+    }
+
+    #[test]
+    fn sync_batches_are_capped() {
+        let mut node = Node::new(addr(7000), &[]);
+        node.chain = BlockChain::with_params(ChainParams::TESTING);
+        let mut parent = Block::genesis(&ChainParams::TESTING);
+        for _ in 0..MAX_SYNC_BLOCKS + 2 {
+            let time = parent.header.timestamp() + 1;
+            let block = Block::mine_at(parent.hash(), 0, Transactions(vec![]), DUMMY_MINER, time);
+            assert!(node.add_block(&block));
+            parent = block;
+        }
+
+        let Some(reply @ Message::Blocks(_)) = node.answer(Message::GetBlocks { from_height: 1 })
+        else {
+            panic!("block requests are answered");
+        };
+        assert!(Vec::<u8>::from(&reply).len() <= MAX_MESSAGE_LEN);
+        let Message::Blocks(batch) = reply else {
+            unreachable!()
+        };
+        assert_eq!(batch.len(), MAX_SYNC_BLOCKS);
+        assert_eq!(
+            batch[0].header.prev_block_hash,
+            node.chain.main_chain_from(0)[0].hash()
+        );
+
+        // the requester pages through the rest
+        let next = node.sync_batch(1 + MAX_SYNC_BLOCKS);
+        assert_eq!(next.len(), 2);
+        assert_eq!(next.last(), Some(node.chain.highest_block()));
+        assert!(node.sync_batch(node.chain.main_chain_length()).is_empty());
+
+        // batches end before exceeding the message size
+        let blocks = node.chain.main_chain_from(1);
+        let max_len = Vec::<u8>::from(&Message::Blocks(batch[..3].to_vec())).len();
+        assert_eq!(leading_blocks(blocks.clone(), 10, max_len), batch[..3]);
+        assert_eq!(leading_blocks(blocks.clone(), 10, max_len - 1), batch[..2]);
+        assert_eq!(leading_blocks(blocks, 2, max_len), batch[..2]);
+    }
+
+    #[test]
+    fn single_catch_up_at_a_time() {
+        let mut node = Node::new(addr(7000), &[]);
+        assert_eq!(node.start_catch_up(), None);
+
+        node.add_peer(addr(7001));
+        node.add_peer(addr(7002));
+        assert_eq!(node.start_catch_up(), Some(addr(7002)));
+        assert_eq!(node.start_catch_up(), None);
+        node.catching_up = false;
+        node.add_peer(addr(7001));
+        assert_eq!(node.start_catch_up(), Some(addr(7001)));
+    }
+
+    #[tokio::test]
+    async fn large_message_over_socket() {
+        let listener = TcpListener::bind(addr(0)).await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let txs = Transactions(Transaction::signed_dummy_txs(MAX_TXS as u32));
+        let block = Block::new(
+            Block::genesis(&ChainParams::MAINNET).hash(),
+            GLOBAL_DIFFICULTY,
+            txs,
+            DUMMY_MINER,
+        );
+        let message = Message::NewBlock(block.clone());
+        assert!(Vec::<u8>::from(&message).len() > 1024);
+
+        let sending = tokio::spawn(async move { message.broadcast([local].iter()).await });
+        let received = accept_message(&listener).await.unwrap();
+        sending.await.unwrap().unwrap();
+        assert!(matches!(received, Message::NewBlock(b) if b == block));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_rejected() {
+        let listener = TcpListener::bind(addr(0)).await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let len = MAX_MESSAGE_LEN as u32 + 1;
+        stream.write_all(&len.to_be_bytes()).await.unwrap();
+
+        let err = accept_message(&listener).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(MessageTooLong::is_cause_of(&err));
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_counted() {
+        let listener = TcpListener::bind(addr(0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let node_state = Arc::new(RwLock::new(Node::new(address, &[])));
+        let server = tokio::spawn(serve(listener, node_state.clone(), Default::default()));
+
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let len = MAX_MESSAGE_LEN as u32 + 1;
+            stream.write_all(&len.to_be_bytes()).await.unwrap();
+        }
+        // the node keeps serving
+        let reply = Message::GetOrphans.request(&address).await;
+        assert!(matches!(reply, Ok(Message::Orphans(orphans)) if orphans.is_empty()));
+        let host = address.ip();
+        let started = Instant::now();
+        while node_state.read().await.oversized_messages(&host) < 2 {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "oversized messages counted"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // garbage within the limit isn't counted
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&[0, 0, 0, 2, 0xff, 0xff]).await.unwrap();
+        drop(stream);
+        assert!(Message::GetOrphans.request(&address).await.is_ok());
+        server.abort();
+        assert_eq!(node_state.read().await.oversized_messages(&host), 2);
+    }
+
+    #[test]
+    fn withholding_suspected() {
+        let mut node = Node::new(addr(7001), &[]);
+        let interval = node.chain.params().target_block_interval;
+        let genesis = Block::genesis(&ChainParams::MAINNET);
+        let mined = genesis.header.timestamp() + 1;
+        let block = Block::mine_at(genesis.hash(), 0, Transactions(vec![]), DUMMY_MINER, mined);
+        let (host, other) = (addr(7002).ip(), "127.0.0.2".parse().unwrap());
+
+        // blocks announced one interval apart, right after they were mined, look fine
+        for i in 0..BURST_BLOCKS as u64 {
+            let now = mined + i * (interval + 1);
+            let block = Block::mine_at(genesis.hash(), 0, Transactions(vec![]), DUMMY_MINER, now);
+            assert!(!node.record_block_arrival(other, &block, now));
+        }
+        assert_eq!(node.withholding_suspicions(&other), 0);
+
+        // a burst of blocks from one host
+        for i in 0..BURST_BLOCKS as u64 {
+            let suspicious = node.record_block_arrival(host, &block, mined + i);
+            assert_eq!(suspicious, i + 1 == BURST_BLOCKS as u64);
+        }
+        assert_eq!(node.withholding_suspicions(&host), 1);
+
+        // a block announced long after it was mined
+        let late = mined + 10 * interval;
+        assert!(node.record_block_arrival(other, &block, late));
+        assert_eq!(node.withholding_suspicions(&host), 1);
+        assert_eq!(node.withholding_suspicions(&other), 1);
+    }
+
+    /// Reads the message of the next connection, like each task of [crate::serve].
+    async fn accept_message(listener: &TcpListener) -> io::Result<Message> {
+        let (mut socket, _) = listener.accept().await?;
+        Message::read_from(&mut socket).await
+    }
+
+    #[tokio::test]
+    async fn broadcast_survives_unreachable_peers() {
+        let listener = TcpListener::bind(addr(0)).await.unwrap();
+        let live = listener.local_addr().unwrap();
+        let closed = TcpListener::bind(addr(0)).await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let message = Message::Tx(Transactions(Transaction::signed_dummy_txs(1)));
+        let expected = Vec::<u8>::from(&message);
+        let sending = tokio::spawn(async move {
+            // the unreachable peer comes first, but doesn't stop the broadcast
+            message.broadcast([closed_addr, live].iter()).await
+        });
+        let received = accept_message(&listener).await.unwrap();
+        assert_eq!(Vec::<u8>::from(&received), expected);
+        let failures = sending.await.unwrap().unwrap_err();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, closed_addr);
+    }
+
+    #[tokio::test]
+    async fn broadcast_times_out() {
+        // a peer that accepts connections but never reads, so a large message fills its (small)
+        // receive buffer and then the sender's
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        socket.bind(addr(0)).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let stalled = listener.local_addr().unwrap();
+        let txs = Transaction::dummy_txs(MAX_MESSAGE_LEN as u32 / 200);
+        let message = Message::Tx(Transactions(txs));
+        assert!(Vec::<u8>::from(&message).len() <= MAX_MESSAGE_LEN);
+        let timeout = Duration::from_millis(200);
+        let started = Instant::now();
+        let failures = message
+            .broadcast_with_timeout([stalled].iter(), timeout)
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < timeout * 5);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, stalled);
+        assert_eq!(failures[0].1.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn unreachable_peers_are_dropped() {
+        let peers = [addr(7001), addr(7002)];
+        let mut node = Node::new(addr(7000), &peers);
+        for _ in 1..MAX_PEER_FAILURES {
+            assert!(node.record_broadcast(&peers, &peers[..1]).is_empty());
+        }
+        // reaching a peer resets its failures
+        node.record_broadcast(&peers, &[]);
+        for _ in 1..MAX_PEER_FAILURES {
+            assert!(node.record_broadcast(&peers, &peers[..1]).is_empty());
+        }
+        assert_eq!(node.record_broadcast(&peers, &peers[..1]), peers[..1]);
+        assert_eq!(node.peers, peers[1..].iter().cloned().collect());
+        assert!(node.failures.is_empty());
+
+        // but it is added again once announced
+        node.handle(Message::Connect(peers[0]));
+        assert_eq!(node.peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn connections_are_served_concurrently() {
+        let listener = TcpListener::bind(addr(0)).await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let node_state = Arc::new(RwLock::new(Node::new(local, &[])));
+        let miner = Arc::new(RwLock::new(Miner::default()));
+        tokio::spawn(crate::serve(listener, node_state.clone(), miner));
+
+        // a peer that connects but stalls in the middle of its message ...
+        let mut stalled = TcpStream::connect(local).await.unwrap();
+        stalled.write_all(&64u32.to_be_bytes()).await.unwrap();
+        // ... doesn't keep the node from handling the next one
+        let txs = Transactions(Transaction::signed_dummy_txs(1));
+        Message::Tx(txs).broadcast([local].iter()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node_state.read().await.mempool_len() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the second connection is handled while the first one stalls");
+        drop(stalled);
+    }
+
+    #[tokio::test]
+    async fn panicking_mining_task_is_contained() {
+        let failed = crate::run_blocking(|| -> Block { panic!("nonce space exhausted") }).await;
+        assert_eq!(failed, None);
+
+        // the runtime, and with it the node, carries on
+        let node_state = Arc::new(RwLock::new(Node::new(addr(7000), &[])));
+        let txs = Transactions(Transaction::signed_dummy_txs(1));
+        let handle = node_state.clone();
+        let handled = crate::run_blocking(move || handle.blocking_write().handle(Message::Tx(txs)));
+        assert!(handled.await.is_some());
+        assert_eq!(node_state.read().await.mempool.len(), 1);
+    }
+
+    #[test]
+    fn cancelled_sync_keeps_valid_chain() {
+        let mut source = Node::new(addr(7000), &[]);
+        let mut parent = Block::genesis(&ChainParams::MAINNET);
+        for _ in 0..4 {
+            let txs = Transactions(vec![]);
+            let time = parent.header.timestamp() + 1;
+            let block = Block::mine_at(parent.hash(), GLOBAL_DIFFICULTY, txs, DUMMY_MINER, time);
+            assert!(source.add_block(&block));
+            parent = block;
+        }
+        let blocks: Vec<Block> = source
+            .chain
+            .main_chain_from(1)
+            .into_iter()
+            .cloned()
+            .collect();
+
+        // cancel while the third block is downloaded
+        let mut node = Node::new(addr(7001), &[]);
+        let canceller = node.sync_canceller();
+        let downloading = blocks.clone().into_iter().enumerate().map(|(i, block)| {
+            if i == 2 {
+                canceller.store(true, Ordering::Relaxed);
+            }
+            block
+        });
+        assert_eq!(node.sync_blocks(downloading), 2);
+        assert_eq!(node.chain.main_chain_length(), 3);
+        let progress = node.sync_progress();
+        assert_eq!((progress.current, progress.target), (2, blocks.len()));
+        assert!(progress.blocks_per_sec > 0.0 && progress.eta_secs.unwrap() > 0.0);
+        assert_eq!(node.chain.highest_block(), &blocks[1]);
+        assert_eq!(
+            node.chain
+                .dry_run_add(&blocks[2])
+                .map(|dry_run| dry_run.result),
+            Ok(AddResult::New(vec![blocks[2].hash()]))
+        );
+
+        // resuming the sync completes the chain
+        canceller.store(false, Ordering::Relaxed);
+        assert_eq!(node.sync_blocks(blocks[2..].to_vec()), 2);
+        assert_eq!(node.chain.highest_block(), source.chain.highest_block());
+        let Some(Message::SyncProgress(progress)) = node.answer(Message::GetSyncProgress) else {
+            panic!("sync progress requests are answered");
+        };
+        assert_eq!((progress.current, progress.target), (2, 2));
+        assert_eq!(progress.eta_secs, Some(0.0));
+    }
+
+    #[test]
+    fn trusted_sync_verifies_only_last_pow() {
+        let txs = Transactions(vec![]);
+        let genesis = Block::genesis(&ChainParams::MAINNET).hash();
+        let unmined = Block::new(genesis, GLOBAL_DIFFICULTY, txs.clone(), DUMMY_MINER);
+        let time = unmined.header.timestamp() + 1;
+        let tip = Block::mine_at(unmined.hash(), GLOBAL_DIFFICULTY, txs, DUMMY_MINER, time);
+
+        let mut node = Node::new(addr(7000), &[]);
+        node.sync_blocks([unmined.clone(), tip.clone()]);
+        assert_eq!(node.chain.main_chain_length(), 1);
+
+        let mut node = Node::new(addr(7000), &[]);
+        node.trust_peers_pow = true;
+        node.sync_blocks([unmined.clone()]);
+        assert_eq!(node.chain.main_chain_length(), 1);
+        node.sync_blocks([unmined, tip.clone()]);
+        assert_eq!(node.chain.highest_block(), &tip);
+    }
+
+    #[test]
+    fn orphan_block_requests_sync() {
+        let mut node = Node::new(addr(7000), &[]);
+        let txs = Transactions(vec![]);
+        let orphan = Block::mine_new([9; 32], GLOBAL_DIFFICULTY, txs, DUMMY_MINER);
+        let (reply, _) = node.handle(Message::NewBlock(orphan));
+        assert!(matches!(reply, Some(Message::GetBlocks { from_height: 1 })));
+    }
+
+    #[test]
+    fn overspending_txs_dropped() {
+        let mut node = Node::new(addr(7000), &[]);
+        // 1 + 2 + ... + 13 = 91 of the 100 genesis "coin"
+        let txs = Transaction::signed_dummy_txs(14);
+        node.handle(Message::Tx(Transactions(txs[..13].to_vec())));
+        assert_eq!(node.mempool.len(), 13);
+        // the 14th would overspend, given the pending ones
+        let (reply, _) = node.handle(Message::Tx(Transactions(txs[13..].to_vec())));
+        assert!(reply.is_none());
+        assert_eq!(node.mempool.len(), 13);
+
+        // once the pending ones are committed, the balance of 9 still doesn't cover it
+        let mut committed: Vec<Transaction> = node.mempool.values().cloned().collect();
+        committed.sort_by_key(Transaction::nonce);
+        let committed = Transactions(committed);
+        let block = Block::mine_new(
+            Block::genesis(&ChainParams::MAINNET).hash(),
+            GLOBAL_DIFFICULTY,
+            committed,
+            DUMMY_MINER,
+        );
+        assert!(node.add_block(&block));
+        assert!(node.mempool.is_empty());
+        // committed transactions are not accepted again
+        let (reply, _) = node.handle(Message::Tx(Transactions(txs[..1].to_vec())));
+        assert!(reply.is_none());
+        assert!(node.mempool.is_empty());
+        node.handle(Message::Tx(Transactions(txs[13..].to_vec())));
+        assert!(node.mempool.is_empty());
+    }
+
+    #[test]
+    fn tip_and_difficulty_on_request() {
+        let mut node = Node::new(addr(7000), &[]);
+        fund_dummy_keys(&mut node);
+        let Some(Message::Tip {
+            hash,
+            height,
+            timestamp,
+            difficulty,
+        }) = node.answer(Message::GetTip)
+        else {
+            panic!("tip requests are answered");
+        };
+        let tip = node.chain.highest_block().clone();
+        assert_eq!(
+            (hash, height, timestamp),
+            (tip.hash(), 1, tip.header.timestamp())
+        );
+        assert_eq!(difficulty, node.chain.difficulty_for_height(2));
+
+        let Some(Message::DifficultyHistory(history)) = node.answer(Message::GetDifficultyHistory)
+        else {
+            panic!("difficulty history requests are answered");
+        };
+        assert_eq!(history, node.chain.difficulty_history());
+        assert_eq!(history.len(), height + 1);
+
+        // a block mined at it is accepted
+        let block = Block::mine_at(
+            hash,
+            difficulty,
+            Transactions(vec![]),
+            DUMMY_MINER,
+            timestamp + 1,
+        );
+        assert!(node.add_block(&block));
+    }
+
+    #[test]
+    fn balances_on_request() {
+        let mut node = Node::new(addr(7000), &[]);
+        fund_dummy_keys(&mut node);
+        let addresses: Vec<Address> = (0..6)
+            .map(|i| address_of(&Transaction::dummy_key(i).verifying_key()))
+            .collect();
+        let Some(Message::Balances(balances)) =
+            node.answer(Message::GetBalances(addresses.clone()))
+        else {
+            panic!("balance requests are answered");
+        };
+        let expected: Vec<u32> = addresses.iter().map(|a| node.chain.balance_of(a)).collect();
+        assert_eq!(balances, expected);
+        assert_eq!(balances[1..], [1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn orphans_on_request() {
+        let mut node = Node::new(addr(7000), &[]);
+        let txs = Transactions(vec![]);
+        let orphan = Block::mine_new([9; 32], GLOBAL_DIFFICULTY, txs, DUMMY_MINER);
+        node.handle(Message::NewBlock(orphan.clone()));
+
+        let Some(Message::Orphans(orphans)) = node.answer(Message::GetOrphans) else {
+            panic!("orphan requests are answered");
+        };
+        assert_eq!(orphans, vec![orphan]);
+        assert!(node.answer(Message::Addr(vec![])).is_none());
+    }
+
+    #[test]
+    fn raw_tx_on_request() {
+        let mut node = Node::new(addr(7000), &[]);
+        fund_dummy_keys(&mut node);
+        let raw = dummy_tx_with_fee(3, 1).to_hex();
+        let tx = Transaction::from_hex(&raw).unwrap();
+        node.handle(Message::Tx(Transactions(vec![tx.clone()])));
+
+        let found = node.answer(Message::GetTransaction(tx.hash()));
+        let Some(Message::TransactionFound(Some((pending, None)))) = found else {
+            panic!("pending transactions are found");
+        };
+        assert_eq!(pending.to_hex(), raw);
+        let tip = node.chain.highest_block().clone();
+        let funding = &tip.transactions.0[0];
+        let found = node.answer(Message::GetTransaction(funding.hash()));
+        let Some(Message::TransactionFound(Some((committed, Some(height))))) = found else {
+            panic!("committed transactions are found");
+        };
+        assert_eq!(&committed, funding);
+        assert_eq!(node.chain.block_at_height(height), Some(&tip));
+        let found = node.answer(Message::GetTransaction([7; 32]));
+        assert!(matches!(found, Some(Message::TransactionFound(None))));
+
+        let found = node.answer(Message::GetBlock(tip.hash()));
+        assert!(matches!(found, Some(Message::BlockFound(Ok(Some(block)))) if block == tip));
+        let found = node.answer(Message::GetBlock([7; 32]));
+        assert!(matches!(found, Some(Message::BlockFound(Ok(None)))));
+    }
+
+    #[test]
+    fn pruned_blocks_on_request() {
+        let mut node = Node::new(addr(7000), &[]);
+        node.chain = BlockChain::with_params(ChainParams::TESTING);
+        node.chain.set_keep_depth(Some(2));
+        let mut parent = Block::genesis(&ChainParams::TESTING);
+        let mut blocks = Vec::new();
+        for _ in 0..4 {
+            let time = parent.header.timestamp() + 1;
+            let block = Block::mine_at(parent.hash(), 0, Transactions(vec![]), DUMMY_MINER, time);
+            let (reply, _) = node.handle(Message::NewBlock(block.clone()));
+            assert!(matches!(reply, Some(Message::NewBlock(_))));
+            parent = block.clone();
+            blocks.push(block);
+        }
+        // pruned nodes still reject invalid blocks
+        let time = parent.header.timestamp() + 1;
+        let wrong = Block::mine_at(parent.hash(), 1, Transactions(vec![]), DUMMY_MINER, time);
+        assert!(node.handle(Message::NewBlock(wrong)).0.is_none());
+        assert_eq!(node.chain.pruned_height(), 3);
+
+        let found = node.answer(Message::GetBlock(blocks[0].hash()));
+        assert!(matches!(found, Some(Message::BlockFound(Err(Pruned(3))))));
+        let found = node.answer(Message::GetBlock(blocks[3].hash()));
+        assert!(matches!(found, Some(Message::BlockFound(Ok(Some(b)))) if b == blocks[3]));
+
+        // peers can only sync the blocks that are not pruned
+        let synced = node.answer(Message::GetBlocks { from_height: 1 });
+        assert!(matches!(synced, Some(Message::Blocks(b)) if b.is_empty()));
+        let synced = node.answer(Message::GetBlocks { from_height: 3 });
+        assert!(matches!(synced, Some(Message::Blocks(b)) if b == blocks[2..]));
+    }
+
+    #[tokio::test]
+    async fn mempool_commitment_verifies() {
+        let listener = TcpListener::bind(addr(0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut node = Node::new(address, &[]);
+        node.handle(Message::Tx(Transactions(Transaction::signed_dummy_txs(7))));
+        let node_state = Arc::new(RwLock::new(node));
+        let server = tokio::spawn(serve(listener, node_state, Default::default()));
+
+        let reply = Message::GetMempool.request(&address).await;
+        server.abort();
+        let Ok(Message::Mempool { txs, commitment }) = reply else {
+            panic!("mempool requests are answered");
+        };
+        assert_eq!(txs.0.len(), 7);
+        assert!(txs.verify_commitment(&commitment));
+
+        // a node leaving out or altering a transaction is caught
+        let mut tampered = txs.clone();
+        tampered.0.pop();
+        assert!(!tampered.verify_commitment(&commitment));
+        let mut tampered = txs;
+        tampered.0[0] = tampered.0[0].clone().with_fee(1);
+        assert!(!tampered.verify_commitment(&commitment));
+    }
 }
@@ -1,18 +1,28 @@
-use crate::hash::{has_leading_zeros, B58Encode, Hash, Hashable, HASH_LENGTH};
-use crate::tx::{Transactions, GENESIS_TXS_HASH};
-use crate::GLOBAL_DIFFICULTY;
+use crate::hash::{
+    has_leading_zero_bits, wire_from_hex, wire_to_hex, B58Encode, Hash, Hashable, HexEncode,
+    HexError, HASH_LENGTH,
+};
+use crate::params::{ChainParams, GenesisConfig};
+use crate::tx::{merkle_root, now, Address, Transaction, Transactions};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
-use std::u32;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
 /// Fully identifies a block on the chain.
 /// A block is valid iff hashing all its bytes results in a hash with at least `difficulty` leading
-/// zero bytes and the difficulty is equal to the global constant.
-// TODO: once the difficulty is a function, this should be check as well
+/// zero bits. Whether the difficulty is the one the chain expects is checked by the chain,
+/// see [crate::BlockChain::difficulty_for_height].
+/// Miners may grind the timestamp as an extra nonce, the chain still bounds it by the parent's
+/// timestamp and the local clock, see [crate::BlockError::PastTimestamp].
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct BlockHeader {
     pub prev_block_hash: Hash,
     merkle_hash: Hash,
+    /// The UNIX timestamp (in seconds) at which the block was mined.
+    timestamp: u64,
+    /// The number of leading zero bits the header's hash must have. It used to count bytes,
+    /// see [difficulty_from_bytes] to migrate.
     difficulty: u32,
     pub nonce: u32,
 }
@@ -21,33 +31,63 @@ impl Debug for BlockHeader {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "BlockHeader {{ prev_block_hash: {}, merkle_hash: {}, difficulty: {}, nonce: {} }}",
+            "BlockHeader {{ prev_block_hash: {}, merkle_hash: {}, timestamp: {}, difficulty: {}, nonce: {} }}",
             self.prev_block_hash.encode(),
             self.merkle_hash.encode(),
+            self.timestamp,
             self.difficulty,
             self.nonce
         )
     }
 }
 
-/// The nonce making [GENESIS_HEADER] valid.
-pub const GENESIS_NONCE: u32 = 437;
+/// The nonce making the main network's genesis valid, see [ChainParams::MAINNET].
+/// Since the difficulty counts bits instead of bytes, genesis is mined at 8 bits (the work of the
+/// former 1 byte) which changed its hash and thus this nonce.
+/// Any change to the layout of headers or transactions changes it too, recompute it (and the
+/// pinned genesis hashes) with [Block::compute_genesis].
+#[cfg(not(feature = "canonical"))]
+pub const GENESIS_NONCE: u32 = 3;
+/// Hashing the canonical bytes (see the `canonical` feature) changes the hash of genesis and
+/// thus its nonce, so such nodes form a network of their own.
+#[cfg(feature = "canonical")]
+pub const GENESIS_NONCE: u32 = 234;
 
-/// The hard-coded first block (header) on this chain.
-pub const GENESIS_HEADER: BlockHeader = BlockHeader {
+/// The first block (header) of the main network, see [ChainParams::MAINNET]. Pinned to detect
+/// when a change of the header or transaction layout changes it.
+#[cfg(test)]
+pub(crate) const GENESIS_HEADER: BlockHeader = BlockHeader {
     prev_block_hash: [0; HASH_LENGTH],
-    difficulty: 1,
-    merkle_hash: GENESIS_TXS_HASH,
+    difficulty: ChainParams::MAINNET.genesis.difficulty,
+    merkle_hash: crate::tx::GENESIS_TXS_HASH,
+    timestamp: ChainParams::MAINNET.genesis.timestamp,
     nonce: GENESIS_NONCE,
 };
 
+/// The length of the bytes of a header, see [BlockHeader::canonical_bytes].
+const HEADER_LENGTH: usize = 80;
+
+/// Where the nonce starts in the bytes of a header, see [BlockHeader::canonical_bytes].
+const NONCE_OFFSET: usize = 76;
+
+/// Every this many attempts, mining checks whether it should stop.
+const STOP_CHECK_INTERVAL: usize = 1024;
+
+#[cfg(test)]
+thread_local! {
+    /// How many headers had their proof-of-work checked on this thread, see
+    /// [BlockHeader::is_valid]. Per thread, such that tests running in parallel don't interfere.
+    pub(crate) static POW_CHECKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 impl BlockHeader {
     /// Creates a new block header with 0 nonce.
     /// This block header is only valid after [Self::solve]'ing it and changing the nonce.
-    pub fn new(prev_block_hash: Hash, merkle_hash: Hash, difficulty: u32) -> Self {
+    pub fn new(prev_block_hash: Hash, merkle_hash: Hash, difficulty: u32, timestamp: u64) -> Self {
         BlockHeader {
             prev_block_hash,
             merkle_hash,
+            timestamp,
             difficulty,
             nonce: 0,
         }
@@ -55,77 +95,371 @@ impl BlockHeader {
 
     /// Creates a new, _valid_ block. I.e. mines/solves it such that the hash
     /// satisfies the given difficulty.
-    pub fn mine_new(prev_block_hash: Hash, merkle_hash: Hash, difficulty: u32) -> Self {
-        let initial = Self::new(prev_block_hash, merkle_hash, difficulty);
+    pub fn mine_new(
+        prev_block_hash: Hash,
+        merkle_hash: Hash,
+        difficulty: u32,
+        timestamp: u64,
+    ) -> Self {
+        let initial = Self::new(prev_block_hash, merkle_hash, difficulty, timestamp);
+        let nonce = initial.solve(&AtomicBool::new(false));
         Self {
-            nonce: initial.solve(),
+            nonce: nonce.expect("some nonce solves the header"),
             ..initial
         }
     }
 
     /// Mines the nonce needed to solve this block/make it valid.
-    fn solve(&self) -> u32 {
-        (0..u32::MAX)
-            .find(|n| {
-                let new_header = Self { nonce: *n, ..*self };
-                let hash = new_header.hash();
-                has_leading_zeros(&hash, self.difficulty as usize)
-            })
-            .unwrap()
+    /// Returns `None` if `cancel` is set before, e.g. because the parent is outdated.
+    fn solve(&self, cancel: &AtomicBool) -> Option<u32> {
+        self.search(0..=u32::MAX, || cancel.load(Ordering::Relaxed))
+    }
+
+    /// Like [Self::solve] but searches the nonces on the given number of threads, thread `i`
+    /// trying `i`, `i + threads`, etc. Once a thread finds a valid nonce, the others stop.
+    /// Returns _a_ valid nonce, not necessarily the lowest one.
+    pub fn solve_parallel(&self, threads: usize, cancel: &AtomicBool) -> Option<u32> {
+        let threads = threads.max(1);
+        let found = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads as u32)
+                .map(|first| {
+                    let found = &found;
+                    scope.spawn(move || {
+                        let nonces = (first..=u32::MAX).step_by(threads);
+                        let nonce = self.search(nonces, || {
+                            found.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed)
+                        });
+                        if nonce.is_some() {
+                            found.store(true, Ordering::Relaxed);
+                        }
+                        nonce
+                    })
+                })
+                .collect();
+            (workers.into_iter())
+                .filter_map(|worker| worker.join().expect("mining thread panicked"))
+                .min()
+        })
+    }
+
+    /// Tries the given nonces in order until one solves this header or `stop` returns true,
+    /// which is checked every [STOP_CHECK_INTERVAL] attempts.
+    /// Attempts only differ in the nonce, so the header is serialized once and each attempt just
+    /// overwrites the nonce's bytes, see [Self::canonical_bytes].
+    fn search<I, F>(&self, nonces: I, stop: F) -> Option<u32>
+    where
+        I: Iterator<Item = u32>,
+        F: Fn() -> bool,
+    {
+        let mut bytes = self.canonical_bytes();
+        (nonces.enumerate())
+            .take_while(|(i, _)| i % STOP_CHECK_INTERVAL != 0 || !stop())
+            .find(|(_, n)| self.is_solved_by(&mut bytes, *n))
+            .map(|(_, n)| n)
+    }
+
+    /// Whether the header with the given nonce is valid. The `bytes` must be the header's
+    /// [Self::canonical_bytes], of which only the nonce is overwritten.
+    fn is_solved_by(&self, bytes: &mut [u8], nonce: u32) -> bool {
+        bytes[NONCE_OFFSET..].copy_from_slice(&nonce.to_le_bytes());
+        has_leading_zero_bits(&Self::hash_bytes(bytes), self.difficulty as usize)
     }
 
     pub fn is_valid(&self) -> bool {
-        (self.difficulty == GLOBAL_DIFFICULTY || self.merkle_hash == GENESIS_TXS_HASH)
-            && has_leading_zeros(&self.hash(), self.difficulty as usize)
+        #[cfg(test)]
+        POW_CHECKS.with(|checks| checks.set(checks.get() + 1));
+        has_leading_zero_bits(&self.hash(), self.difficulty as usize)
+    }
+
+    /// The difficulty this header is mined at.
+    pub fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+
+    /// The UNIX timestamp (in seconds) at which this header was mined.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// The fixed byte layout of this header for implementations outside Rust (80 bytes):
+    /// `prev_block_hash` (32) | `merkle_hash` (32) | `timestamp` (u64 LE) | `difficulty` (u32 LE)
+    /// | `nonce` (u32 LE).
+    /// This is identical to the bincode encoding, so hashes agree with either.
+    pub fn canonical_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let mut bytes = [0; HEADER_LENGTH];
+        bytes[..32].copy_from_slice(&self.prev_block_hash);
+        bytes[32..64].copy_from_slice(&self.merkle_hash);
+        bytes[64..72].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[72..NONCE_OFFSET].copy_from_slice(&self.difficulty.to_le_bytes());
+        bytes[NONCE_OFFSET..].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
     }
 }
 
 impl Hashable for BlockHeader {
+    /// Hashes the header's bytes on the stack, so hashing never allocates.
     fn hash(&self) -> Hash {
-        Self::hash_bytes(&bincode::serialize(self).expect("Block should be serializable"))
+        #[cfg(feature = "canonical")]
+        let bytes = self.canonical_bytes();
+        #[cfg(not(feature = "canonical"))]
+        let bytes = {
+            let mut bytes = [0; HEADER_LENGTH];
+            bincode::serialize_into(&mut bytes[..], self).expect("a header fits its length");
+            bytes
+        };
+        Self::hash_bytes(&bytes)
     }
 }
 
 /// A full block on this chain.
 /// A block is valid iff
 /// - its [BlockHeader] is valid
+/// - it contains at least one (the coinbase) and at most [MAX_TXS] transactions
+/// - its first and only its first transaction is a coinbase, which has the header's timestamp
+///   and mints the [block_reward] for the block's height plus the fees of all other transactions
+/// - all its other transactions are signed by their spender, see [Transaction::verify_signature]
 /// - the hash of its [Transactions] is equal to the merkle_tree_hash of its [BlockHeader]
+/// - it contains every batch of transactions either completely or not at all
 #[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Block {
     pub header: BlockHeader,
     pub transactions: Transactions,
 }
 
+/// The maximum number of transactions in a block, including its coinbase.
 pub const MAX_TXS: usize = 100;
 
+/// The "coin" currently minted by every block, see [block_reward].
+pub const BLOCK_REWARD: u32 = 100;
+
+/// Converts a difficulty counting leading zero bytes, as headers did before they counted bits,
+/// to the bits of the same work, e.g. to migrate a stored chain, see
+/// [crate::BlockChain::migrate_saved].
+pub fn difficulty_from_bytes(bytes: u32) -> u32 {
+    bytes.saturating_mul(8)
+}
+
+/// Converts a difficulty in bits to leading zero bytes, as headers counted before, rounding up
+/// to at least the same work.
+pub fn difficulty_to_bytes(bits: u32) -> u32 {
+    bits.div_ceil(8)
+}
+
+/// The "coin" the coinbase of the block at the given height mints for its miner.
+/// The genesis block's coinbase is the [crate::GENESIS_TX].
+// TODO: the reward is constant. To halve it over time, make it depend on the height here, and
+//   pass the height to `Block::mine_new` and friends, which always pay the [BLOCK_REWARD] (plus
+//   the fees).
+pub fn block_reward(_height: usize) -> u32 {
+    BLOCK_REWARD
+}
+
 impl Block {
-    /// Create a new block. This block is only valid after mining/solving its header
-    /// and changing the nonce.
-    pub fn new(prev_block_hash: Hash, difficulty: u32, transactions: Transactions) -> Self {
+    /// Creates a new block at the current time, whose coinbase pays the [BLOCK_REWARD] and fees
+    /// to the miner. It is only valid once mined, see [Self::mine_new].
+    pub fn new(
+        prev_block_hash: Hash,
+        difficulty: u32,
+        transactions: Transactions,
+        miner: Address,
+    ) -> Self {
+        let timestamp = now();
+        let transactions = Self::with_coinbase(transactions, miner, timestamp);
         Block {
-            header: BlockHeader::new(prev_block_hash, transactions.hash(), difficulty),
+            header: BlockHeader::new(prev_block_hash, transactions.hash(), difficulty, timestamp),
             transactions,
         }
     }
 
-    /// Returns the first block on this chain.
-    pub fn genesis() -> Self {
+    /// Prepends the coinbase paying the [BLOCK_REWARD] and the transactions' fees to the miner.
+    fn with_coinbase(transactions: Transactions, miner: Address, timestamp: u64) -> Transactions {
+        let fees: u64 = transactions.0.iter().map(|tx| tx.fee() as u64).sum();
+        let amount = u32::try_from(BLOCK_REWARD as u64 + fees).expect("fees exceed any amount");
+        let coinbase = Transaction::coinbase(miner, amount, timestamp);
+        Transactions([vec![coinbase], transactions.0].concat())
+    }
+
+    /// Returns the first block of the chain with the given parameters, see [GenesisConfig].
+    pub fn genesis(params: &ChainParams) -> Self {
+        let config = &params.genesis;
+        let transactions = Transactions::genesis(config);
         Block {
-            header: GENESIS_HEADER,
-            transactions: Transactions::genesis(),
+            header: BlockHeader {
+                prev_block_hash: [0; HASH_LENGTH],
+                merkle_hash: transactions.hash(),
+                timestamp: config.timestamp,
+                difficulty: config.difficulty,
+                nonce: config.nonce,
+            },
+            transactions,
         }
     }
 
-    /// Creates a new, _valid_ block. I.e. mines/solves its nonce.
-    pub fn mine_new(prev_block_hash: Hash, difficulty: u32, transactions: Transactions) -> Self {
+    /// Mines the genesis block of the given config from scratch, i.e. with the lowest nonce
+    /// solving it (whatever the config's nonce). For the main network, this is its
+    /// [Block::genesis], so it recomputes the hard-coded constants after a change of the header
+    /// or transaction layout.
+    pub fn compute_genesis(config: &GenesisConfig) -> Self {
+        let transactions = Transactions::genesis(config);
+        let merkle_hash = transactions.hash();
+        let (difficulty, timestamp) = (config.difficulty, config.timestamp);
         Block {
-            header: BlockHeader::mine_new(prev_block_hash, transactions.hash(), difficulty),
+            header: BlockHeader::mine_new([0; HASH_LENGTH], merkle_hash, difficulty, timestamp),
             transactions,
         }
     }
 
-    pub fn is_valid(&self) -> bool {
-        self.transactions.hash() == self.header.merkle_hash && self.header.is_valid()
+    /// Creates a new, _valid_ block at the current time. I.e. prepends the coinbase paying the
+    /// [BLOCK_REWARD] and the fees to the miner and mines/solves its nonce.
+    pub fn mine_new(
+        prev_block_hash: Hash,
+        difficulty: u32,
+        transactions: Transactions,
+        miner: Address,
+    ) -> Self {
+        Self::mine_at(prev_block_hash, difficulty, transactions, miner, now())
+    }
+
+    /// Like [Self::mine_new] but at a fixed time, i.e. deterministic.
+    pub fn mine_at(
+        prev_block_hash: Hash,
+        difficulty: u32,
+        transactions: Transactions,
+        miner: Address,
+        timestamp: u64,
+    ) -> Self {
+        let transactions = Self::with_coinbase(transactions, miner, timestamp);
+        let merkle_hash = transactions.hash();
+        Self::mine_with_root(
+            prev_block_hash,
+            difficulty,
+            transactions,
+            merkle_hash,
+            timestamp,
+        )
+    }
+
+    /// Like [Self::mine_at] but with the merkle root of the transactions already known,
+    /// e.g. from a [crate::MerkleAccumulator] used to build the block template.
+    /// The transactions must already start with their coinbase.
+    pub fn mine_with_root(
+        prev_block_hash: Hash,
+        difficulty: u32,
+        transactions: Transactions,
+        merkle_hash: Hash,
+        timestamp: u64,
+    ) -> Self {
+        Block {
+            header: BlockHeader::mine_new(prev_block_hash, merkle_hash, difficulty, timestamp),
+            transactions,
+        }
+    }
+
+    /// Returns this block with the given parent and difficulty, mined again as both are part of
+    /// its header, e.g. to migrate a stored chain, see [crate::BlockChain::migrate_saved]. Its
+    /// transactions and timestamp stay the same.
+    pub(crate) fn mine_again(&self, prev_block_hash: Hash, difficulty: u32) -> Self {
+        Self::mine_with_root(
+            prev_block_hash,
+            difficulty,
+            self.transactions.clone(),
+            self.header.merkle_hash,
+            self.header.timestamp,
+        )
+    }
+
+    /// Whether this block is valid at the given height, see [Block]. Whether it only spends what
+    /// its spenders have (e.g. no immature coinbase) depends on its chain, which checks that, see
+    /// [crate::BlockChain::add_block].
+    pub fn is_valid(&self, height: usize) -> bool {
+        self.is_well_formed() && self.pays_reward(height)
+    }
+
+    /// All checks of [Self::is_valid] that don't depend on the block's height, e.g. for blocks
+    /// whose parent is not known yet.
+    pub(crate) fn is_well_formed(&self) -> bool {
+        self.is_consistent() && self.header.is_valid()
+    }
+
+    /// All checks of [Self::is_well_formed] but the proof-of-work, i.e. whether the block's
+    /// transactions are structured correctly and match its header.
+    pub(crate) fn is_consistent(&self) -> bool {
+        // checked first, as the merkle root of no transactions is undefined
+        (1..=MAX_TXS).contains(&self.transactions.0.len())
+            && self.has_single_coinbase()
+            && self.transactions.hash() == self.header.merkle_hash
+            && self.transactions.has_complete_batches()
+            && self.transactions.0.iter().all(Transaction::has_valid_data)
+            && self.is_signed()
+    }
+
+    /// Whether all transactions but the coinbase are signed by their spender. Coinbases mint,
+    /// so nobody can sign them, see [Transaction::coinbase].
+    fn is_signed(&self) -> bool {
+        let txs = self.transactions.0.iter();
+        txs.filter(|tx| !tx.is_coinbase())
+            .all(Transaction::verify_signature)
+    }
+
+    /// Whether the coinbase mints exactly the [block_reward] for the given height plus the
+    /// [Self::total_fees]. Only meaningful for well-formed blocks, see [Self::is_well_formed].
+    pub(crate) fn pays_reward(&self, height: usize) -> bool {
+        self.coinbase().is_some_and(|coinbase| {
+            coinbase.amount() as u64 == block_reward(height) as u64 + self.total_fees()
+        })
+    }
+
+    /// The sum of the fees of all transactions but the coinbase, which the miner collects.
+    pub fn total_fees(&self) -> u64 {
+        let txs = self.transactions.0.iter();
+        txs.filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.fee() as u64)
+            .sum()
+    }
+
+    /// Whether the first and only the first transaction is a coinbase, created at the
+    /// time of the block.
+    fn has_single_coinbase(&self) -> bool {
+        match self.transactions.0.split_first() {
+            Some((coinbase, rest)) => {
+                coinbase.is_coinbase()
+                    && coinbase.timestamp() == self.header.timestamp
+                    && !rest.iter().any(Transaction::is_coinbase)
+            }
+            None => false,
+        }
+    }
+
+    /// The transaction minting the reward for this block's miner, i.e. the first one.
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions.0.first().filter(|tx| tx.is_coinbase())
+    }
+}
+
+/// Proves that a transaction is _not_ part of a block.
+///
+/// Transactions in a block are not sorted, so the proof consists of the hashes of all the
+/// block's transactions, which must recompute to the block's merkle root.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct AbsenceProof {
+    pub tx_hashes: Vec<Hash>,
+}
+
+impl Block {
+    /// Returns a proof that the given transaction is not part of this block, if it isn't.
+    pub fn absence_proof(&self, tx_hash: &Hash) -> Option<AbsenceProof> {
+        let tx_hashes: Vec<Hash> = self.transactions.0.iter().map(|t| t.hash()).collect();
+        (!tx_hashes.contains(tx_hash)).then_some(AbsenceProof { tx_hashes })
+    }
+}
+
+impl AbsenceProof {
+    /// Verifies that the transaction is not part of the block with the given (trusted) header.
+    pub fn verify(&self, tx_hash: &Hash, header: &BlockHeader) -> bool {
+        (1..=MAX_TXS).contains(&self.tx_hashes.len())
+            && merkle_root(&self.tx_hashes) == header.merkle_hash
+            && !self.tx_hashes.contains(tx_hash)
     }
 }
 
@@ -136,35 +470,321 @@ impl Hashable for Block {
     }
 }
 
+/// A raw block, i.e. the hex of its bytes on the wire.
+impl HexEncode for Block {
+    fn to_hex(&self) -> String {
+        wire_to_hex(self)
+    }
+
+    fn from_hex(s: &str) -> Result<Self, HexError> {
+        wire_from_hex(s)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::block::{Block, BlockHeader, GENESIS_NONCE};
+    use crate::block::{
+        block_reward, AbsenceProof, Block, BlockHeader, BLOCK_REWARD, GENESIS_HEADER,
+        GENESIS_NONCE, MAX_TXS, NONCE_OFFSET,
+    };
     use crate::hash::{Hash, Hashable, HASH_LENGTH};
-    use crate::tx::{Transaction, Transactions};
+    use crate::params::{ChainParams, GenesisConfig};
+    use crate::tx::{
+        Batch, Transaction, Transactions, DUMMY_MINER as MINER, GENESIS_TIME, GENESIS_TXS_HASH,
+        MAX_COINBASE_DATA,
+    };
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     const PREVIOUS_HASH: Hash = [7; HASH_LENGTH];
 
+    #[test]
+    fn canonical_header_bytes() {
+        let header = Block::genesis(&ChainParams::MAINNET).header;
+        let bytes = header.canonical_bytes();
+        assert_eq!(bytes.len(), 80);
+        assert_eq!(bytes[..32], [0; HASH_LENGTH]);
+        assert_eq!(bytes[32..64], header.merkle_hash);
+        assert_eq!(bytes[64..72], GENESIS_TIME.to_le_bytes());
+        assert_eq!(bytes[72..76], [8, 0, 0, 0]);
+        assert_eq!(bytes[76..], GENESIS_NONCE.to_le_bytes());
+        assert_eq!(bytes[..], bincode::serialize(&header).unwrap());
+    }
+
+    #[test]
+    fn solve_hashes_like_bincode() {
+        let header = BlockHeader::new(PREVIOUS_HASH, [5; HASH_LENGTH], 12, GENESIS_TIME);
+        let mut bytes = header.canonical_bytes();
+        for nonce in 0..10_000 {
+            bytes[NONCE_OFFSET..].copy_from_slice(&u32::to_le_bytes(nonce));
+            let naive = bincode::serialize(&BlockHeader {
+                nonce,
+                ..header.clone()
+            })
+            .unwrap();
+            assert_eq!(
+                BlockHeader::hash_bytes(&bytes),
+                BlockHeader::hash_bytes(&naive)
+            );
+        }
+        let nonce = header.solve(&AtomicBool::new(false)).unwrap();
+        let naive = (0..).find(|n| {
+            BlockHeader {
+                nonce: *n,
+                ..header.clone()
+            }
+            .is_valid()
+        });
+        assert_eq!(Some(nonce), naive);
+    }
+
+    #[test]
+    fn solve_parallel() {
+        for difficulty in [2, 16] {
+            let mut header = BlockHeader::new(PREVIOUS_HASH, [5; HASH_LENGTH], difficulty, 0);
+            header.nonce = header.solve_parallel(4, &AtomicBool::new(false)).unwrap();
+            assert!(header.is_valid());
+        }
+        // a single thread finds the same (lowest) nonce as solving sequentially
+        let header = BlockHeader::new(PREVIOUS_HASH, [5; HASH_LENGTH], 8, GENESIS_TIME);
+        let not_cancelled = AtomicBool::new(false);
+        assert_eq!(
+            header.solve_parallel(1, &not_cancelled),
+            header.solve(&not_cancelled)
+        );
+    }
+
+    #[test]
+    fn cancel_solve() {
+        // practically unsolvable
+        let header = BlockHeader::new(PREVIOUS_HASH, [5; HASH_LENGTH], 64, GENESIS_TIME);
+        let cancel = AtomicBool::new(false);
+        let start = Instant::now();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                cancel.store(true, Ordering::Relaxed);
+            });
+            assert_eq!(header.solve(&cancel), None);
+        });
+        assert_eq!(header.solve_parallel(4, &cancel), None);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
     #[test]
     fn mined_block_header_valid() {
-        assert!(!BlockHeader::new(PREVIOUS_HASH, [5; HASH_LENGTH], 2).is_valid());
-        assert!(BlockHeader::mine_new(PREVIOUS_HASH, [5; HASH_LENGTH], 2).is_valid());
+        assert!(!BlockHeader::new(PREVIOUS_HASH, [5; HASH_LENGTH], 16, GENESIS_TIME).is_valid());
+        assert!(
+            BlockHeader::mine_new(PREVIOUS_HASH, [5; HASH_LENGTH], 16, GENESIS_TIME).is_valid()
+        );
+    }
+
+    /// Signs the transactions again with [Transaction::dummy_key] `0`, which spends the
+    /// [Transaction::signed_dummy_txs], e.g. after changing them.
+    fn signed(txs: Vec<Transaction>) -> Vec<Transaction> {
+        let key = Transaction::dummy_key(0);
+        (txs.into_iter())
+            .map(|mut tx| {
+                tx.sign(&key);
+                tx
+            })
+            .collect()
     }
 
     #[test]
     fn mined_block_valid() {
-        let txs = Transaction::dummy_txs(10);
-        assert!(!Block::new(PREVIOUS_HASH, 2, Transactions(txs.clone())).is_valid());
-        assert!(Block::mine_new(PREVIOUS_HASH, 2, Transactions(txs)).is_valid());
+        let txs = Transaction::signed_dummy_txs(10);
+        assert!(!Block::new(PREVIOUS_HASH, 16, Transactions(txs.clone()), MINER).is_valid(1));
+        assert!(Block::mine_new(PREVIOUS_HASH, 16, Transactions(txs), MINER).is_valid(1));
+    }
+
+    #[test]
+    fn coinbase() {
+        let txs = Transactions(Transaction::signed_dummy_txs(3));
+        let block = Block::mine_at(PREVIOUS_HASH, 0, txs.clone(), MINER, GENESIS_TIME);
+        let coinbase = block.coinbase().unwrap();
+        assert_eq!(coinbase.receiver(), &MINER);
+        assert_eq!(coinbase.amount(), block_reward(1));
+        assert!(block.is_valid(1));
+
+        let mine = |txs: Vec<Transaction>| {
+            let txs = Transactions(txs);
+            let root = txs.hash();
+            Block::mine_with_root(PREVIOUS_HASH, 0, txs, root, GENESIS_TIME)
+        };
+        let with_coinbase = |coinbase: Vec<Transaction>| [coinbase, txs.0.clone()].concat();
+        let wrong_reward = Transaction::coinbase(MINER, BLOCK_REWARD + 1, GENESIS_TIME);
+        assert!(!mine(with_coinbase(vec![wrong_reward])).is_valid(1));
+        let other = Transaction::coinbase([1; HASH_LENGTH], BLOCK_REWARD, GENESIS_TIME);
+        assert!(!mine(with_coinbase(vec![coinbase.clone(), other])).is_valid(1));
+        let late = Transaction::coinbase(MINER, BLOCK_REWARD, GENESIS_TIME + 1);
+        assert!(!mine(with_coinbase(vec![late])).is_valid(1));
+        // the coinbase must come first
+        assert!(!mine([txs.0.clone(), vec![coinbase.clone()]].concat()).is_valid(1));
+        assert!(!mine(txs.0.clone()).is_valid(1));
+        let empty = Block {
+            transactions: Transactions(vec![]),
+            ..block
+        };
+        assert!(!empty.is_valid(1));
+    }
+
+    #[test]
+    fn coinbase_message() {
+        let txs = Transaction::signed_dummy_txs(2);
+        let mine = |data: &[u8], txs: &[Transaction]| {
+            let coinbase = Transaction::coinbase(MINER, BLOCK_REWARD, GENESIS_TIME);
+            let txs =
+                Transactions([vec![coinbase.with_data(data.to_vec())], txs.to_vec()].concat());
+            let root = txs.hash();
+            Block::mine_with_root(PREVIOUS_HASH, 0, txs, root, GENESIS_TIME)
+        };
+        let tagged = mine(b"mined by us", &txs);
+        assert!(tagged.is_valid(1));
+        let bytes = bincode::serialize(&tagged).unwrap();
+        let received: Block = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(received.coinbase().unwrap().data(), b"mined by us");
+        assert_eq!(received, tagged);
+        // another message changes the merkle root, i.e. is extra-nonce space
+        assert_ne!(mine(b"mined by them", &txs).header, tagged.header);
+
+        assert!(mine(&[b'x'; MAX_COINBASE_DATA], &txs).is_valid(1));
+        assert!(!mine(&[b'x'; MAX_COINBASE_DATA + 1], &txs).is_valid(1));
+        // only the coinbase may carry a message
+        let spam = Transaction::signed_dummy_txs(1)
+            .remove(0)
+            .with_data(vec![1]);
+        assert!(!mine(b"", &signed(vec![spam])).is_valid(1));
+    }
+
+    #[test]
+    fn transaction_count_bounded() {
+        let full = Transactions(Transaction::signed_dummy_txs(MAX_TXS as u32 - 1));
+        let block = Block::mine_at(PREVIOUS_HASH, 0, full, MINER, GENESIS_TIME);
+        assert!(block.is_valid(1));
+
+        let txs = Transactions(Transaction::signed_dummy_txs(MAX_TXS as u32));
+        let oversized = Block::mine_at(PREVIOUS_HASH, 0, txs, MINER, GENESIS_TIME);
+        assert_eq!(oversized.transactions.0.len(), MAX_TXS + 1);
+        assert!(!oversized.is_valid(1));
+
+        // doesn't panic for lack of a merkle root
+        let empty = Block {
+            header: BlockHeader::mine_new(PREVIOUS_HASH, [0; HASH_LENGTH], 0, GENESIS_TIME),
+            transactions: Transactions(vec![]),
+        };
+        assert!(!empty.is_valid(1));
+    }
+
+    #[test]
+    fn coinbase_collects_fees() {
+        let txs = signed(
+            (Transaction::signed_dummy_txs(3).into_iter())
+                .map(|tx| tx.with_fee(2))
+                .collect(),
+        );
+        let block = Block::mine_at(PREVIOUS_HASH, 0, Transactions(txs.clone()), MINER, 1);
+        assert_eq!(block.total_fees(), 6);
+        assert_eq!(block.coinbase().unwrap().amount(), BLOCK_REWARD + 6);
+        assert!(block.is_valid(1));
+
+        // the miner must not collect more or less
+        let coinbase = Transaction::coinbase(MINER, BLOCK_REWARD, 1);
+        let txs = Transactions([vec![coinbase], txs].concat());
+        let root = txs.hash();
+        assert!(!Block::mine_with_root(PREVIOUS_HASH, 0, txs, root, 1).is_valid(1));
     }
 
     #[test]
     fn genesis_block_is_valid() {
-        let genesis_block = Block::genesis();
-        assert_eq!(genesis_block.header.solve(), GENESIS_NONCE);
-        assert!(genesis_block.is_valid());
+        let genesis_block = Block::genesis(&ChainParams::MAINNET);
+        let nonce = genesis_block.header.solve(&AtomicBool::new(false));
+        assert_eq!(nonce, Some(GENESIS_NONCE));
+        assert!(genesis_block.is_valid(0));
         assert_eq!(
             genesis_block.transactions.hash(),
             genesis_block.header.merkle_hash
         );
     }
+
+    #[test]
+    fn genesis_constants_are_up_to_date() {
+        assert!(
+            GENESIS_HEADER.is_valid(),
+            "GENESIS_HEADER isn't solved, did the layout of headers or transactions change?"
+        );
+        let computed = Block::compute_genesis(&ChainParams::MAINNET.genesis);
+        assert_eq!(
+            computed.header.merkle_hash, GENESIS_TXS_HASH,
+            "update GENESIS_TXS_HASH to {:?}",
+            computed.header.merkle_hash
+        );
+        assert_eq!(
+            computed.header.nonce, GENESIS_NONCE,
+            "update GENESIS_NONCE to {}",
+            computed.header.nonce
+        );
+        assert_eq!(computed, Block::genesis(&ChainParams::MAINNET));
+        assert_eq!(computed.header, GENESIS_HEADER);
+        let easy = GenesisConfig {
+            difficulty: 0,
+            ..ChainParams::MAINNET.genesis
+        };
+        assert!(Block::compute_genesis(&easy).is_valid(0));
+    }
+
+    #[test]
+    fn partial_batch_invalid() {
+        let batch = Batch {
+            id: [3; HASH_LENGTH],
+            size: 2,
+        };
+        let txs = signed(
+            (Transaction::signed_dummy_txs(3).into_iter().enumerate())
+                .map(|(i, tx)| if i < 2 { tx.with_batch(batch) } else { tx })
+                .collect(),
+        );
+        let partial = Transactions(txs[1..].to_vec());
+        assert!(!Block::mine_new(PREVIOUS_HASH, 2, partial, MINER).is_valid(1));
+        assert!(Block::mine_new(PREVIOUS_HASH, 16, Transactions(txs), MINER).is_valid(1));
+    }
+
+    #[test]
+    fn unsigned_spends_invalid() {
+        let txs = Transaction::signed_dummy_txs(3);
+        assert!(Block::mine_at(PREVIOUS_HASH, 0, Transactions(txs.clone()), MINER, 1).is_valid(1));
+
+        let mut forged = txs.clone();
+        forged[1] = forged[1].clone().with_fee(1);
+        assert!(!Block::mine_at(PREVIOUS_HASH, 0, Transactions(forged), MINER, 1).is_valid(1));
+        let unsigned = Transaction::dummy_txs(1);
+        assert!(!Block::mine_at(PREVIOUS_HASH, 0, Transactions(unsigned), MINER, 1).is_valid(1));
+    }
+
+    #[test]
+    fn absence_proof() {
+        let txs = Transaction::dummy_txs(6);
+        let block = Block::new(PREVIOUS_HASH, 0, Transactions(txs[..5].to_vec()), MINER);
+        let absent = txs[5].hash();
+        let present = txs[2].hash();
+
+        let proof = block.absence_proof(&absent).unwrap();
+        assert!(proof.verify(&absent, &block.header));
+        assert!(!proof.verify(&present, &block.header));
+        assert!(block.absence_proof(&present).is_none());
+
+        // hiding the present transaction breaks the merkle root
+        let forged = AbsenceProof {
+            tx_hashes: proof
+                .tx_hashes
+                .iter()
+                .filter(|h| **h != present)
+                .cloned()
+                .collect(),
+        };
+        assert!(!forged.verify(&present, &block.header));
+        let empty = AbsenceProof { tx_hashes: vec![] };
+        assert!(!empty.verify(&absent, &block.header));
+    }
 }
@@ -1,36 +1,219 @@
 use crate::block::Block;
-use crate::hash::{Hash, Hashable};
-use std::collections::HashMap;
+use crate::hash::{B58Encode, Hash, Hashable};
+use crate::params::ChainParams;
+use crate::tx::{now, Address, Transaction};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::{fs, io, iter};
+
+/// The outcome of adding a valid block to the chain.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AddResult {
+    /// The block is new and was added to the chain. Contains the hashes of all blocks that got
+    /// connected: the block itself first, then any buffered orphans descending from it
+    /// (parents before their children).
+    New(Vec<Hash>),
+    /// The block is already part of the chain, nothing changed.
+    Duplicate,
+    /// The block's parent is not (yet) part of the chain. The block is buffered and will be
+    /// connected once its parent gets added.
+    Orphan,
+}
+
+/// What adding a block would result in, see [BlockChain::dry_run_add].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DryRun {
+    /// The outcome of adding the block.
+    pub result: AddResult,
+    /// The highest block afterwards.
+    pub tip: Hash,
+    /// The work of the main chain afterwards, see [ChainStats::total_work].
+    pub total_work: u128,
+}
+
+/// The reasons for rejecting a block.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BlockError {
+    /// The block itself is invalid, see [Block::is_valid].
+    Invalid,
+    /// The block's parent is not part of the chain and the block is not worth buffering as an
+    /// orphan, as it is mined at a lower difficulty than the next main-chain block, see
+    /// [BlockChain::min_orphan_difficulty].
+    UnknownParent,
+    /// The block is not mined at the difficulty expected for its height,
+    /// see [BlockChain::difficulty_for_height].
+    WrongDifficulty,
+    /// The block's timestamp is too far ahead of the local clock,
+    /// see [ChainParams::max_future_drift].
+    FutureTimestamp,
+    /// The block's timestamp is not after its parent's.
+    PastTimestamp,
+    /// The block's height is pinned to another block, see [BlockChain::add_checkpoint].
+    Checkpoint,
+    /// A transaction of the block spends more than its spender has at that point, i.e. after
+    /// the block's parent and the transactions before it in the block.
+    Overspent,
+    /// A transaction of the block doesn't have its spender's next nonce at that point, i.e. is
+    /// replayed or skips one, see [BlockChain::nonce_of].
+    WrongNonce,
+    /// A transaction of the block spends "coin" minted by a coinbase too few blocks before on
+    /// the block's own chain, see [ChainParams::coinbase_maturity].
+    ImmatureCoinbase,
+    /// The block's branch forks off the main chain below blocks whose transactions are pruned,
+    /// which would be needed to validate it, see [BlockChain::set_keep_depth].
+    PrunedFork,
+}
+
+/// The error of querying transactions that a pruning chain discarded, see
+/// [BlockChain::set_keep_depth]. Contains the height below which main-chain blocks are pruned.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Pruned(pub usize);
+
+impl Display for Pruned {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transactions below height {} are pruned", self.0)
+    }
+}
+
+impl Error for Pruned {}
+
+/// Summary of the main chain, e.g. for dashboards, see [BlockChain::stats].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChainStats {
+    /// The height of the highest block, i.e. `0` for only the genesis block.
+    pub height: usize,
+    /// The expected number of hashes computed to mine all blocks, i.e. the sum of
+    /// `2^difficulty` over the blocks.
+    pub total_work: u128,
+    /// The average number of seconds between two consecutive mined blocks, i.e. from the
+    /// first block after genesis on, whose timestamp is arbitrary. `None` below two of them.
+    pub avg_block_interval: Option<f64>,
+    /// The timestamp of the genesis block.
+    pub oldest_timestamp: u64,
+    /// The timestamp of the highest block.
+    pub newest_timestamp: u64,
+}
 
 struct BlockEntry {
     block: Block,
     height: usize,
+    // Number of transactions from genesis up to and including this block
+    tx_count: usize,
+    // Expected number of hashes computed to mine this block and its ancestors
+    work: u128,
+    // Whether the block only kept its coinbase of all its transactions, see BlockChain::prune
+    pruned: bool,
+}
+
+/// The pruned part of a chain saved by [BlockChain::save_to_path]: the pruned main-chain blocks,
+/// genesis first and with only their coinbase, each with the number of transactions up to and
+/// including it, and the balances and nonces after the last of them.
+#[derive(Serialize, Deserialize)]
+struct SavedPruning {
+    keep_depth: Option<usize>,
+    blocks: Vec<(Block, usize)>,
+    balances: HashMap<Address, i64>,
+    nonces: HashMap<Address, u64>,
 }
 
+/// The maximum number of orphan blocks (i.e. blocks whose parent is unknown) that a chain
+/// buffers. Orphans are only checked for their proof-of-work, so they must not fill the memory.
+/// Once full, the oldest orphan makes room for a new one.
+pub const MAX_ORPHANS: usize = 100;
+
 /// Structure holding all the blocks of this blockchain.
-// TODO: This currently only cares about blocks & the main chain. Implement a quick way
-//  to query committed transactions (i.e. transactions on the main chain).
 pub struct BlockChain {
+    params: ChainParams,
     blocks: HashMap<Hash, BlockEntry>,
     // Hash serving as a direct access pointer to the highest block on the main chain
     highest_block_hash: Hash,
+    // Number of blocks rejected for a timestamp too far in the future, hinting at clock skew
+    future_block_rejections: usize,
+    // Orphan blocks waiting for their parent, keyed by the hash of that missing parent
+    orphans: HashMap<Hash, Vec<Block>>,
+    // The missing parent of each orphan, in the order the orphans arrived (oldest first)
+    orphan_arrivals: VecDeque<Hash>,
+    // The balances after the highest block on the main chain
+    balances: HashMap<Address, i64>,
+    // The hash of the main-chain block including each committed transaction, by its hash
+    tx_index: HashMap<Hash, Hash>,
+    // The highest nonce each spender committed on the main chain
+    nonces: HashMap<Address, u64>,
+    // The hashes of the blocks pinned at certain heights
+    checkpoints: HashMap<usize, Hash>,
+    // In pruning mode, the number of highest main-chain blocks keeping all their transactions
+    keep_depth: Option<usize>,
+    // The height of the lowest main-chain block keeping all its transactions, 0 unless pruned
+    pruned_height: usize,
+    // The balances after the highest pruned block, from which the others are replayed
+    pruned_balances: HashMap<Address, i64>,
+    // The nonces after the highest pruned block
+    pruned_nonces: HashMap<Address, u64>,
 }
 
 impl BlockChain {
     /// Create a new blockchain with only the genesis block.
     pub fn new() -> Self {
-        let genesis_block = Block::genesis();
+        BlockChain::with_params(ChainParams::default())
+    }
+
+    /// Create a new blockchain with only the genesis block and the given parameters.
+    pub fn with_params(params: ChainParams) -> Self {
+        let genesis_block = Block::genesis(&params);
         let genesis_hash = genesis_block.hash();
+        let mut balances = HashMap::new();
+        apply_transactions(&mut balances, &genesis_block);
+        let mut tx_index = HashMap::new();
+        index_transactions(&mut tx_index, genesis_hash, &genesis_block);
         BlockChain {
+            params,
             blocks: HashMap::from([(
                 genesis_hash,
                 BlockEntry {
+                    tx_count: genesis_block.transactions.0.len(),
+                    work: block_work(&genesis_block),
                     block: genesis_block,
                     height: 0,
+                    pruned: false,
                 },
             )]),
             highest_block_hash: genesis_hash,
+            future_block_rejections: 0,
+            orphans: HashMap::new(),
+            orphan_arrivals: VecDeque::new(),
+            balances,
+            tx_index,
+            // the genesis block only mints
+            nonces: HashMap::new(),
+            checkpoints: HashMap::new(),
+            keep_depth: None,
+            pruned_height: 0,
+            pruned_balances: HashMap::new(),
+            pruned_nonces: HashMap::new(),
+        }
+    }
+
+    /// Builds a chain from the genesis block and the given blocks, e.g. to reach a certain state
+    /// in tests without the network. Each block's parent must be the genesis block or precede
+    /// it in the slice, otherwise this fails with [BlockError::UnknownParent] as no orphans are
+    /// buffered. Fails on the first invalid block.
+    pub fn from_blocks(params: ChainParams, blocks: &[Block]) -> Result<Self, BlockError> {
+        let mut chain = BlockChain::with_params(params);
+        for block in blocks {
+            if let Some((hash, _)) = chain.validate(block, true)? {
+                chain.insert(hash, block.clone());
+            }
         }
+        Ok(chain)
+    }
+
+    /// Returns the consensus parameters of this chain.
+    pub fn params(&self) -> &ChainParams {
+        &self.params
     }
 
     fn highest_block_entry(&self) -> &BlockEntry {
@@ -44,34 +227,1067 @@ impl BlockChain {
         &self.highest_block_entry().block
     }
 
-    /// Returns the length (i.e. height + 1) of the longest chain (i.e. the main chain).
+    /// Returns the hash of the highest block on the main chain, without hashing it again.
+    pub fn highest_block_hash(&self) -> Hash {
+        self.highest_block_hash
+    }
+
+    /// Returns the number of all blocks of the chain, i.e. on the main chain and on forks,
+    /// but not the buffered orphans, see [Self::orphan_count].
+    pub fn total_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns the block with the given hash, if it is part of the chain (main or side chain).
+    /// Fails if the block is pruned, see [Self::set_keep_depth].
+    pub fn block(&self, hash: &Hash) -> Result<Option<&Block>, Pruned> {
+        match self.blocks.get(hash) {
+            Some(entry) if entry.pruned => Err(Pruned(self.pruned_height)),
+            entry => Ok(entry.map(|entry| &entry.block)),
+        }
+    }
+
+    /// Whether the block with the given hash is part of the chain, pruned or not.
+    pub fn contains_block(&self, hash: &Hash) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Returns the main-chain blocks from the given height up to the highest block (in
+    /// ascending order of height). Empty if the height is beyond the main chain. Pruned blocks
+    /// are left out, see [Self::pruned_height].
+    pub fn main_chain_from(&self, height: usize) -> Vec<&Block> {
+        let mut blocks: Vec<&Block> = (self.main_chain_entries())
+            .take_while(|e| e.height >= height && !e.pruned)
+            .map(|e| &e.block)
+            .collect();
+        blocks.reverse();
+        blocks
+    }
+
+    /// Iterates over the main chain from genesis (or the lowest block that is not pruned) to the
+    /// highest block.
+    pub fn iter_main_chain(&self) -> impl Iterator<Item = &Block> {
+        self.main_chain_from(0).into_iter()
+    }
+
+    /// Returns the main-chain block at the given height, if the main chain is that long and the
+    /// block is not pruned.
+    pub fn block_at_height(&self, height: usize) -> Option<&Block> {
+        (self.main_chain_entry_at(height))
+            .filter(|e| !e.pruned)
+            .map(|e| &e.block)
+    }
+
+    /// Returns the main-chain entry at the given height, pruned or not.
+    fn main_chain_entry_at(&self, height: usize) -> Option<&BlockEntry> {
+        (self.main_chain_entries())
+            .take_while(|e| e.height >= height)
+            .find(|e| e.height == height)
+    }
+
+    /// Returns the blocks from the given block down to where its branch meets the main chain,
+    /// e.g. the blocks a reorganisation took off the main chain if given the former highest
+    /// block. Empty if the block is on the main chain (or unknown).
+    pub fn stale_branch(&self, hash: &Hash) -> Vec<&Block> {
+        let mut main = self.main_chain_entries().peekable();
+        let branch = iter::successors(self.blocks.get(hash), |entry| {
+            self.blocks.get(&entry.block.header.prev_block_hash)
+        });
+        branch
+            .take_while(|entry| {
+                while main.next_if(|m| m.height > entry.height).is_some() {}
+                !main.peek().is_some_and(|m| std::ptr::eq(*m, *entry))
+            })
+            .map(|entry| &entry.block)
+            .collect()
+    }
+
+    /// Walks the main chain back from the highest block to genesis.
+    fn main_chain_entries(&self) -> impl Iterator<Item = &BlockEntry> {
+        self.ancestors(self.highest_block_entry())
+    }
+
+    /// Walks the chain back from the given entry (included) to genesis.
+    fn ancestors<'a>(&'a self, entry: &'a BlockEntry) -> impl Iterator<Item = &'a BlockEntry> {
+        iter::successors(Some(entry), |entry| {
+            self.blocks.get(&entry.block.header.prev_block_hash)
+        })
+    }
+
+    /// Whether the work of each main-chain entry adds its block's work to its parent's,
+    /// i.e. strictly increases from genesis to the highest block.
+    fn main_chain_work_adds_up(&self) -> bool {
+        let mut entries = self.main_chain_entries().peekable();
+        while let Some(entry) = entries.next() {
+            let parent_work = entries.peek().map_or(0, |parent| parent.work);
+            if parent_work.checked_add(block_work(&entry.block)) != Some(entry.work) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the length (i.e. height + 1) of the main chain, i.e. the chain with the most work.
+    /// As the difficulty varies, that need not be the longest chain.
     pub fn main_chain_length(&self) -> usize {
         self.highest_block_entry().height + 1
     }
 
-    /// Verifies a block and if it is valid, adds it to this blockchain.
-    /// Returns whether the block was accepted and new or not.
-    // TODO: currently, this only accepts blocks for which the parent is known i.e.
-    //   orphans are rejected.
-    pub fn add_block(&mut self, block: &Block) -> bool {
-        if let Some(parent) = block
-            .is_valid()
-            .then(|| self.blocks.get(&block.header.prev_block_hash))
-            .flatten()
-        {
+    /// Returns the number of committed transactions, i.e. transactions on the main chain.
+    pub fn total_transactions(&self) -> usize {
+        self.highest_block_entry().tx_count
+    }
+
+    /// Whether the given transaction is committed, i.e. included in a block on the main chain.
+    pub fn contains_transaction(&self, tx_hash: &Hash) -> bool {
+        self.tx_index.contains_key(tx_hash)
+    }
+
+    /// Returns a committed transaction and the height of the (main-chain) block including it.
+    /// Should the transaction be included more than once, this is the lowest block. Transactions
+    /// of pruned blocks are not found anymore, see [Self::set_keep_depth].
+    pub fn find_transaction(&self, tx_hash: &Hash) -> Option<(&Transaction, usize)> {
+        let entry = self.blocks.get(self.tx_index.get(tx_hash)?)?;
+        let tx = (entry.block.transactions.0.iter()).find(|t| &t.hash() == tx_hash)?;
+        Some((tx, entry.height))
+    }
+
+    /// Returns the number of confirmations of a committed transaction, i.e. `1` if it is in the
+    /// highest block, `2` if one block is on top of it etc. Returns `None` if not committed.
+    pub fn confirmations(&self, tx_hash: &Hash) -> Option<usize> {
+        let (_, height) = self.find_transaction(tx_hash)?;
+        Some(self.main_chain_length() - height)
+    }
+
+    /// Picks a committed transaction, e.g. for test harnesses to query or propagate. The `rng`
+    /// is given the number of committed transactions and returns the index of the one to pick,
+    /// counting from genesis. Picking one of a pruned block returns nothing.
+    pub fn random_committed_tx(
+        &self,
+        rng: impl FnOnce(usize) -> usize,
+    ) -> Option<(Hash, &Transaction)> {
+        let total = self.total_transactions();
+        if total == 0 {
+            return None;
+        }
+        let index = rng(total) % total;
+        let entry = (self.main_chain_entries())
+            .take_while(|entry| !entry.pruned)
+            .find(|entry| entry.tx_count - entry.block.transactions.0.len() <= index)?;
+        let offset = index + entry.block.transactions.0.len() - entry.tx_count;
+        let tx = &entry.block.transactions.0[offset];
+        Some((tx.hash(), tx))
+    }
+
+    /// Returns the difficulty a block at the given height on the main chain must be mined at.
+    /// The next block to be mined is at height [Self::main_chain_length].
+    ///
+    /// # Panics
+    /// If the height is above the next block's, as its difficulty is not yet known.
+    pub fn difficulty_for_height(&self, height: usize) -> u32 {
+        let next = self.main_chain_length();
+        assert!(height <= next, "difficulty of height {} is unknown", height);
+        if height == 0 {
+            return self.params.genesis.difficulty;
+        }
+        let mut parent = self.highest_block_entry();
+        for _ in height..next {
+            parent = &self.blocks[&parent.block.header.prev_block_hash];
+        }
+        self.next_difficulty(parent)
+    }
+
+    /// Returns the difficulty of a block on top of the given parent. Every
+    /// [ChainParams::retarget_window] blocks, the difficulty goes up (down) by one bit, i.e.
+    /// the work doubles (halves), if the last blocks came twice as fast (slow) as targeted.
+    fn next_difficulty(&self, parent: &BlockEntry) -> u32 {
+        // Genesis is given, so the first blocks start from the chain's difficulty
+        let difficulty = match parent.height {
+            0 => self.params.difficulty,
+            _ => parent.block.header.difficulty(),
+        };
+        let Some(window) = self.params.retarget_window else {
+            return difficulty;
+        };
+        // Measure the time of at most a window of blocks, but without genesis whose
+        // timestamp is arbitrary
+        let blocks = window.min(parent.height.saturating_sub(1));
+        if !(parent.height + 1).is_multiple_of(window) || blocks == 0 {
+            return difficulty;
+        }
+        let mut first = parent;
+        for _ in 0..blocks {
+            first = &self.blocks[&first.block.header.prev_block_hash];
+        }
+        let actual = parent.block.header.timestamp() - first.block.header.timestamp();
+        let target = blocks as u64 * self.params.target_block_interval;
+        if actual * 2 < target {
+            difficulty + 1
+        } else if actual > target * 2 {
+            difficulty.saturating_sub(1)
+        } else {
+            difficulty
+        }
+    }
+
+    /// Returns the height and difficulty of every block on the main chain, from genesis to tip.
+    /// Nodes serve it to tools, see [crate::Message::GetDifficultyHistory].
+    pub fn difficulty_history(&self) -> Vec<(usize, u32)> {
+        let mut history = Vec::with_capacity(self.main_chain_length());
+        let mut hash = self.highest_block_hash;
+        while let Some(entry) = self.blocks.get(&hash) {
+            history.push((entry.height, entry.block.header.difficulty()));
+            hash = entry.block.header.prev_block_hash;
+        }
+        history.reverse();
+        history
+    }
+
+    /// Returns the current balance of the address, i.e. after all committed transactions.
+    /// Unlike [Self::balance_at], this is kept up to date while adding blocks.
+    pub fn balance_of(&self, address: &Address) -> u32 {
+        clamp_balance(self.balances.get(address).copied().unwrap_or(0))
+    }
+
+    /// Returns the part of the address' current balance that the next block may spend, i.e.
+    /// without the "coin" of coinbases that are still immature then, see
+    /// [ChainParams::coinbase_maturity].
+    pub fn spendable_balance_of(&self, address: &Address) -> u32 {
+        let immature = self.immature_coinbases(self.highest_block_entry(), None);
+        let balance = self.balances.get(address).copied().unwrap_or(0);
+        clamp_balance(balance - immature.get(address).unwrap_or(&0))
+    }
+
+    /// Returns the current balances of the addresses, in their order, like [Self::balance_of].
+    /// Wallets with many addresses thus query them all in one call, e.g. under one lock.
+    pub fn balances(&self, addresses: &[Address]) -> Vec<u32> {
+        addresses
+            .iter()
+            .map(|address| self.balance_of(address))
+            .collect()
+    }
+
+    /// Returns the nonce of the address' last committed transaction, or `0` if there is none.
+    /// Its next transaction must have the nonce after, see [Transaction::nonce], also within a
+    /// block, see [BlockError::WrongNonce].
+    pub fn nonce_of(&self, address: &Address) -> u64 {
+        self.nonces.get(address).copied().unwrap_or(0)
+    }
+
+    /// Returns all addresses with a positive balance, richest first (and by address for equal
+    /// balances), e.g. for a "rich list".
+    pub fn accounts(&self) -> Vec<(Address, u32)> {
+        let mut accounts: Vec<(Address, u32)> = (self.balances.iter())
+            .map(|(address, balance)| (*address, clamp_balance(*balance)))
+            .filter(|(_, balance)| *balance > 0)
+            .collect();
+        accounts.sort_unstable_by(|(a, a_balance), (b, b_balance)| {
+            b_balance.cmp(a_balance).then(a.cmp(b))
+        });
+        accounts
+    }
+
+    /// Returns the sum of all balances, i.e. all "coin" ever minted by the coinbases of the main
+    /// chain.
+    pub fn circulating_supply(&self) -> u64 {
+        (self.balances.values())
+            .map(|balance| clamp_balance(*balance) as u64)
+            .sum()
+    }
+
+    /// Returns the balance of the address after the main-chain block at the given height (or the
+    /// highest block, if the chain is shorter), by replaying all transactions up to it. Fails
+    /// below the highest pruned block, whose balances are the oldest known, see
+    /// [Self::set_keep_depth].
+    // TODO: replaying gets slow for long chains. Once that matters, keep periodic snapshots.
+    pub fn balance_at(&self, address: &Address, height: usize) -> Result<u32, Pruned> {
+        if height < self.pruned_height.saturating_sub(1) {
+            return Err(Pruned(self.pruned_height));
+        }
+        let balances = self.replay_balances(height);
+        Ok(clamp_balance(balances.get(address).copied().unwrap_or(0)))
+    }
+
+    /// Recomputes all balances by replaying the main chain and returns the addresses whose
+    /// balance differs from the one kept up to date while adding blocks (see [Self::balance_of]).
+    /// This audits the incremental bookkeeping, so it should always be empty.
+    pub fn audit_balances(&self) -> Vec<Address> {
+        let replayed = self.replay_balances(usize::MAX);
+        let mut divergent: Vec<Address> = (replayed.keys().chain(self.balances.keys()))
+            .filter(|a| replayed.get(*a).unwrap_or(&0) != self.balances.get(*a).unwrap_or(&0))
+            .copied()
+            .collect();
+        divergent.sort_unstable();
+        divergent.dedup();
+        divergent
+    }
+
+    /// Computes the balances after the main-chain block at the given height by replaying all
+    /// transactions up to it, starting from the balances after the pruned blocks.
+    fn replay_balances(&self, height: usize) -> HashMap<Address, i64> {
+        let mut balances = self.pruned_balances.clone();
+        let mut hash = self.highest_block_hash;
+        while let Some(entry) = self.blocks.get(&hash).filter(|e| !e.pruned) {
+            if entry.height <= height {
+                apply_transactions(&mut balances, &entry.block);
+            }
+            hash = entry.block.header.prev_block_hash;
+        }
+        balances
+    }
+
+    /// Returns the fraction of all known blocks (main and side chains) that are not on
+    /// the main chain, i.e. were orphaned by a fork.
+    pub fn orphan_rate(&self) -> f64 {
+        let orphaned = self.blocks.len() - self.main_chain_length();
+        orphaned as f64 / self.blocks.len() as f64
+    }
+
+    /// Returns the height, work and block production rate of the main chain.
+    pub fn stats(&self) -> ChainStats {
+        let height = self.main_chain_length() - 1;
+        let total_work = self.highest_block_entry().work;
+        let oldest_timestamp = self.params.genesis.timestamp;
+        let newest_timestamp = self.highest_block().header.timestamp();
+        // block timestamps strictly increase, so the intervals add up to the whole time span
+        let avg_block_interval = (height >= 2).then(|| {
+            let first = self
+                .main_chain_entry_at(1)
+                .expect("the main chain is that high");
+            (newest_timestamp - first.block.header.timestamp()) as f64 / (height - 1) as f64
+        });
+        ChainStats {
+            height,
+            total_work,
+            avg_block_interval,
+            oldest_timestamp,
+            newest_timestamp,
+        }
+    }
+
+    /// Returns how many blocks were rejected for a timestamp too far in the future.
+    /// Many of them hint at skewed clocks, either locally or in the network.
+    pub fn future_block_rejections(&self) -> usize {
+        self.future_block_rejections
+    }
+
+    /// Returns the hashes of all known blocks whose parent is the given block (in no
+    /// particular order). More than one child means the chain forks at that block.
+    pub fn children_of(&self, hash: &Hash) -> Vec<Hash> {
+        self.blocks
+            .iter()
+            .filter(|(_, entry)| &entry.block.header.prev_block_hash == hash)
+            .map(|(child, _)| *child)
+            .collect()
+    }
+
+    /// Returns the lowest common ancestor of the two given blocks, i.e. the block at which
+    /// their chains fork. Returns `None` if either of the blocks is unknown.
+    pub fn common_ancestor(&self, a: &Hash, b: &Hash) -> Option<Hash> {
+        let (mut a, mut b) = (*a, *b);
+        let (mut entry_a, mut entry_b) = (self.blocks.get(&a)?, self.blocks.get(&b)?);
+        // Walk back the higher of the two until both meet
+        while a != b {
+            if entry_a.height >= entry_b.height {
+                a = entry_a.block.header.prev_block_hash;
+                entry_a = self.blocks.get(&a)?;
+            } else {
+                b = entry_b.block.header.prev_block_hash;
+                entry_b = self.blocks.get(&b)?;
+            }
+        }
+        Some(a)
+    }
+
+    /// Writes all blocks (main and side chains, but no orphans) and the main-chain pointer to
+    /// the given file, such that the chain survives a restart. A pruning chain also writes its
+    /// pruned blocks, with only their coinbase, and the balances and nonces after them.
+    pub fn save_to_path(&self, path: &Path) -> io::Result<()> {
+        let mut entries: Vec<(&Hash, &BlockEntry)> =
+            (self.blocks.iter()).filter(|(_, e)| !e.pruned).collect();
+        // Parents (with less work) before children and for equal work, the main chain first:
+        // only a block with strictly more work takes over the tip, so the first one re-added wins.
+        let main_chain: HashSet<Hash> = self.iter_main_chain().map(Block::hash).collect();
+        entries.sort_by_key(|(hash, entry)| (entry.work, !main_chain.contains(*hash)));
+        let blocks: Vec<&Block> = entries.into_iter().map(|(_, entry)| &entry.block).collect();
+        let pruning = (self.keep_depth.is_some() || self.pruned_height > 0).then(|| {
+            let mut pruned: Vec<(Block, usize)> = (self.main_chain_entries())
+                .filter(|e| e.pruned)
+                .map(|e| (e.block.clone(), e.tx_count))
+                .collect();
+            pruned.reverse();
+            SavedPruning {
+                keep_depth: self.keep_depth,
+                blocks: pruned,
+                balances: self.pruned_balances.clone(),
+                nonces: self.pruned_nonces.clone(),
+            }
+        });
+        Self::write_saved(path, &blocks, self.highest_block_hash, pruning.as_ref())
+    }
+
+    /// Reads a chain saved by [Self::save_to_path] with the given parameters. Every block is
+    /// validated again, so a tampered file fails with [ErrorKind::InvalidData] instead of
+    /// producing an invalid chain. Of pruned blocks, only the headers can be validated again, so
+    /// the balances and nonces after them are taken as saved.
+    pub fn load_from_path(path: &Path, params: ChainParams) -> io::Result<Self> {
+        let (blocks, highest_block_hash, pruning) = Self::read_saved(path)?;
+        let mut chain = BlockChain::with_params(params);
+        if let Some(pruning) = pruning {
+            chain.restore_pruned(pruning)?;
+        }
+        for block in &blocks {
+            match chain.add_block(block) {
+                Ok(AddResult::New(_)) | Ok(AddResult::Duplicate) => {}
+                result => {
+                    let reason = format!("block {} is {:?}", block.hash().encode(), result);
+                    return Err(io::Error::new(ErrorKind::InvalidData, reason));
+                }
+            }
+        }
+        if chain.highest_block_hash != highest_block_hash {
+            let reason = "the highest block does not match the saved main chain";
+            return Err(io::Error::new(ErrorKind::InvalidData, reason));
+        }
+        Ok(chain)
+    }
+
+    /// Puts the pruned blocks saved by [Self::save_to_path] on top of the genesis block of a new
+    /// chain, whose pruning mode they restore. Their headers are validated again, but as their
+    /// transactions are gone, the balances and nonces after them are taken as saved.
+    fn restore_pruned(&mut self, pruning: SavedPruning) -> io::Result<()> {
+        self.keep_depth = pruning.keep_depth;
+        let mut blocks = pruning.blocks.into_iter();
+        let Some((genesis, _)) = blocks.next() else {
+            return Ok(());
+        };
+        let genesis_hash = self.highest_block_hash;
+        if genesis.hash() != genesis_hash {
+            let reason = "the pruned blocks don't start at the genesis block";
+            return Err(io::Error::new(ErrorKind::InvalidData, reason));
+        }
+        for (block, tx_count) in blocks {
+            let parent = self.highest_block_entry();
+            let hash = block.hash();
+            let is_valid = block.header.prev_block_hash == self.highest_block_hash
+                && block.header.is_valid()
+                && block.header.timestamp() > parent.block.header.timestamp()
+                && block.header.difficulty() == self.next_difficulty(parent);
+            if !is_valid {
+                let reason = format!("pruned block {} is invalid", hash.encode());
+                return Err(io::Error::new(ErrorKind::InvalidData, reason));
+            }
             let entry = BlockEntry {
-                block: block.clone(),
                 height: parent.height + 1,
+                tx_count,
+                work: parent.work + block_work(&block),
+                block,
+                pruned: true,
             };
-            let hash = block.hash();
-            // Update the main-chain pointer if this block is now the highest
-            if entry.height >= self.main_chain_length() {
-                self.highest_block_hash = hash;
+            self.blocks.insert(hash, entry);
+            self.highest_block_hash = hash;
+        }
+        let genesis = (self.blocks.get_mut(&genesis_hash)).expect("genesis is in the chain");
+        genesis
+            .block
+            .transactions
+            .0
+            .retain(Transaction::is_coinbase);
+        genesis.pruned = true;
+        self.pruned_height = self.main_chain_length();
+        self.tx_index.clear();
+        self.balances = pruning.balances.clone();
+        self.pruned_balances = pruning.balances;
+        self.nonces = pruning.nonces.clone();
+        self.pruned_nonces = pruning.nonces;
+        Ok(())
+    }
+
+    /// Reads the blocks of a chain saved by [Self::save_to_path], parents before children,
+    /// without validating them, e.g. to add them to a running chain. Pruned blocks are left out.
+    pub fn read_blocks(path: &Path) -> io::Result<Vec<Block>> {
+        Ok(Self::read_saved(path)?.0)
+    }
+
+    /// Rewrites the difficulty of every block of a chain saved by [Self::save_to_path] with the
+    /// given conversion, e.g. [crate::difficulty_from_bytes] for a chain saved while the
+    /// difficulty counted bytes. The difficulty is part of the header, so each block is mined
+    /// again (on top of its migrated parent), and the chain stays valid. It is then loaded like
+    /// any other, see [Self::load_from_path]. Pruned blocks keep their merkle root, so they are
+    /// migrated just the same.
+    pub fn migrate_saved(path: &Path, convert: impl Fn(u32) -> u32) -> io::Result<()> {
+        let (blocks, highest_block_hash, mut pruning) = Self::read_saved(path)?;
+        let mut migrated_hashes: HashMap<Hash, Hash> = HashMap::new();
+        let mut migrate = |block: &Block| {
+            let parent = block.header.prev_block_hash;
+            let parent = migrated_hashes.get(&parent).copied().unwrap_or(parent);
+            let migrated_block = block.mine_again(parent, convert(block.header.difficulty()));
+            migrated_hashes.insert(block.hash(), migrated_block.hash());
+            migrated_block
+        };
+        // parents are saved before their children (and pruned blocks are the lowest), so they
+        // are migrated first
+        for (block, _) in pruning.iter_mut().flat_map(|p| p.blocks.iter_mut()) {
+            *block = migrate(block);
+        }
+        let migrated: Vec<Block> = blocks.iter().map(&mut migrate).collect();
+        let highest_block_hash = (migrated_hashes.get(&highest_block_hash))
+            .copied()
+            .unwrap_or(highest_block_hash);
+        Self::write_saved(
+            path,
+            &migrated.iter().collect::<Vec<_>>(),
+            highest_block_hash,
+            pruning.as_ref(),
+        )
+    }
+
+    fn read_saved(path: &Path) -> io::Result<(Vec<Block>, Hash, Option<SavedPruning>)> {
+        let bytes = fs::read(path)?;
+        // chains saved before pruning existed end right after the main-chain pointer
+        bincode::deserialize(&bytes)
+            .or_else(|_| {
+                bincode::deserialize::<(Vec<Block>, Hash)>(&bytes)
+                    .map(|(blocks, highest_block_hash)| (blocks, highest_block_hash, None))
+            })
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn write_saved(
+        path: &Path,
+        blocks: &[&Block],
+        highest_block_hash: Hash,
+        pruning: Option<&SavedPruning>,
+    ) -> io::Result<()> {
+        let bytes = bincode::serialize(&(blocks, highest_block_hash, pruning))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        fs::write(path, bytes)
+    }
+
+    /// Switches to pruning mode, or back if `None`. Once a main-chain block is buried below the
+    /// highest `keep_depth` blocks, it only keeps its header and coinbase, also when saved (see
+    /// [Self::save_to_path]). So the chain's headers still add up and the maturity of coinbases
+    /// is still checked, but queries for pruned transactions fail with [Pruned] (or find nothing)
+    /// and blocks forking off below the pruned blocks are rejected with
+    /// [BlockError::PrunedFork]. Pruned blocks stay pruned when leaving pruning mode.
+    pub fn set_keep_depth(&mut self, keep_depth: Option<usize>) {
+        self.keep_depth = keep_depth;
+        self.prune();
+    }
+
+    /// Returns the height below which main-chain blocks are pruned, i.e. `0` if none are, see
+    /// [Self::set_keep_depth].
+    pub fn pruned_height(&self) -> usize {
+        self.pruned_height
+    }
+
+    /// Discards the transactions but the coinbase of the main-chain blocks below the highest
+    /// [Self::set_keep_depth] ones, along with the side branches forking off below them, which
+    /// could never be validated again. The balances and nonces after the pruned blocks are kept
+    /// to replay the others from, see [Self::replay_balances].
+    fn prune(&mut self) {
+        let Some(keep_depth) = self.keep_depth else {
+            return;
+        };
+        let height = self.main_chain_length().saturating_sub(keep_depth);
+        if height <= self.pruned_height {
+            return;
+        }
+        let mut newly_pruned = Vec::new();
+        let mut hash = self.highest_block_hash;
+        while let Some(entry) = self.blocks.get(&hash).filter(|e| !e.pruned) {
+            if entry.height < height {
+                newly_pruned.push(hash);
+            }
+            hash = entry.block.header.prev_block_hash;
+        }
+        for hash in newly_pruned.into_iter().rev() {
+            let entry = (self.blocks.get_mut(&hash)).expect("main-chain block is in the chain");
+            apply_transactions(&mut self.pruned_balances, &entry.block);
+            record_nonces(&mut self.pruned_nonces, &entry.block);
+            for tx in &entry.block.transactions.0 {
+                let tx_hash = tx.hash();
+                if self.tx_index.get(&tx_hash) == Some(&hash) {
+                    self.tx_index.remove(&tx_hash);
+                }
+            }
+            entry.block.transactions.0.retain(Transaction::is_coinbase);
+            entry.pruned = true;
+        }
+        self.pruned_height = height;
+        // all main-chain blocks below are pruned now, the others are on side branches
+        let dead: HashSet<Hash> = (self.blocks.iter())
+            .filter(|(_, entry)| entry.height < height && !entry.pruned)
+            .map(|(hash, _)| *hash)
+            .collect();
+        self.remove_branches(dead);
+    }
+
+    /// Pins the given height to the block with the given hash, e.g. as announced by a trusted
+    /// node. Other blocks at this height are rejected from then on, so no fork branching off
+    /// below it can ever overtake the checkpointed chain. Side-chain blocks already stored there
+    /// are pruned along with their descendants, such that they can't get children either.
+    /// Returns whether the checkpoint was new and adopted, which it is not if the height is
+    /// already pinned or the main chain has another block there.
+    // TODO: a checkpoint contradicting the main chain is ignored. Instead, the node could switch
+    //  to the checkpointed fork if known, or else sync it from its peers.
+    pub fn add_checkpoint(&mut self, height: usize, hash: Hash) -> bool {
+        if self.checkpoints.contains_key(&height)
+            || self
+                .main_chain_entry_at(height)
+                .is_some_and(|e| e.block.hash() != hash)
+        {
+            return false;
+        }
+        self.checkpoints.insert(height, hash);
+        self.prune_conflicting(height, hash);
+        true
+    }
+
+    /// Removes the blocks at the given height but the one with the given hash, and all their
+    /// descendants. These are never on the main chain, so only side chains shrink.
+    fn prune_conflicting(&mut self, height: usize, hash: Hash) {
+        let conflicting: HashSet<Hash> = (self.blocks.iter())
+            .filter(|(child, entry)| entry.height == height && *child != &hash)
+            .map(|(child, _)| *child)
+            .collect();
+        self.remove_branches(conflicting);
+    }
+
+    /// Removes the given blocks and all their descendants, which must not be on the main chain.
+    fn remove_branches(&mut self, roots: HashSet<Hash>) {
+        let Some(lowest) = roots.iter().map(|root| self.blocks[root].height).min() else {
+            return;
+        };
+        let mut entries: Vec<(&Hash, &BlockEntry)> = (self.blocks.iter())
+            .filter(|(_, entry)| entry.height > lowest)
+            .collect();
+        // parents before their children
+        entries.sort_by_key(|(_, entry)| entry.height);
+        let mut removed = roots;
+        for (child, entry) in entries {
+            if removed.contains(&entry.block.header.prev_block_hash) {
+                removed.insert(*child);
+            }
+        }
+        self.blocks.retain(|hash, _| !removed.contains(hash));
+    }
+
+    /// Verifies a block and if it is valid, adds it to this blockchain.
+    /// Returns whether the block was new, already known or buffered as an orphan,
+    /// or why it was rejected. Adding a block also connects the orphans waiting for it.
+    /// The block is validated as borrowed and only copied into the chain once accepted.
+    /// The height of an orphan is unknown until its parent arrives, so checkpoints are only
+    /// checked when connecting it, see [Self::add_checkpoint].
+    pub fn add_block(&mut self, block: &Block) -> Result<AddResult, BlockError> {
+        self.add(block, true)
+    }
+
+    /// Like [Self::add_block], but without verifying the block's proof-of-work, e.g. to sync
+    /// faster from peers that are trusted to have verified it. All other rules are still
+    /// checked, such that a structurally invalid block is rejected.
+    /// Orphans are verified fully, both when buffered and when connected by the block.
+    pub fn add_block_trusting_pow(&mut self, block: &Block) -> Result<AddResult, BlockError> {
+        self.add(block, false)
+    }
+
+    fn add(&mut self, block: &Block, check_pow: bool) -> Result<AddResult, BlockError> {
+        match self.validate(block, check_pow) {
+            Ok(Some((hash, _))) => {
+                self.insert(hash, block.clone());
+                Ok(AddResult::New(self.connect_orphans(hash)))
+            }
+            Ok(None) => Ok(AddResult::Duplicate),
+            Err(BlockError::UnknownParent) => self.buffer_orphan(block, check_pow),
+            Err(e) => {
+                if e == BlockError::FutureTimestamp {
+                    self.future_block_rejections += 1;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Verifies a block like [Self::add_block] and reports what adding it would result in,
+    /// but without changing the chain. The orphans a new block would connect are neither
+    /// reported nor accounted for in the resulting tip and work.
+    pub fn dry_run_add(&self, block: &Block) -> Result<DryRun, BlockError> {
+        let highest = self.highest_block_entry();
+        let result = match self.validate(block, true) {
+            // like when inserting, only a block with more work becomes the tip
+            Ok(Some((hash, parent))) if parent.work + block_work(block) > highest.work => {
+                let total_work = parent.work + block_work(block);
+                return Ok(DryRun {
+                    result: AddResult::New(vec![hash]),
+                    tip: hash,
+                    total_work,
+                });
+            }
+            Ok(Some((hash, _))) => AddResult::New(vec![hash]),
+            Ok(None) => AddResult::Duplicate,
+            Err(BlockError::UnknownParent)
+                if block.header.difficulty() >= self.min_orphan_difficulty() =>
+            {
+                AddResult::Orphan
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(DryRun {
+            result,
+            tip: self.highest_block_hash,
+            total_work: highest.work,
+        })
+    }
+
+    /// Returns the buffered orphans, i.e. blocks waiting for their parent, ordered by hash.
+    /// Many of them hint at a node that fell behind or peers sending unconnected blocks.
+    pub fn orphan_blocks(&self) -> Vec<&Block> {
+        let mut orphans: Vec<&Block> = self.orphans.values().flatten().collect();
+        orphans.sort_by_cached_key(|block| block.hash());
+        orphans
+    }
+
+    /// Returns the number of buffered orphans, i.e. blocks waiting for their parent.
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.values().map(Vec::len).sum()
+    }
+
+    /// Inserts an already validated block.
+    fn insert(&mut self, hash: Hash, block: Block) {
+        let parent = self
+            .blocks
+            .get(&block.header.prev_block_hash)
+            .expect("parent of a validated block must be in the chain");
+        let entry = BlockEntry {
+            height: parent.height + 1,
+            tx_count: parent.tx_count + block.transactions.0.len(),
+            work: parent.work + block_work(&block),
+            block,
+            pruned: false,
+        };
+        // Update the main-chain pointer (and balances) if this block now has the most work. On
+        // a tie, the block seen first stays the tip.
+        let is_highest = entry.work > self.highest_block_entry().work;
+        let extends_main_chain = entry.block.header.prev_block_hash == self.highest_block_hash;
+        if is_highest && extends_main_chain {
+            apply_transactions(&mut self.balances, &entry.block);
+            index_transactions(&mut self.tx_index, hash, &entry.block);
+            record_nonces(&mut self.nonces, &entry.block);
+        }
+        let previous_highest = self.highest_block_hash;
+        self.blocks.insert(hash, entry);
+        // A fork became the main chain: take back the transactions of the former main chain down
+        // to where the fork branches off and apply the fork's, like when validating a block
+        if is_highest && !extends_main_chain {
+            let ledger = (self.ledger_after(hash))
+                .expect("a validated block doesn't fork off below pruned blocks");
+            let (balances, nonces) = (ledger.balances, ledger.nonces);
+            for (address, difference) in balances {
+                *self.balances.entry(address).or_default() += difference;
+            }
+            self.nonces.extend(nonces);
+        }
+        if is_highest {
+            self.highest_block_hash = hash;
+        }
+        if is_highest && !extends_main_chain {
+            let stale: HashSet<Hash> = (self.stale_branch(&previous_highest).into_iter())
+                .map(Block::hash)
+                .collect();
+            self.tx_index
+                .retain(|_, block_hash| !stale.contains(block_hash));
+            let fork = (self.common_ancestor(&hash, &previous_highest))
+                .expect("both blocks are in the chain");
+            let branch: Vec<Hash> = (self.main_chain_from(self.blocks[&fork].height + 1).iter())
+                .map(|block| block.hash())
+                .collect();
+            for block_hash in branch {
+                index_transactions(
+                    &mut self.tx_index,
+                    block_hash,
+                    &self.blocks[&block_hash].block,
+                );
             }
-            return self.blocks.insert(hash, entry).is_none();
         }
-        false
+        if is_highest {
+            self.prune();
+        }
+        debug_assert!(
+            self.main_chain_work_adds_up(),
+            "main-chain work must add up"
+        );
+    }
+
+    /// Returns the lowest difficulty at which blocks whose parent is unknown are buffered as
+    /// orphans, i.e. the next main-chain block's. So cheaply mined blocks can't displace the
+    /// orphans of an honest chain we fell behind on.
+    pub fn min_orphan_difficulty(&self) -> u32 {
+        self.difficulty_for_height(self.main_chain_length())
+    }
+
+    /// Buffers a block whose parent is unknown, if mined at the [Self::min_orphan_difficulty].
+    /// Its proof-of-work is verified here unless `pow_checked` already. If the orphan pool is
+    /// full, the oldest orphan is evicted.
+    fn buffer_orphan(&mut self, block: &Block, pow_checked: bool) -> Result<AddResult, BlockError> {
+        let parent = block.header.prev_block_hash;
+        let siblings = self.orphans.get(&parent);
+        if siblings.is_some_and(|siblings| siblings.contains(block)) {
+            return Ok(AddResult::Orphan);
+        }
+        if block.header.difficulty() < self.min_orphan_difficulty() {
+            return Err(BlockError::UnknownParent);
+        }
+        if !pow_checked && !block.header.is_valid() {
+            return Err(BlockError::Invalid);
+        }
+        if self.orphan_count() >= MAX_ORPHANS {
+            self.evict_oldest_orphan();
+        }
+        self.orphans.entry(parent).or_default().push(block.clone());
+        self.orphan_arrivals.push_back(parent);
+        Ok(AddResult::Orphan)
     }
+
+    /// Drops the orphan that arrived first. Siblings are buffered in the order they arrived, so
+    /// it is the first one waiting for the parent that was missing first.
+    fn evict_oldest_orphan(&mut self) {
+        let Some(parent) = self.orphan_arrivals.pop_front() else {
+            return;
+        };
+        if let Some(siblings) = self.orphans.get_mut(&parent) {
+            siblings.remove(0);
+            if siblings.is_empty() {
+                self.orphans.remove(&parent);
+            }
+        }
+    }
+
+    /// Connects all buffered orphans descending from the given (just added) block and returns
+    /// the hashes of all connected blocks, starting with the given one. Orphans that turn out
+    /// to be invalid in the context of their parent are dropped.
+    fn connect_orphans(&mut self, hash: Hash) -> Vec<Hash> {
+        let mut connected = vec![hash];
+        let mut i = 0;
+        while let Some(parent) = connected.get(i).copied() {
+            let orphans = self.orphans.remove(&parent).unwrap_or_default();
+            if !orphans.is_empty() {
+                self.orphan_arrivals.retain(|missing| missing != &parent);
+            }
+            for orphan in orphans {
+                if let Ok(Some((hash, _))) = self.validate(&orphan, true) {
+                    self.insert(hash, orphan);
+                    connected.push(hash);
+                }
+            }
+            i += 1;
+        }
+        connected
+    }
+
+    /// Verifies a block against this chain, its proof-of-work only if `check_pow`. Its
+    /// transactions are checked against the balances and nonces its parent leaves behind, which
+    /// differ from the main chain's for a block on a side branch, see [Self::ledger_after].
+    /// Returns `None` if the block is already known, or else its hash and the entry of its parent.
+    fn validate(
+        &self,
+        block: &Block,
+        check_pow: bool,
+    ) -> Result<Option<(Hash, &BlockEntry)>, BlockError> {
+        let hash = block.hash();
+        // Known blocks (including genesis) must not touch the chain again. They were validated
+        // when first added, so skip the expensive checks, e.g. when re-importing a chain.
+        if self.blocks.contains_key(&hash) {
+            return Ok(None);
+        }
+        let is_valid = if check_pow {
+            block.is_well_formed()
+        } else {
+            block.is_consistent()
+        };
+        if !is_valid {
+            return Err(BlockError::Invalid);
+        }
+        if block.header.timestamp() > now() + self.params.max_future_drift {
+            return Err(BlockError::FutureTimestamp);
+        }
+        let parent = self
+            .blocks
+            .get(&block.header.prev_block_hash)
+            .ok_or(BlockError::UnknownParent)?;
+        if !block.pays_reward(parent.height + 1) {
+            return Err(BlockError::Invalid);
+        }
+        if (self.checkpoints.get(&(parent.height + 1))).is_some_and(|pinned| pinned != &hash) {
+            return Err(BlockError::Checkpoint);
+        }
+        if block.header.timestamp() <= parent.block.header.timestamp() {
+            return Err(BlockError::PastTimestamp);
+        }
+        if block.header.difficulty() != self.next_difficulty(parent) {
+            return Err(BlockError::WrongDifficulty);
+        }
+        let immature = self.immature_coinbases(parent, block.coinbase());
+        self.ledger_after(block.header.prev_block_hash)?
+            .spend(block, &immature)?;
+        Ok(Some((hash, parent)))
+    }
+
+    /// Returns the "coin" minted for each miner by the coinbases of a block and its ancestors
+    /// that the block may not spend yet, see [ChainParams::coinbase_maturity]. These are taken
+    /// from the block's own chain, so a block is checked against its branch, also when a reorg
+    /// later makes it part of the main chain. The block's own coinbase is given, if known.
+    fn immature_coinbases(
+        &self,
+        parent: &BlockEntry,
+        coinbase: Option<&Transaction>,
+    ) -> HashMap<Address, i64> {
+        let height = parent.height + 1;
+        let ancestors = self
+            .ancestors(parent)
+            .map(|e| (e.height, e.block.coinbase()));
+        let mut immature = HashMap::new();
+        let recent = (iter::once((height, coinbase)).chain(ancestors))
+            .take_while(|(h, _)| *h > 0 && h + self.params.coinbase_maturity > height);
+        for coinbase in recent.filter_map(|(_, coinbase)| coinbase) {
+            *immature.entry(*coinbase.receiver()).or_default() += coinbase.amount() as i64;
+        }
+        immature
+    }
+
+    /// Returns the balances and nonces after the given block, i.e. the ones its children are
+    /// validated against. Starts from the main chain's ones, undoes its blocks down to where the
+    /// block's branch forks off and applies the branch up to the block. So it is cheap for the
+    /// highest block and blocks close to it. Fails if main-chain blocks to undo are pruned.
+    fn ledger_after(&self, hash: Hash) -> Result<Ledger<'_>, BlockError> {
+        let mut ledger = Ledger {
+            chain: self,
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+        };
+        let (mut main, mut branch) = (self.highest_block_hash, hash);
+        let mut branch_blocks = Vec::new();
+        // like finding the common ancestor, both end at genesis at the latest
+        while main != branch {
+            let (main_entry, branch_entry) = (&self.blocks[&main], &self.blocks[&branch]);
+            if branch_entry.height >= main_entry.height {
+                branch_blocks.push(&branch_entry.block);
+                branch = branch_entry.block.header.prev_block_hash;
+            } else if main_entry.pruned {
+                return Err(BlockError::PrunedFork);
+            } else {
+                ledger.undo(&main_entry.block);
+                main = main_entry.block.header.prev_block_hash;
+            }
+        }
+        for block in branch_blocks.into_iter().rev() {
+            block.transactions.0.iter().for_each(|tx| ledger.apply(tx));
+        }
+        Ok(ledger)
+    }
+}
+
+/// The balances and nonces after some block, kept as the differences to the ones after the
+/// highest block (see [BlockChain::balance_of] and [BlockChain::nonce_of]), such that validating
+/// a block doesn't copy all of them.
+struct Ledger<'a> {
+    chain: &'a BlockChain,
+    balances: HashMap<Address, i64>,
+    nonces: HashMap<Address, u64>,
+}
+
+impl Ledger<'_> {
+    fn balance(&self, address: &Address) -> i64 {
+        let difference = self.balances.get(address).copied().unwrap_or(0);
+        self.chain.balances.get(address).copied().unwrap_or(0) + difference
+    }
+
+    fn nonce(&self, address: &Address) -> u64 {
+        (self.nonces.get(address).copied()).unwrap_or_else(|| self.chain.nonce_of(address))
+    }
+
+    fn apply(&mut self, tx: &Transaction) {
+        apply_transaction(&mut self.balances, tx);
+        if !tx.is_coinbase() {
+            self.nonces.insert(*tx.spender(), tx.nonce());
+        }
+    }
+
+    /// Takes back the block's transactions, see [apply_transactions]. Nonces have no gaps, so
+    /// the spender's nonce before a transaction is the one just below.
+    fn undo(&mut self, block: &Block) {
+        for tx in block.transactions.0.iter().rev() {
+            *self.balances.entry(*tx.receiver()).or_default() -= tx.amount() as i64;
+            if !tx.is_coinbase() {
+                *self.balances.entry(*tx.spender()).or_default() += tx.cost() as i64;
+                self.nonces.insert(*tx.spender(), tx.nonce() - 1);
+            }
+        }
+    }
+
+    /// Applies the block's transactions in order, unless one of them spends more than its
+    /// spender has or doesn't have their next nonce at that point. Of the spender's balance, the
+    /// given immature "coin" is not spendable, see [BlockChain::immature_coinbases].
+    fn spend(&mut self, block: &Block, immature: &HashMap<Address, i64>) -> Result<(), BlockError> {
+        for tx in block.transactions.0.iter() {
+            if !tx.is_coinbase() {
+                if tx.nonce() != self.nonce(tx.spender()) + 1 {
+                    return Err(BlockError::WrongNonce);
+                }
+                let balance = self.balance(tx.spender());
+                if balance < tx.cost() as i64 {
+                    return Err(BlockError::Overspent);
+                }
+                if balance - immature.get(tx.spender()).unwrap_or(&0) < tx.cost() as i64 {
+                    return Err(BlockError::ImmatureCoinbase);
+                }
+            }
+            self.apply(tx);
+        }
+        Ok(())
+    }
+}
+
+/// Credits the receivers and debits the spenders of the block's transactions, including the
+/// fees, which the coinbase credits to the miner. Coinbase transactions create "coin", so their
+/// spender is never debited.
+fn apply_transactions(balances: &mut HashMap<Address, i64>, block: &Block) {
+    for tx in &block.transactions.0 {
+        apply_transaction(balances, tx);
+    }
+}
+
+/// Credits the receiver and debits the spender of the transaction, see [apply_transactions].
+fn apply_transaction(balances: &mut HashMap<Address, i64>, tx: &Transaction) {
+    *balances.entry(*tx.receiver()).or_default() += tx.amount() as i64;
+    if !tx.is_coinbase() {
+        *balances.entry(*tx.spender()).or_default() -= tx.cost() as i64;
+    }
+}
+
+/// Adds the block's transactions to the index, unless they are already included earlier.
+fn index_transactions(tx_index: &mut HashMap<Hash, Hash>, block_hash: Hash, block: &Block) {
+    for tx in &block.transactions.0 {
+        tx_index.entry(tx.hash()).or_insert(block_hash);
+    }
+}
+
+/// Raises the spenders' highest committed nonces to those of the block's transactions.
+fn record_nonces(nonces: &mut HashMap<Address, u64>, block: &Block) {
+    for tx in block.transactions.0.iter().filter(|tx| !tx.is_coinbase()) {
+        let nonce = nonces.entry(*tx.spender()).or_default();
+        *nonce = tx.nonce().max(*nonce);
+    }
+}
+
+/// The expected number of hashes computed to mine the block, i.e. 2 to the power of its difficulty.
+fn block_work(block: &Block) -> u128 {
+    1 << block.header.difficulty()
+}
+
+/// Blocks never overspend, so balances are never negative. They only exceed the largest amount
+/// if an address collects a lot of "coin", which is capped.
+fn clamp_balance(balance: i64) -> u32 {
+    balance.clamp(0, u32::MAX as i64) as u32
 }
 
 impl Default for BlockChain {
@@ -80,43 +1296,1469 @@ impl Default for BlockChain {
     }
 }
 
+/// Builds a canonical chain of `length` blocks (including genesis) with [ChainParams::TESTING],
+/// i.e. quickly and deterministically. The block at height `h` relays 1 "coin" from
+/// [Transaction::dummy_key] `0`, which receives the [crate::GENESIS_TX], to key `1`, which passes
+/// it on to key `2` and so on up to key `h`. So key `0` spends once per block and every other key
+/// `i` from the block after the one funding it.
+#[cfg(test)]
+pub(crate) fn canonical_chain(length: usize) -> BlockChain {
+    use crate::tx::{address_of, Transaction, Transactions, DUMMY_MINER, GENESIS_TIME};
+
+    let keys: Vec<_> = (0..length as u32).map(Transaction::dummy_key).collect();
+    let addresses: Vec<Address> = (keys.iter())
+        .map(|key| address_of(&key.verifying_key()))
+        .collect();
+    let mut chain = BlockChain::with_params(ChainParams::TESTING);
+    for height in 1..length {
+        let time = GENESIS_TIME + height as u64;
+        let txs = (1..=height)
+            .map(|i| {
+                let nonce = (height - i + 1) as u64;
+                let mut tx = Transaction::new(addresses[i - 1], addresses[i], 1, time);
+                tx = tx.with_nonce(nonce);
+                tx.sign(&keys[i - 1]);
+                tx
+            })
+            .collect();
+        let block = Block::mine_at(
+            chain.highest_block().hash(),
+            0,
+            Transactions(txs),
+            DUMMY_MINER,
+            time,
+        );
+        chain.add_block(&block).expect("canonical block is valid");
+    }
+    chain
+}
+
 #[cfg(test)]
 mod test {
-    use crate::block::Block;
-    use crate::chain::BlockChain;
-    use crate::hash::Hashable;
-    use crate::tx::{Transaction, Transactions};
+    use crate::block::{
+        difficulty_from_bytes, difficulty_to_bytes, Block, BLOCK_REWARD, POW_CHECKS,
+    };
+    use crate::chain::{
+        canonical_chain, AddResult, BlockChain, BlockError, DryRun, Pruned, MAX_ORPHANS,
+    };
+    use crate::hash::{Hash, Hashable, HASH_LENGTH};
+    use crate::params::ChainParams;
+    use crate::tx::{address_of, MINT_ADDRESS};
+    use crate::tx::{now, Transaction, Transactions, DUMMY_MINER, GENESIS_TIME, GENESIS_TX};
     use crate::GLOBAL_DIFFICULTY;
+    use std::collections::HashSet;
+    use std::io::ErrorKind;
 
     #[test]
     fn add_block() {
         let mut chain = BlockChain::new();
         assert_eq!(chain.main_chain_length(), 1);
-        assert_eq!(chain.highest_block(), &Block::genesis());
+        assert_eq!(
+            chain.highest_block(),
+            &Block::genesis(&ChainParams::TESTING)
+        );
         let genesis_hash = chain.highest_block().hash();
 
-        let txs = Transaction::dummy_txs(10);
-        let first_block = Block::mine_new(genesis_hash, GLOBAL_DIFFICULTY, Transactions(txs));
-        assert!(chain.add_block(&first_block));
+        let txs = Transaction::signed_dummy_txs(10);
+        let first_block = Block::mine_new(
+            genesis_hash,
+            GLOBAL_DIFFICULTY,
+            Transactions(txs),
+            DUMMY_MINER,
+        );
+        assert_eq!(
+            chain.add_block(&first_block),
+            Ok(AddResult::New(vec![first_block.hash()]))
+        );
         assert_eq!(chain.main_chain_length(), 2);
         assert_eq!(chain.highest_block(), &first_block);
 
         // add a forked block on genesis block
-        let txs = Transaction::dummy_txs(2);
-        let second_block = Block::mine_new(genesis_hash, GLOBAL_DIFFICULTY, Transactions(txs));
-        assert!(chain.add_block(&second_block));
+        let txs = Transaction::signed_dummy_txs(2);
+        let second_block = Block::mine_new(
+            genesis_hash,
+            GLOBAL_DIFFICULTY,
+            Transactions(txs),
+            DUMMY_MINER,
+        );
+        assert_eq!(
+            chain.add_block(&second_block),
+            Ok(AddResult::New(vec![second_block.hash()]))
+        );
         // length is still two
         assert_eq!(chain.main_chain_length(), 2);
         // highest block is still the first "highest" block
         assert_eq!(chain.highest_block(), &first_block);
 
         // add a second block on the fork
-        let txs = Transaction::dummy_txs(3);
-        let third_block =
-            Block::mine_new(second_block.hash(), GLOBAL_DIFFICULTY, Transactions(txs));
-        assert!(chain.add_block(&third_block));
+        // key 0's next transaction on the fork
+        let txs = Transaction::signed_dummy_txs(3).split_off(2);
+        let time = second_block.header.timestamp() + 1;
+        let third_block = Block::mine_at(
+            second_block.hash(),
+            GLOBAL_DIFFICULTY,
+            Transactions(txs),
+            DUMMY_MINER,
+            time,
+        );
+        assert_eq!(
+            chain.add_block(&third_block),
+            Ok(AddResult::New(vec![third_block.hash()]))
+        );
         assert_eq!(chain.main_chain_length(), 3);
         // now, the highest block has switched
         assert_eq!(chain.highest_block(), &third_block);
     }
+
+    #[test]
+    fn fork_navigation() {
+        let mut chain = BlockChain::new();
+        let genesis_hash = chain.highest_block().hash();
+
+        let first_block = Block::mine_new(
+            genesis_hash,
+            GLOBAL_DIFFICULTY,
+            Transactions(Transaction::signed_dummy_txs(1)),
+            DUMMY_MINER,
+        );
+        let second_block = Block::mine_new(
+            genesis_hash,
+            GLOBAL_DIFFICULTY,
+            Transactions(Transaction::signed_dummy_txs(2)),
+            DUMMY_MINER,
+        );
+        let third_block = Block::mine_at(
+            second_block.hash(),
+            GLOBAL_DIFFICULTY,
+            Transactions(Transaction::signed_dummy_txs(3).split_off(2)),
+            DUMMY_MINER,
+            second_block.header.timestamp() + 1,
+        );
+        for block in [&first_block, &second_block, &third_block] {
+            assert_eq!(
+                chain.add_block(block),
+                Ok(AddResult::New(vec![block.hash()]))
+            );
+        }
+
+        let (first, second, third) = (first_block.hash(), second_block.hash(), third_block.hash());
+        assert_eq!(chain.common_ancestor(&first, &third), Some(genesis_hash));
+        assert_eq!(chain.common_ancestor(&third, &first), Some(genesis_hash));
+        assert_eq!(chain.common_ancestor(&third, &second), Some(second));
+        assert_eq!(chain.common_ancestor(&first, &first), Some(first));
+        assert_eq!(chain.common_ancestor(&first, &[9; HASH_LENGTH]), None);
+
+        let mut genesis_children = chain.children_of(&genesis_hash);
+        genesis_children.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(genesis_children, expected);
+        assert_eq!(chain.children_of(&second), vec![third]);
+        assert!(chain.children_of(&third).is_empty());
+    }
+
+    #[test]
+    fn iter_main_chain() {
+        let mut chain = canonical_chain(3);
+        let main: Vec<Block> = chain.iter_main_chain().cloned().collect();
+        assert_eq!(main.len(), 3);
+        assert_eq!(main[0], Block::genesis(&ChainParams::TESTING));
+        assert_eq!(&main[2], chain.highest_block());
+
+        // a shorter fork off the first block is not part of the main chain
+        let txs = Transactions(vec![]);
+        let time = main[1].header.timestamp() + 10;
+        let side_block = Block::mine_at(main[1].hash(), 0, txs, DUMMY_MINER, time);
+        chain.add_block(&side_block).unwrap();
+        assert_eq!(chain.main_chain_length(), 3);
+        assert!(chain.iter_main_chain().eq(main.iter()));
+        for (height, block) in main.iter().enumerate() {
+            assert_eq!(chain.block_at_height(height), Some(block));
+        }
+        assert_eq!(chain.block_at_height(3), None);
+    }
+
+    #[test]
+    fn stats() {
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+        let stats = chain.stats();
+        assert_eq!(stats.height, 0);
+        assert_eq!(stats.total_work, 1 << 8);
+        assert_eq!(stats.avg_block_interval, None);
+        assert_eq!(stats.oldest_timestamp, GENESIS_TIME);
+        assert_eq!(stats.newest_timestamp, GENESIS_TIME);
+
+        // the interval to genesis doesn't count, its timestamp is arbitrary
+        let deltas = [100, 5, 10];
+        let mut time = GENESIS_TIME;
+        for (i, delta) in deltas.into_iter().enumerate() {
+            time += delta;
+            let txs = Transactions(vec![]);
+            let parent = chain.highest_block().hash();
+            let block = Block::mine_at(parent, 0, txs, DUMMY_MINER, time);
+            assert!(chain.add_block(&block).is_ok());
+            if i == 0 {
+                assert_eq!(chain.stats().avg_block_interval, None);
+            }
+        }
+        let stats = chain.stats();
+        assert_eq!(stats.height, 3);
+        // the genesis block is mined at 8 bits, the others at 0
+        assert_eq!(stats.total_work, (1 << 8) + 3);
+        assert_eq!(stats.avg_block_interval, Some(7.5));
+        assert_eq!(stats.oldest_timestamp, GENESIS_TIME);
+        assert_eq!(stats.newest_timestamp, GENESIS_TIME + 115);
+    }
+
+    #[test]
+    fn total_blocks_include_forks() {
+        let mut chain = canonical_chain(3);
+        assert_eq!(chain.total_blocks(), 3);
+        assert_eq!(chain.highest_block_hash(), chain.highest_block().hash());
+        let tip = chain.highest_block_hash();
+
+        // fork off genesis with a single side-chain block
+        let txs = Transactions(Transaction::signed_dummy_txs(5));
+        let genesis = Block::genesis(&ChainParams::TESTING).hash();
+        let fork = Block::mine_at(genesis, 0, txs.clone(), DUMMY_MINER, GENESIS_TIME + 1);
+        assert!(chain.add_block(&fork).is_ok());
+        assert_eq!(chain.total_blocks(), 4);
+        assert_eq!(chain.main_chain_length(), 3);
+        assert_eq!(chain.highest_block_hash(), tip);
+
+        // orphans aren't part of the chain
+        let orphan = Block::mine_at([9; HASH_LENGTH], 0, txs, DUMMY_MINER, GENESIS_TIME + 2);
+        assert_eq!(chain.add_block(&orphan), Ok(AddResult::Orphan));
+        assert_eq!(chain.orphan_count(), 1);
+        assert_eq!(chain.total_blocks(), 4);
+    }
+
+    #[test]
+    fn orphan_rate() {
+        let mut chain = canonical_chain(3);
+        assert_eq!(chain.orphan_rate(), 0.0);
+
+        // fork off genesis with a single side-chain block
+        let txs = Transactions(Transaction::signed_dummy_txs(5));
+        let side_block = Block::mine_new(
+            Block::genesis(&ChainParams::TESTING).hash(),
+            0,
+            txs,
+            DUMMY_MINER,
+        );
+        assert_eq!(
+            chain.add_block(&side_block),
+            Ok(AddResult::New(vec![side_block.hash()]))
+        );
+        assert_eq!(chain.main_chain_length(), 3);
+        assert_eq!(chain.orphan_rate(), 0.25);
+    }
+
+    /// Mines `count` blocks on the main chain, `interval` seconds apart, at the expected
+    /// difficulty.
+    fn mine_blocks(chain: &mut BlockChain, count: usize, interval: u64) {
+        for _ in 0..count {
+            let parent = chain.highest_block().clone();
+            let difficulty = chain.difficulty_for_height(chain.main_chain_length());
+            let time = parent.header.timestamp() + interval;
+            let txs = Transactions(vec![]);
+            let block = Block::mine_at(parent.hash(), difficulty, txs, DUMMY_MINER, time);
+            assert_eq!(
+                chain.add_block(&block),
+                Ok(AddResult::New(vec![block.hash()]))
+            );
+        }
+    }
+
+    #[test]
+    fn difficulty_retargeting() {
+        let params = ChainParams {
+            difficulty: 4,
+            target_block_interval: 10,
+            retarget_window: Some(4),
+            ..ChainParams::MAINNET
+        };
+
+        let mut fast = BlockChain::with_params(params.clone());
+        mine_blocks(&mut fast, 3, 1);
+        assert_eq!(fast.difficulty_for_height(3), 4);
+        assert_eq!(fast.difficulty_for_height(4), 5);
+        mine_blocks(&mut fast, 4, 1);
+        assert_eq!(fast.difficulty_for_height(7), 5);
+        assert_eq!(fast.difficulty_for_height(8), 6);
+        assert_eq!(fast.difficulty_for_height(1), 4);
+        assert_eq!(
+            fast.difficulty_for_height(0),
+            Block::genesis(&ChainParams::TESTING).header.difficulty()
+        );
+
+        let mut slow = BlockChain::with_params(params.clone());
+        mine_blocks(&mut slow, 3, 100);
+        assert_eq!(slow.difficulty_for_height(4), 3);
+
+        let mut on_target = BlockChain::with_params(params);
+        mine_blocks(&mut on_target, 3, 10);
+        assert_eq!(on_target.difficulty_for_height(4), 4);
+
+        // blocks ignoring the retarget are rejected
+        let tip = fast.highest_block().hash();
+        let time = fast.highest_block().header.timestamp() + 1;
+        let txs = Transactions(vec![]);
+        let stale = Block::mine_at(tip, 5, txs, DUMMY_MINER, time);
+        assert_eq!(fast.add_block(&stale), Err(BlockError::WrongDifficulty));
+    }
+
+    #[test]
+    fn heaviest_chain_wins() {
+        let params = ChainParams {
+            difficulty: 2,
+            target_block_interval: 10,
+            retarget_window: Some(2),
+            ..ChainParams::MAINNET
+        };
+        // 6 blocks on target at difficulty 2
+        let mut chain = BlockChain::with_params(params.clone());
+        mine_blocks(&mut chain, 6, 10);
+        let long_tip = chain.highest_block().clone();
+        let long_work = chain.stats().total_work;
+
+        // a fork of blocks coming fast, so its difficulty is raised from height 4 on
+        let mut parent = Block::genesis(&ChainParams::TESTING);
+        let mut fork = Vec::new();
+        for difficulty in [2, 2, 2, 3, 3] {
+            let time = parent.header.timestamp() + 1;
+            let txs = Transactions(vec![]);
+            parent = Block::mine_at(parent.hash(), difficulty, txs, DUMMY_MINER, time);
+            assert_eq!(
+                chain.add_block(&parent),
+                Ok(AddResult::New(vec![parent.hash()]))
+            );
+            fork.push(parent.clone());
+            // the fork's first four blocks have less work than the long chain, all five more
+            assert_eq!(chain.highest_block() == &parent, fork.len() == 5);
+        }
+        assert_eq!(long_work, (1 << 8) + 6 * 4);
+        assert_eq!(chain.stats().total_work, long_work + 4);
+        assert_eq!(chain.highest_block(), &fork[4]);
+        assert_eq!(chain.main_chain_length(), 6);
+        assert_eq!(chain.stale_branch(&long_tip.hash()).len(), 6);
+        assert!(chain.audit_balances().is_empty());
+
+        // the heavier but shorter fork stays the main chain after a restart
+        let path = std::env::temp_dir().join(format!("heavy-{}.bin", std::process::id()));
+        chain.save_to_path(&path).unwrap();
+        let loaded = BlockChain::load_from_path(&path, params).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.highest_block(), &fork[4]);
+    }
+
+    #[test]
+    fn timestamp_after_parent() {
+        let mut chain = canonical_chain(3);
+        let tip = chain.highest_block().clone();
+        let txs = Transactions(vec![]);
+
+        let past = Block::mine_at(tip.hash(), 0, txs.clone(), DUMMY_MINER, GENESIS_TIME);
+        assert_eq!(chain.add_block(&past), Err(BlockError::PastTimestamp));
+        let same = Block::mine_at(
+            tip.hash(),
+            0,
+            txs.clone(),
+            DUMMY_MINER,
+            tip.header.timestamp(),
+        );
+        assert_eq!(chain.add_block(&same), Err(BlockError::PastTimestamp));
+        let future = Block::mine_at(
+            tip.hash(),
+            0,
+            txs.clone(),
+            DUMMY_MINER,
+            now() + 24 * 60 * 60,
+        );
+        assert_eq!(chain.add_block(&future), Err(BlockError::FutureTimestamp));
+
+        let next = Block::mine_at(tip.hash(), 0, txs, DUMMY_MINER, tip.header.timestamp() + 1);
+        assert_eq!(
+            chain.add_block(&next),
+            Ok(AddResult::New(vec![next.hash()]))
+        );
+        assert_eq!(chain.main_chain_length(), 4);
+    }
+
+    #[test]
+    fn future_timestamp() {
+        let mut chain = canonical_chain(2);
+        let tip = chain.highest_block().hash();
+        let drift = chain.params().max_future_drift;
+        let txs = Transactions(vec![]);
+
+        let future = Block::mine_at(tip, 0, txs.clone(), DUMMY_MINER, now() + drift + 60);
+        assert_eq!(chain.add_block(&future), Err(BlockError::FutureTimestamp));
+        assert_eq!(chain.dry_run_add(&future), Err(BlockError::FutureTimestamp));
+        assert_eq!(chain.future_block_rejections(), 1);
+        assert_eq!(chain.main_chain_length(), 2);
+
+        let skewed = Block::mine_at(tip, 0, txs, DUMMY_MINER, now() + drift - 60);
+        assert_eq!(
+            chain.add_block(&skewed),
+            Ok(AddResult::New(vec![skewed.hash()]))
+        );
+        assert_eq!(chain.future_block_rejections(), 1);
+    }
+
+    #[test]
+    fn balance_at() {
+        let chain = canonical_chain(5);
+        let rich = *GENESIS_TX.receiver();
+        // key 2 is funded in the second block and passes on what it receives from then on
+        let relay = address_of(&Transaction::dummy_key(2).verifying_key());
+        let balances = |address| {
+            (0..5)
+                .map(|h| chain.balance_at(address, h).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(balances(&rich), vec![100, 99, 98, 97, 96]);
+        assert_eq!(balances(&relay), vec![0, 0, 1, 1, 1]);
+
+        // the same as replaying a chain that ends at that height
+        for height in 0..5 {
+            let truncated = canonical_chain(height + 1);
+            for address in [rich, relay] {
+                assert_eq!(
+                    chain.balance_at(&address, height),
+                    truncated.balance_at(&address, usize::MAX)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn overspending_rejected() {
+        let mut chain = canonical_chain(2);
+        let tip = chain.highest_block().clone();
+        let rich = *GENESIS_TX.receiver();
+        let key = Transaction::dummy_key(0);
+        let spend = |amount, nonce| {
+            let mut tx = Transaction::new(rich, DUMMY_MINER, amount, GENESIS_TIME);
+            tx = tx.with_nonce(nonce);
+            tx.sign(&key);
+            tx
+        };
+        let mine = |parent: &Block, txs| {
+            let time = parent.header.timestamp() + 1;
+            Block::mine_at(parent.hash(), 0, Transactions(txs), DUMMY_MINER, time)
+        };
+
+        // key 0 has 99 "coin" left after relaying 1
+        let overspent = Err(BlockError::Overspent);
+        assert_eq!(chain.add_block(&mine(&tip, vec![spend(100, 2)])), overspent);
+        // each one alone is affordable, but not both
+        let both = vec![spend(60, 2), spend(40, 3)];
+        assert_eq!(chain.add_block(&mine(&tip, both)), overspent);
+        assert_eq!(chain.balance_of(&rich), 99);
+        assert!(chain.audit_balances().is_empty());
+
+        // on a fork off genesis, key 0 spends all 100 "coin", and then has none left there
+        let fork = mine(&Block::genesis(&ChainParams::TESTING), vec![spend(100, 1)]);
+        assert_eq!(
+            chain.add_block(&fork),
+            Ok(AddResult::New(vec![fork.hash()]))
+        );
+        assert_eq!(chain.add_block(&mine(&fork, vec![spend(1, 2)])), overspent);
+        assert_eq!(chain.highest_block(), &tip);
+
+        let all = mine(&tip, vec![spend(99, 2)]);
+        assert_eq!(chain.add_block(&all), Ok(AddResult::New(vec![all.hash()])));
+        assert_eq!(chain.balance_of(&rich), 0);
+    }
+
+    #[test]
+    fn immature_coinbase_rejected() {
+        let params = ChainParams {
+            coinbase_maturity: 3,
+            ..ChainParams::TESTING
+        };
+        let mut chain = BlockChain::with_params(params);
+        let key = Transaction::dummy_key(9);
+        let miner = address_of(&key.verifying_key());
+        let mut spend = Transaction::new(miner, DUMMY_MINER, 1, GENESIS_TIME).with_nonce(1);
+        spend.sign(&key);
+        let mine = |parent: &Block, txs, miner| {
+            let time = parent.header.timestamp() + 1;
+            Block::mine_at(parent.hash(), 0, Transactions(txs), miner, time)
+        };
+        let extend = |chain: &mut BlockChain, parent: &Block, txs, miner| {
+            let block = mine(parent, txs, miner);
+            assert_eq!(
+                chain.add_block(&block),
+                Ok(AddResult::New(vec![block.hash()]))
+            );
+            block
+        };
+
+        // the miner's coinbase at height 1 may be spent from height 4 on
+        let a1 = extend(
+            &mut chain,
+            &Block::genesis(&ChainParams::TESTING),
+            vec![],
+            miner,
+        );
+        let a2 = extend(&mut chain, &a1, vec![], DUMMY_MINER);
+        let immature = Err(BlockError::ImmatureCoinbase);
+        assert_eq!(
+            chain.add_block(&mine(&a2, vec![spend.clone()], DUMMY_MINER)),
+            immature
+        );
+        let a3 = extend(&mut chain, &a2, vec![], DUMMY_MINER);
+        assert_eq!(chain.spendable_balance_of(&miner), BLOCK_REWARD);
+        extend(&mut chain, &a3, vec![spend.clone()], DUMMY_MINER);
+
+        // a longer fork, on which the miner's coinbase is one block higher, takes over
+        let b1 = extend(
+            &mut chain,
+            &Block::genesis(&ChainParams::TESTING),
+            vec![],
+            DUMMY_MINER,
+        );
+        let b2 = extend(&mut chain, &b1, vec![], miner);
+        let b3 = extend(&mut chain, &b2, vec![], DUMMY_MINER);
+        let b4 = extend(&mut chain, &b3, vec![], DUMMY_MINER);
+        let b5 = extend(&mut chain, &b4, vec![], DUMMY_MINER);
+        assert_eq!(chain.highest_block(), &b5);
+        assert_eq!(chain.balance_of(&miner), BLOCK_REWARD);
+
+        // the spend at height 4 was mature on the stale branch, but not on the main chain
+        assert_eq!(
+            chain.add_block(&mine(&b3, vec![spend.clone()], DUMMY_MINER)),
+            immature
+        );
+        extend(&mut chain, &b4, vec![spend], DUMMY_MINER);
+        assert_eq!(chain.highest_block(), &b5);
+    }
+
+    #[test]
+    fn replayed_nonces_rejected() {
+        let mut chain = canonical_chain(2);
+        let tip = chain.highest_block().clone();
+        let mine = |txs| {
+            let time = tip.header.timestamp() + 1;
+            Block::mine_at(tip.hash(), 0, Transactions(txs), DUMMY_MINER, time)
+        };
+        // key 0's transactions of the first block, and its next ones
+        let committed = tip.transactions.0[1].clone();
+        let next = Transaction::signed_dummy_txs(3).split_off(1);
+
+        let wrong_nonce = Err(BlockError::WrongNonce);
+        assert_eq!(chain.add_block(&mine(vec![committed])), wrong_nonce);
+        assert_eq!(chain.add_block(&mine(next[1..].to_vec())), wrong_nonce);
+        let twice = vec![next[0].clone(), next[0].clone()];
+        assert_eq!(chain.add_block(&mine(twice)), wrong_nonce);
+        let reversed = vec![next[1].clone(), next[0].clone()];
+        assert_eq!(chain.add_block(&mine(reversed)), wrong_nonce);
+        assert_eq!(chain.nonce_of(GENESIS_TX.receiver()), 1);
+
+        let block = mine(next);
+        assert_eq!(
+            chain.add_block(&block),
+            Ok(AddResult::New(vec![block.hash()]))
+        );
+        assert_eq!(chain.nonce_of(GENESIS_TX.receiver()), 3);
+    }
+
+    #[test]
+    fn balance_of() {
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+        let rich = &GENESIS_TX.receiver().to_owned();
+        assert_eq!(chain.balance_of(rich), 100);
+        assert_eq!(chain.balance_of(&MINT_ADDRESS), 0);
+
+        let txs = Transaction::signed_dummy_txs(3);
+        let time = GENESIS_TIME + 1;
+        let block = Block::mine_at(
+            Block::genesis(&ChainParams::TESTING).hash(),
+            0,
+            Transactions(txs.clone()),
+            DUMMY_MINER,
+            time,
+        );
+        chain.add_block(&block).unwrap();
+        assert_eq!(chain.balance_of(rich), 100 - 1 - 2 - 3);
+        assert_eq!(chain.balance_of(txs[2].receiver()), 3);
+
+        // in one call, in order and with repetitions
+        let addresses = [*txs[2].receiver(), MINT_ADDRESS, *rich, *txs[2].receiver()];
+        let balances: Vec<u32> = addresses.iter().map(|a| chain.balance_of(a)).collect();
+        assert_eq!(chain.balances(&addresses), balances);
+        assert_eq!(chain.balances(&[]), vec![]);
+    }
+
+    #[test]
+    fn from_blocks() {
+        let chain = canonical_chain(4);
+        let blocks: Vec<Block> = chain.main_chain_from(1).into_iter().cloned().collect();
+        let rebuilt = BlockChain::from_blocks(ChainParams::TESTING, &blocks).unwrap();
+        assert!(rebuilt.iter_main_chain().eq(chain.iter_main_chain()));
+        assert_eq!(rebuilt.accounts(), chain.accounts());
+
+        // the parent of the first block is missing
+        let result = BlockChain::from_blocks(ChainParams::TESTING, &blocks[1..]);
+        assert_eq!(result.err(), Some(BlockError::UnknownParent));
+
+        let mut tampered = blocks.clone();
+        tampered[1].transactions.0.pop();
+        let result = BlockChain::from_blocks(ChainParams::TESTING, &tampered);
+        assert_eq!(result.err(), Some(BlockError::Invalid));
+    }
+
+    #[test]
+    fn stale_branch() {
+        let mut chain = canonical_chain(3);
+        let main = chain.main_chain_from(1);
+        let (first, tip) = (main[0].clone(), main[1].hash());
+        assert!(chain.stale_branch(&tip).is_empty());
+        assert!(chain.stale_branch(&[9; HASH_LENGTH]).is_empty());
+
+        let mut parent = first;
+        for _ in 0..2 {
+            let txs = Transactions(vec![]);
+            let time = parent.header.timestamp() + 10;
+            let block = Block::mine_at(parent.hash(), 0, txs, DUMMY_MINER, time);
+            chain.add_block(&block).unwrap();
+            parent = block;
+        }
+        assert_eq!(chain.highest_block(), &parent);
+        let stale: Vec<Hash> = chain.stale_branch(&tip).iter().map(|b| b.hash()).collect();
+        assert_eq!(stale, vec![tip]);
+    }
+
+    #[test]
+    fn checkpoints() {
+        let mut chain = canonical_chain(3);
+        let pinned = chain.block_at_height(2).unwrap().hash();
+        assert!(!chain.add_checkpoint(2, [9; HASH_LENGTH]));
+        assert!(chain.add_checkpoint(2, pinned));
+        assert!(!chain.add_checkpoint(2, pinned));
+
+        // a fork may branch off, but not pass the checkpoint
+        let parent = chain.block_at_height(1).unwrap().clone();
+        let txs = Transactions(vec![]);
+        let time = parent.header.timestamp() + 10;
+        let fork = Block::mine_at(parent.hash(), 0, txs.clone(), DUMMY_MINER, time);
+        assert_eq!(chain.add_block(&fork), Err(BlockError::Checkpoint));
+        let fork = Block::mine_at(parent.header.prev_block_hash, 0, txs, DUMMY_MINER, time);
+        assert!(chain.add_block(&fork).is_ok());
+        assert_eq!(chain.block_at_height(2).unwrap().hash(), pinned);
+
+        // checkpoints ahead of the chain are fine, too
+        assert!(chain.add_checkpoint(5, [9; HASH_LENGTH]));
+
+        // a side branch stored before its height gets pinned is pruned, so it can't grow
+        let mine = |parent: &Block, time| {
+            Block::mine_at(parent.hash(), 0, Transactions(vec![]), DUMMY_MINER, time)
+        };
+        let tip = chain.highest_block().clone();
+        let main = mine(&tip, tip.header.timestamp() + 1);
+        let main_child = mine(&main, main.header.timestamp() + 1);
+        let side = mine(&tip, tip.header.timestamp() + 2);
+        let side_child = mine(&side, side.header.timestamp() + 1);
+        for block in [&main, &main_child, &side, &side_child] {
+            assert!(chain.add_block(block).is_ok());
+        }
+        let total = chain.total_blocks();
+        assert!(chain.add_checkpoint(3, main.hash()));
+        assert_eq!(chain.total_blocks(), total - 2);
+        assert!(!chain.contains_block(&side_child.hash()));
+        assert_eq!(chain.add_block(&side), Err(BlockError::Checkpoint));
+        let grandchild = mine(&side_child, side_child.header.timestamp() + 1);
+        assert_eq!(chain.add_block(&grandchild), Ok(AddResult::Orphan));
+        assert_eq!(chain.highest_block(), &main_child);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut chain = canonical_chain(3);
+        let txs = Transactions(vec![]);
+        let time = GENESIS_TIME + 10;
+        chain
+            .add_block(&Block::mine_at(
+                Block::genesis(&ChainParams::TESTING).hash(),
+                0,
+                txs,
+                DUMMY_MINER,
+                time,
+            ))
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("chain-{}.bin", std::process::id()));
+
+        chain.save_to_path(&path).unwrap();
+        let loaded = BlockChain::load_from_path(&path, ChainParams::TESTING).unwrap();
+        assert!(loaded.iter_main_chain().eq(chain.iter_main_chain()));
+        assert_eq!(loaded.blocks.len(), chain.blocks.len());
+        assert_eq!(loaded.accounts(), chain.accounts());
+
+        // blocks mined at difficulty 0 are invalid on the main network
+        let err = BlockChain::load_from_path(&path, ChainParams::MAINNET).err();
+        assert_eq!(err.map(|e| e.kind()), Some(ErrorKind::InvalidData));
+
+        std::fs::write(&path, b"").unwrap();
+        let err = BlockChain::load_from_path(&path, ChainParams::TESTING).err();
+        assert_eq!(err.map(|e| e.kind()), Some(ErrorKind::InvalidData));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pruning() {
+        let blocks: Vec<Block> = (canonical_chain(11).main_chain_from(1).into_iter())
+            .cloned()
+            .collect();
+        let mut chain = BlockChain::from_blocks(ChainParams::TESTING, &blocks[..7]).unwrap();
+        let txs = Transactions(vec![]);
+        let side = Block::mine_at(blocks[1].hash(), 0, txs, DUMMY_MINER, GENESIS_TIME + 50);
+        chain.add_block(&side).unwrap();
+        let unpruned = BlockChain::from_blocks(ChainParams::TESTING, &blocks[..8]).unwrap();
+
+        // heights 0 to 4 keep only their header and coinbase, side branches below are dropped
+        chain.set_keep_depth(Some(3));
+        assert_eq!(chain.pruned_height(), 5);
+        let old = &blocks[2];
+        assert_eq!(chain.block(&old.hash()), Err(Pruned(5)));
+        assert!(chain.contains_block(&old.hash()));
+        assert_eq!(chain.block(&blocks[4].hash()), Ok(Some(&blocks[4])));
+        assert!(!chain.contains_block(&side.hash()));
+        assert_eq!(chain.iter_main_chain().count(), 3);
+        let old_tx = (old.transactions.0.iter()).find(|tx| !tx.is_coinbase());
+        let old_tx = old_tx.unwrap().clone();
+        assert_eq!(chain.find_transaction(&old_tx.hash()), None);
+        let rich = *GENESIS_TX.receiver();
+        assert_eq!(chain.balance_at(&rich, 2), Err(Pruned(5)));
+        assert_eq!(chain.balance_at(&rich, 4), unpruned.balance_at(&rich, 4));
+        assert!(chain.audit_balances().is_empty());
+
+        // new blocks are still validated, and push the pruned height up
+        let time = GENESIS_TIME + 50;
+        let replay = Transactions(vec![old_tx]);
+        let replay = Block::mine_at(blocks[6].hash(), 0, replay, DUMMY_MINER, time);
+        assert_eq!(chain.add_block(&replay), Err(BlockError::WrongNonce));
+        assert!(matches!(chain.add_block(&blocks[7]), Ok(AddResult::New(_))));
+        assert_eq!(chain.pruned_height(), 6);
+        assert_eq!(chain.accounts(), unpruned.accounts());
+
+        // forks may branch off the highest pruned block, but not below
+        let txs = Transactions(vec![]);
+        let deep_fork = Block::mine_at(blocks[3].hash(), 0, txs, DUMMY_MINER, time);
+        assert_eq!(chain.add_block(&deep_fork), Err(BlockError::PrunedFork));
+        let txs = Transactions(vec![]);
+        let fork = Block::mine_at(blocks[4].hash(), 0, txs, DUMMY_MINER, time);
+        assert!(matches!(chain.add_block(&fork), Ok(AddResult::New(_))));
+
+        // the pruned state survives a restart, in less space
+        let path = std::env::temp_dir().join(format!("pruned-{}.bin", std::process::id()));
+        unpruned.save_to_path(&path).unwrap();
+        let unpruned_size = std::fs::metadata(&path).unwrap().len();
+        chain.save_to_path(&path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < unpruned_size);
+        let mut loaded = BlockChain::load_from_path(&path, ChainParams::TESTING).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.pruned_height(), 6);
+        assert_eq!(loaded.highest_block(), chain.highest_block());
+        assert_eq!(loaded.blocks.len(), chain.blocks.len());
+        assert_eq!(loaded.block(&old.hash()), Err(Pruned(6)));
+        assert_eq!(loaded.accounts(), chain.accounts());
+        assert_eq!(loaded.total_transactions(), chain.total_transactions());
+        let spender = address_of(&Transaction::dummy_key(0).verifying_key());
+        assert_eq!(loaded.nonce_of(&spender), chain.nonce_of(&spender));
+
+        // ... and keeps pruning
+        assert!(matches!(
+            loaded.add_block(&blocks[8]),
+            Ok(AddResult::New(_))
+        ));
+        assert_eq!(loaded.pruned_height(), 7);
+        assert!(!loaded.contains_block(&fork.hash()));
+
+        // a reorganisation above the pruned blocks takes back the former main chain
+        let mut branch = blocks[..8].to_vec();
+        for i in 0..3 {
+            let txs = Transactions(vec![]);
+            let time = GENESIS_TIME + 60 + i;
+            let block = Block::mine_at(branch[7 + i as usize].hash(), 0, txs, DUMMY_MINER, time);
+            loaded.add_block(&block).unwrap();
+            branch.push(block);
+        }
+        assert_eq!(Some(loaded.highest_block()), branch.last());
+        let stale_tx = blocks[8].transactions.0.last().unwrap().hash();
+        assert_eq!(loaded.find_transaction(&stale_tx), None);
+        assert!(loaded.audit_balances().is_empty());
+        let replayed = BlockChain::from_blocks(ChainParams::TESTING, &branch).unwrap();
+        assert_eq!(loaded.accounts(), replayed.accounts());
+        assert_eq!(loaded.nonce_of(&spender), replayed.nonce_of(&spender));
+    }
+
+    #[test]
+    fn migrate_saved() {
+        let params = ChainParams {
+            difficulty: 8,
+            ..ChainParams::TESTING
+        };
+        let mut chain = BlockChain::with_params(params.clone());
+        mine_blocks(&mut chain, 3, 10);
+        let path = std::env::temp_dir().join(format!("migrate-{}.bin", std::process::id()));
+        chain.save_to_path(&path).unwrap();
+
+        // the chain as saved while the difficulty counted bytes isn't valid anymore
+        BlockChain::migrate_saved(&path, difficulty_to_bytes).unwrap();
+        let old = BlockChain::read_blocks(&path).unwrap();
+        assert!(old.iter().all(|block| block.header.difficulty() == 1));
+        let err = BlockChain::load_from_path(&path, params.clone()).err();
+        assert_eq!(err.map(|e| e.kind()), Some(ErrorKind::InvalidData));
+
+        // ... until migrated, which mines its blocks again exactly as before
+        BlockChain::migrate_saved(&path, difficulty_from_bytes).unwrap();
+        let loaded = BlockChain::load_from_path(&path, params).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.iter_main_chain().eq(chain.iter_main_chain()));
+        assert_eq!(difficulty_to_bytes(9), 2);
+    }
+
+    #[test]
+    fn save_and_load_keeps_tip_over_fork() {
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+        let genesis = Block::genesis(&ChainParams::TESTING).hash();
+        let a = Block::mine_at(
+            genesis,
+            0,
+            Transactions(vec![]),
+            DUMMY_MINER,
+            GENESIS_TIME + 1,
+        );
+        let b = Block::mine_at(
+            genesis,
+            0,
+            Transactions(vec![]),
+            DUMMY_MINER,
+            GENESIS_TIME + 2,
+        );
+        chain.add_block(&a).unwrap();
+        chain.add_block(&b).unwrap();
+        assert_eq!(chain.highest_block_hash(), a.hash());
+        let path = std::env::temp_dir().join(format!("fork-{}.bin", std::process::id()));
+
+        chain.save_to_path(&path).unwrap();
+        let loaded = BlockChain::load_from_path(&path, ChainParams::TESTING).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.highest_block_hash(), a.hash());
+        assert_eq!(loaded.total_blocks(), chain.total_blocks());
+    }
+
+    #[test]
+    fn random_committed_tx() {
+        let chain = canonical_chain(3);
+        let total = chain.total_transactions();
+        let picked: HashSet<Hash> = (0..total)
+            .map(|i| {
+                let (hash, tx) = chain.random_committed_tx(|n| i + n * 2).unwrap();
+                assert_eq!(tx.hash(), hash);
+                assert!(chain.contains_transaction(&hash));
+                hash
+            })
+            .collect();
+        // each index picks another one, from genesis to the highest block
+        assert_eq!(picked.len(), total);
+        let first = chain.random_committed_tx(|_| 0).unwrap().0;
+        assert_eq!(first, GENESIS_TX.hash());
+        let last = chain.random_committed_tx(|n| n - 1).unwrap().1;
+        assert_eq!(chain.highest_block().transactions.0.last(), Some(last));
+    }
+
+    #[test]
+    fn main_chain_work_adds_up() {
+        let mut chain = canonical_chain(4);
+        assert!(chain.main_chain_work_adds_up());
+
+        // a corrupted work value anywhere on the main chain is detected
+        let hash = chain.block_at_height(2).unwrap().hash();
+        chain.blocks.get_mut(&hash).unwrap().work -= 1;
+        assert!(!chain.main_chain_work_adds_up());
+        chain.blocks.get_mut(&hash).unwrap().work += 1;
+        let genesis = Block::genesis(&ChainParams::TESTING).hash();
+        chain.blocks.get_mut(&genesis).unwrap().work = 0;
+        assert!(!chain.main_chain_work_adds_up());
+    }
+
+    #[test]
+    fn find_transaction() {
+        let mut chain = canonical_chain(3);
+        let first = chain.block_at_height(1).unwrap().clone();
+        let committed = first.transactions.0[0].clone();
+        let pending = Transaction::signed_dummy_txs(1).remove(0);
+        let genesis_tx = GENESIS_TX.hash();
+
+        assert_eq!(
+            chain.find_transaction(&committed.hash()),
+            Some((&committed, 1))
+        );
+        assert_eq!(chain.find_transaction(&genesis_tx), Some((&GENESIS_TX, 0)));
+        assert_eq!(chain.confirmations(&committed.hash()), Some(2));
+        assert!(chain.contains_transaction(&committed.hash()));
+        assert_eq!(chain.find_transaction(&pending.hash()), None);
+        assert!(!chain.contains_transaction(&pending.hash()));
+        assert_eq!(chain.find_transaction(&[9; HASH_LENGTH]), None);
+
+        // a longer fork off genesis without the committed transaction takes over
+        let mut parent = Block::genesis(&ChainParams::TESTING);
+        for i in 0..3 {
+            let txs = if i == 0 {
+                vec![pending.clone()]
+            } else {
+                vec![]
+            };
+            let time = parent.header.timestamp() + 10;
+            let block = Block::mine_at(parent.hash(), 0, Transactions(txs), DUMMY_MINER, time);
+            chain.add_block(&block).unwrap();
+            parent = block;
+        }
+        assert_eq!(chain.find_transaction(&committed.hash()), None);
+        assert_eq!(chain.find_transaction(&pending.hash()), Some((&pending, 1)));
+        assert!(chain.contains_transaction(&genesis_tx));
+    }
+
+    #[test]
+    fn accounts() {
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+        let rich = *GENESIS_TX.receiver();
+        assert_eq!(chain.accounts(), vec![(rich, 100)]);
+
+        let txs = Transaction::signed_dummy_txs(3);
+        let time = GENESIS_TIME + 1;
+        let block = Block::mine_at(
+            Block::genesis(&ChainParams::TESTING).hash(),
+            0,
+            Transactions(txs.clone()),
+            DUMMY_MINER,
+            time,
+        );
+        chain.add_block(&block).unwrap();
+        let expected = vec![
+            (DUMMY_MINER, 100),
+            (rich, 94),
+            (*txs[2].receiver(), 3),
+            (*txs[1].receiver(), 2),
+            (*txs[0].receiver(), 1),
+        ];
+        assert_eq!(chain.accounts(), expected);
+    }
+
+    #[test]
+    fn circulating_supply_is_minted() {
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+        let key = Transaction::dummy_key(3);
+        let mut fee_paying = Transaction::new(
+            address_of(&key.verifying_key()),
+            DUMMY_MINER,
+            1,
+            GENESIS_TIME,
+        )
+        .with_fee(2)
+        .with_nonce(1);
+        fee_paying.sign(&key);
+        for txs in [Transaction::signed_dummy_txs(3), vec![fee_paying]] {
+            let tip = chain.highest_block();
+            let time = tip.header.timestamp() + 1;
+            let block = Block::mine_at(tip.hash(), 0, Transactions(txs), DUMMY_MINER, time);
+            chain.add_block(&block).unwrap();
+        }
+
+        // coinbases mint the reward and pay out fees, which other transactions already paid
+        let minted: i64 = (chain.iter_main_chain())
+            .flat_map(|block| &block.transactions.0)
+            .map(|tx| match tx.is_coinbase() {
+                true => tx.amount() as i64,
+                false => -(tx.fee() as i64),
+            })
+            .sum();
+        assert_eq!(minted, GENESIS_TX.amount() as i64 + 2 * BLOCK_REWARD as i64);
+        assert_eq!(chain.circulating_supply(), minted as u64);
+    }
+
+    #[test]
+    fn nonce_of() {
+        let mut chain = canonical_chain(3);
+        // key 0 spends in both blocks, key 1 only in the second one
+        let key_1 = address_of(&Transaction::dummy_key(1).verifying_key());
+        let key_2 = address_of(&Transaction::dummy_key(2).verifying_key());
+        assert_eq!(chain.nonce_of(GENESIS_TX.receiver()), 2);
+        assert_eq!(chain.nonce_of(&key_1), 1);
+        assert_eq!(chain.nonce_of(&key_2), 0);
+        assert_eq!(chain.nonce_of(&MINT_ADDRESS), 0);
+
+        // a fork becoming the main chain only counts its own transactions
+        let mut parent = Block::genesis(&ChainParams::TESTING);
+        for txs in Transaction::signed_dummy_txs(3).chunks(1) {
+            let time = parent.header.timestamp() + 10;
+            let txs = Transactions(txs.to_vec());
+            let block = Block::mine_at(parent.hash(), 0, txs, DUMMY_MINER, time);
+            chain.add_block(&block).unwrap();
+            parent = block;
+        }
+        assert_eq!(chain.highest_block(), &parent);
+        assert_eq!(chain.nonce_of(&key_1), 0);
+        assert_eq!(chain.nonce_of(GENESIS_TX.receiver()), 3);
+    }
+
+    #[test]
+    fn audit_balances() {
+        let mut chain = canonical_chain(4);
+        assert!(chain.audit_balances().is_empty());
+
+        // switch to a fork off the first block, which recomputes the balances
+        let mut parent = chain.block_at_height(1).unwrap().clone();
+        // key 0's transactions after its first one, 1, 2 and 3 per block
+        let mut txs = Transaction::signed_dummy_txs(7).split_off(1);
+        for i in 1..=3 {
+            let rest = txs.split_off(i);
+            let time = parent.header.timestamp() + 10;
+            let block = Block::mine_at(parent.hash(), 0, Transactions(txs), DUMMY_MINER, time);
+            chain.add_block(&block).unwrap();
+            (parent, txs) = (block, rest);
+        }
+        assert_eq!(chain.highest_block(), &parent);
+        assert_eq!(
+            chain.balance_of(GENESIS_TX.receiver()),
+            100 - 1 - (2..=7).sum::<u32>()
+        );
+        assert!(chain.audit_balances().is_empty());
+
+        // a deliberate bookkeeping error is detected
+        let wrong = [42; HASH_LENGTH];
+        chain.balances.insert(wrong, 7);
+        *chain.balances.get_mut(GENESIS_TX.receiver()).unwrap() += 1;
+        let mut expected = vec![wrong, *GENESIS_TX.receiver()];
+        expected.sort_unstable();
+        assert_eq!(chain.audit_balances(), expected);
+    }
+
+    #[test]
+    fn difficulty_history() {
+        let chain = canonical_chain(4);
+        let history = chain.difficulty_history();
+        assert_eq!(history.len(), chain.main_chain_length());
+        // genesis is mined at difficulty 8, all test blocks at 0
+        assert_eq!(history, vec![(0, 8), (1, 0), (2, 0), (3, 0)]);
+        assert_eq!(history[3].1, chain.highest_block().header.difficulty());
+    }
+
+    #[test]
+    fn add_duplicate_block() {
+        let mut chain = BlockChain::new();
+        assert_eq!(
+            chain.add_block(&Block::genesis(&ChainParams::TESTING)),
+            Ok(AddResult::Duplicate)
+        );
+        assert_eq!(chain.main_chain_length(), 1);
+        assert_eq!(
+            chain.highest_block(),
+            &Block::genesis(&ChainParams::TESTING)
+        );
+
+        let txs = Transactions(vec![]);
+        let block = Block::mine_new(
+            Block::genesis(&ChainParams::TESTING).hash(),
+            GLOBAL_DIFFICULTY,
+            txs,
+            DUMMY_MINER,
+        );
+        assert_eq!(
+            chain.add_block(&block),
+            Ok(AddResult::New(vec![block.hash()]))
+        );
+        assert_eq!(chain.add_block(&block), Ok(AddResult::Duplicate));
+        assert_eq!(
+            chain.add_block(&Block::genesis(&ChainParams::TESTING)),
+            Ok(AddResult::Duplicate)
+        );
+        assert_eq!(chain.main_chain_length(), 2);
+        assert_eq!(chain.highest_block(), &block);
+    }
+
+    #[test]
+    fn main_chain_from() {
+        let chain = canonical_chain(4);
+        let blocks: Vec<Block> = chain.iter_main_chain().cloned().collect();
+        assert_eq!(chain.main_chain_from(0), blocks.iter().collect::<Vec<_>>());
+        assert_eq!(
+            chain.main_chain_from(2),
+            blocks[2..].iter().collect::<Vec<_>>()
+        );
+        assert_eq!(chain.main_chain_from(3), vec![chain.highest_block()]);
+        assert!(chain.main_chain_from(4).is_empty());
+    }
+
+    #[test]
+    fn reimport_skips_validation() {
+        let mut chain = canonical_chain(5);
+        let blocks: Vec<Block> = chain.blocks.values().map(|e| e.block.clone()).collect();
+
+        POW_CHECKS.with(|checks| checks.set(0));
+        for block in &blocks {
+            assert_eq!(chain.add_block(block), Ok(AddResult::Duplicate));
+        }
+        assert_eq!(POW_CHECKS.with(|checks| checks.get()), 0);
+
+        // unlike a new block
+        let tip = chain.highest_block();
+        let time = tip.header.timestamp() + 1;
+        let block = Block::mine_at(tip.hash(), 0, Transactions(vec![]), DUMMY_MINER, time);
+        assert!(chain.add_block(&block).is_ok());
+        assert_eq!(POW_CHECKS.with(|checks| checks.get()), 1);
+    }
+
+    #[test]
+    fn only_accepted_blocks_are_stored() {
+        let mut chain = canonical_chain(2);
+        let tip = chain.highest_block().clone();
+        let txs = Transactions(Transaction::signed_dummy_txs(2).split_off(1));
+        let time = tip.header.timestamp() + 1;
+        let block = Block::mine_at(tip.hash(), 0, txs, DUMMY_MINER, time);
+        let mut tampered = block.clone();
+        tampered.transactions.0.pop();
+
+        assert_eq!(chain.add_block(&tampered), Err(BlockError::Invalid));
+        assert_eq!(chain.add_block(&tip), Ok(AddResult::Duplicate));
+        assert_eq!(chain.total_blocks(), 2);
+        assert_eq!(chain.block(&tampered.hash()), Ok(None));
+        assert_eq!(
+            chain.add_block(&block),
+            Ok(AddResult::New(vec![block.hash()]))
+        );
+        assert_eq!(chain.total_blocks(), 3);
+        assert_eq!(chain.block(&block.hash()), Ok(Some(&block)));
+    }
+
+    #[test]
+    fn forged_signature_rejected() {
+        let mut chain = canonical_chain(2);
+        let tip = chain.highest_block().clone();
+        let time = tip.header.timestamp() + 1;
+        let mut txs = Transaction::signed_dummy_txs(2).split_off(1);
+        let block = Block::mine_at(tip.hash(), 0, Transactions(txs.clone()), DUMMY_MINER, time);
+
+        // the miner adds a fee for themselves after the spender signed, a valid block otherwise
+        txs[0] = txs[0].clone().with_fee(10);
+        let forged = Block::mine_at(tip.hash(), 0, Transactions(txs), DUMMY_MINER, time);
+        assert_eq!(chain.add_block(&forged), Err(BlockError::Invalid));
+        assert_eq!(
+            chain.add_block_trusting_pow(&forged),
+            Err(BlockError::Invalid)
+        );
+        assert_eq!(chain.balance_of(&DUMMY_MINER), BLOCK_REWARD);
+        assert_eq!(
+            chain.add_block(&block),
+            Ok(AddResult::New(vec![block.hash()]))
+        );
+    }
+
+    #[test]
+    fn add_rejected_block() {
+        let mut chain = BlockChain::new();
+        let txs = Transactions(Transaction::signed_dummy_txs(3));
+        let unmined = Block::new(
+            Block::genesis(&ChainParams::TESTING).hash(),
+            GLOBAL_DIFFICULTY,
+            txs.clone(),
+            DUMMY_MINER,
+        );
+        assert_eq!(chain.add_block(&unmined), Err(BlockError::Invalid));
+
+        let orphan = Block::mine_new([9; HASH_LENGTH], GLOBAL_DIFFICULTY, txs, DUMMY_MINER);
+        assert_eq!(chain.add_block(&orphan), Ok(AddResult::Orphan));
+        assert_eq!(chain.main_chain_length(), 1);
+    }
+
+    #[test]
+    fn add_block_trusting_pow() {
+        let blocks: Vec<Block> = canonical_chain(5)
+            .iter_main_chain()
+            .skip(1)
+            .cloned()
+            .collect();
+        let mut verified = BlockChain::with_params(ChainParams::TESTING);
+        let mut trusted = BlockChain::with_params(ChainParams::TESTING);
+        for block in &blocks {
+            assert!(verified.add_block(block).is_ok());
+            assert!(trusted.add_block_trusting_pow(block).is_ok());
+        }
+        assert_eq!(trusted.highest_block(), verified.highest_block());
+
+        // structurally invalid blocks are still rejected
+        let tip = trusted.highest_block();
+        let txs = Transactions(Transaction::signed_dummy_txs(5).split_off(4));
+        let time = tip.header.timestamp() + 1;
+        let mut tampered = Block::mine_at(tip.hash(), 0, txs, DUMMY_MINER, time);
+        tampered.transactions.0.pop();
+        assert_eq!(
+            trusted.add_block_trusting_pow(&tampered),
+            Err(BlockError::Invalid)
+        );
+
+        // ... but ones without proof-of-work aren't
+        let mut chain = BlockChain::new();
+        let txs = Transactions(Transaction::signed_dummy_txs(3));
+        let unmined = Block::new(
+            Block::genesis(&ChainParams::TESTING).hash(),
+            GLOBAL_DIFFICULTY,
+            txs,
+            DUMMY_MINER,
+        );
+        assert_eq!(chain.add_block(&unmined), Err(BlockError::Invalid));
+        assert_eq!(
+            chain.add_block_trusting_pow(&unmined),
+            Ok(AddResult::New(vec![unmined.hash()]))
+        );
+    }
+
+    #[test]
+    fn orphans_connect_once_parent_arrives() {
+        let source = canonical_chain(5);
+        let blocks: Vec<Block> = source.iter_main_chain().cloned().collect();
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+
+        // children arrive before their parent (in reverse order even)
+        for block in blocks[2..].iter().rev() {
+            assert_eq!(chain.add_block(block), Ok(AddResult::Orphan));
+            assert_eq!(chain.add_block(block), Ok(AddResult::Orphan));
+        }
+        assert_eq!(chain.orphan_count(), 3);
+        assert_eq!(chain.main_chain_length(), 1);
+        let mut orphans: Vec<&Block> = blocks[2..].iter().collect();
+        orphans.sort_by_key(|block| block.hash());
+        assert_eq!(chain.orphan_blocks(), orphans);
+
+        let connected = blocks[1..].iter().map(Hashable::hash).collect();
+        assert_eq!(chain.add_block(&blocks[1]), Ok(AddResult::New(connected)));
+        assert_eq!(chain.orphan_count(), 0);
+        assert!(chain.orphan_blocks().is_empty());
+        assert_eq!(chain.main_chain_length(), 5);
+        assert_eq!(chain.highest_block(), source.highest_block());
+    }
+
+    #[test]
+    fn orphans_contradicting_checkpoints_are_dropped() {
+        let source = canonical_chain(3);
+        let blocks: Vec<Block> = source.iter_main_chain().cloned().collect();
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+        assert!(chain.add_checkpoint(2, [9; HASH_LENGTH]));
+
+        assert_eq!(chain.add_block(&blocks[2]), Ok(AddResult::Orphan));
+        let connected = vec![blocks[1].hash()];
+        assert_eq!(chain.add_block(&blocks[1]), Ok(AddResult::New(connected)));
+        assert_eq!(chain.orphan_count(), 0);
+        assert_eq!(chain.block(&blocks[2].hash()), Ok(None));
+    }
+
+    #[test]
+    fn invalid_orphans_are_dropped_on_connect() {
+        let source = canonical_chain(3);
+        let blocks: Vec<Block> = source.iter_main_chain().cloned().collect();
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+
+        // a child that is older than its parent only turns out invalid once connected
+        let txs = Transactions(vec![]);
+        let past = Block::mine_at(
+            blocks[1].hash(),
+            0,
+            txs,
+            DUMMY_MINER,
+            blocks[1].header.timestamp(),
+        );
+        assert_eq!(chain.add_block(&past), Ok(AddResult::Orphan));
+        assert_eq!(chain.add_block(&blocks[2]), Ok(AddResult::Orphan));
+
+        let connected = vec![blocks[1].hash(), blocks[2].hash()];
+        assert_eq!(chain.add_block(&blocks[1]), Ok(AddResult::New(connected)));
+        assert_eq!(chain.orphan_count(), 0);
+        assert_eq!(chain.main_chain_length(), 3);
+    }
+
+    #[test]
+    fn orphan_pool_is_bounded() {
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+        let orphan = |parent, i| {
+            let time = GENESIS_TIME + i as u64;
+            Block::mine_at(parent, 0, Transactions(vec![]), DUMMY_MINER, time)
+        };
+        for i in 0..MAX_ORPHANS {
+            assert_eq!(
+                chain.add_block(&orphan([9; HASH_LENGTH], i)),
+                Ok(AddResult::Orphan)
+            );
+        }
+
+        // the oldest orphan makes room for a new one
+        let newest = orphan([8; HASH_LENGTH], 0);
+        assert_eq!(
+            chain.dry_run_add(&newest).unwrap().result,
+            AddResult::Orphan
+        );
+        assert_eq!(chain.add_block(&newest), Ok(AddResult::Orphan));
+        assert_eq!(chain.orphan_count(), MAX_ORPHANS);
+        let orphans = chain.orphan_blocks();
+        assert!(!orphans.contains(&&orphan([9; HASH_LENGTH], 0)));
+        assert!(orphans.contains(&&orphan([9; HASH_LENGTH], 1)));
+        assert!(orphans.contains(&&newest));
+    }
+
+    #[test]
+    fn orphans_need_proof_of_work() {
+        let mut chain = BlockChain::new();
+        let txs = Transactions(vec![]);
+
+        // cheaper than the next main-chain block
+        let cheap = Block::mine_at([9; HASH_LENGTH], 0, txs.clone(), DUMMY_MINER, GENESIS_TIME);
+        assert_eq!(chain.dry_run_add(&cheap), Err(BlockError::UnknownParent));
+        assert_eq!(chain.add_block(&cheap), Err(BlockError::UnknownParent));
+        assert_eq!(
+            chain.add_block_trusting_pow(&cheap),
+            Err(BlockError::UnknownParent)
+        );
+
+        // not mined, which is checked even if trusting the proof-of-work
+        let unmined = Block::new(
+            [9; HASH_LENGTH],
+            GLOBAL_DIFFICULTY,
+            txs.clone(),
+            DUMMY_MINER,
+        );
+        assert_eq!(chain.add_block(&unmined), Err(BlockError::Invalid));
+        assert_eq!(
+            chain.add_block_trusting_pow(&unmined),
+            Err(BlockError::Invalid)
+        );
+        assert_eq!(chain.orphan_count(), 0);
+
+        let mined = Block::mine_new([9; HASH_LENGTH], GLOBAL_DIFFICULTY, txs, DUMMY_MINER);
+        assert_eq!(chain.add_block_trusting_pow(&mined), Ok(AddResult::Orphan));
+    }
+
+    #[test]
+    fn total_transactions() {
+        let mut chain = BlockChain::new();
+        assert_eq!(chain.total_transactions(), 1);
+        let genesis_hash = chain.highest_block().hash();
+
+        let txs = Transactions(Transaction::signed_dummy_txs(10));
+        let first_block = Block::mine_new(genesis_hash, GLOBAL_DIFFICULTY, txs, DUMMY_MINER);
+        chain.add_block(&first_block).unwrap();
+        // including the coinbase
+        assert_eq!(chain.total_transactions(), 12);
+
+        // a shorter fork doesn't count
+        let txs = Transactions(Transaction::signed_dummy_txs(2));
+        let second_block = Block::mine_new(genesis_hash, GLOBAL_DIFFICULTY, txs, DUMMY_MINER);
+        chain.add_block(&second_block).unwrap();
+        assert_eq!(chain.total_transactions(), 12);
+
+        // ... until it becomes the main chain
+        let txs = Transactions(Transaction::signed_dummy_txs(5).split_off(2));
+        let time = second_block.header.timestamp() + 1;
+        let third_block = Block::mine_at(
+            second_block.hash(),
+            GLOBAL_DIFFICULTY,
+            txs,
+            DUMMY_MINER,
+            time,
+        );
+        chain.add_block(&third_block).unwrap();
+        assert_eq!(chain.total_transactions(), 8);
+    }
+
+    #[test]
+    fn wrong_difficulty() {
+        let txs = Transactions(vec![]);
+        let easy_block = Block::mine_new(
+            Block::genesis(&ChainParams::TESTING).hash(),
+            0,
+            txs.clone(),
+            DUMMY_MINER,
+        );
+        let mut chain = BlockChain::new();
+        assert_eq!(
+            chain.add_block(&easy_block),
+            Err(BlockError::WrongDifficulty)
+        );
+        let mut test_chain = BlockChain::with_params(ChainParams::TESTING);
+        assert_eq!(
+            test_chain.add_block(&easy_block),
+            Ok(AddResult::New(vec![easy_block.hash()]))
+        );
+
+        let block = Block::mine_new(
+            Block::genesis(&ChainParams::TESTING).hash(),
+            GLOBAL_DIFFICULTY,
+            txs,
+            DUMMY_MINER,
+        );
+        assert_eq!(
+            test_chain.add_block(&block),
+            Err(BlockError::WrongDifficulty)
+        );
+    }
+
+    #[test]
+    fn canonical_test_chain() {
+        let chain = canonical_chain(50);
+        assert_eq!(chain.main_chain_length(), 50);
+        assert_eq!(chain.highest_block(), canonical_chain(50).highest_block());
+        assert!(chain
+            .blocks
+            .values()
+            .all(|entry| entry.block.is_valid(entry.height)));
+    }
+
+    #[test]
+    fn dry_run_add() {
+        let mut chain = canonical_chain(3);
+        let tip = chain.highest_block().clone();
+        // key 0's next transaction after the two of the canonical chain
+        let txs = Transactions(Transaction::signed_dummy_txs(3).split_off(2));
+        let block = Block::mine_new(tip.hash(), 0, txs, DUMMY_MINER);
+
+        let work = chain.stats().total_work;
+        let dry_run = chain.dry_run_add(&block).unwrap();
+        assert_eq!(dry_run.result, AddResult::New(vec![block.hash()]));
+        assert_eq!(dry_run.tip, block.hash());
+        assert_eq!(dry_run.total_work, work + 1);
+        assert_eq!(chain.highest_block(), &tip);
+        assert_eq!(chain.main_chain_length(), 3);
+        assert_eq!(
+            chain.add_block(&block),
+            Ok(AddResult::New(vec![block.hash()]))
+        );
+        assert_eq!(chain.highest_block_hash(), dry_run.tip);
+        assert_eq!(chain.stats().total_work, dry_run.total_work);
+        let unchanged = |result| DryRun {
+            result,
+            tip: block.hash(),
+            total_work: work + 1,
+        };
+        assert_eq!(
+            chain.dry_run_add(&block),
+            Ok(unchanged(AddResult::Duplicate))
+        );
+
+        // a fork at the tip's height is new but doesn't take over
+        let txs = Transactions(vec![]);
+        let fork = Block::mine_new(tip.hash(), 0, txs.clone(), DUMMY_MINER);
+        let expected = unchanged(AddResult::New(vec![fork.hash()]));
+        assert_eq!(chain.dry_run_add(&fork), Ok(expected));
+
+        let orphan = Block::mine_new([9; HASH_LENGTH], 0, txs.clone(), DUMMY_MINER);
+        assert_eq!(chain.dry_run_add(&orphan), Ok(unchanged(AddResult::Orphan)));
+        assert_eq!(chain.orphan_count(), 0);
+        let hard_block = Block::mine_new(tip.hash(), GLOBAL_DIFFICULTY, txs, DUMMY_MINER);
+        assert_eq!(
+            chain.dry_run_add(&hard_block),
+            Err(BlockError::WrongDifficulty)
+        );
+        assert_eq!(chain.main_chain_length(), 4);
+    }
 }
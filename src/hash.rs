@@ -1,4 +1,6 @@
 use bs58::encode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 
 /// The length of a [`Hash`] (in bytes).
@@ -26,8 +28,79 @@ impl B58Encode for Hash {
     }
 }
 
-pub fn has_leading_zeros(s: &Hash, leading: usize) -> bool {
-    s[..leading].iter().all(|b| *b == 0)
+/// The reasons for rejecting a hex string, see [HexEncode::from_hex].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HexError {
+    /// The string doesn't have two hex digits per byte (of a hash). Contains its length.
+    Length(usize),
+    /// The string contains a character that isn't a hex digit.
+    Char(char),
+    /// The bytes don't encode a valid value, e.g. a [crate::Transaction].
+    Encoding,
+}
+
+/// Hex encoding, as used by most block explorers and test vectors, alongside [B58Encode].
+pub trait HexEncode: Sized {
+    /// Encode this as lowercase hex string.
+    fn to_hex(&self) -> String;
+
+    /// Parse a hex string (of either case) as produced by [Self::to_hex].
+    fn from_hex(s: &str) -> Result<Self, HexError>;
+}
+
+impl HexEncode for Hash {
+    fn to_hex(&self) -> String {
+        bytes_to_hex(self)
+    }
+
+    fn from_hex(s: &str) -> Result<Self, HexError> {
+        let bytes = bytes_from_hex(s)?;
+        bytes.try_into().map_err(|_| HexError::Length(s.len()))
+    }
+}
+
+pub(crate) fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn bytes_from_hex(s: &str) -> Result<Vec<u8>, HexError> {
+    if let Some(c) = s.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(HexError::Char(c));
+    }
+    if !s.len().is_multiple_of(2) {
+        return Err(HexError::Length(s.len()));
+    }
+    // only ASCII hex digits are left, so this can't fail
+    let byte = |digits| u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).unwrap();
+    Ok(s.as_bytes().chunks(2).map(byte).collect())
+}
+
+/// The hex of a value's bytes as sent on the wire, e.g. to hand a raw transaction to other
+/// tools, see [HexEncode].
+pub(crate) fn wire_to_hex<T: Serialize>(value: &T) -> String {
+    bytes_to_hex(&bincode::serialize(value).expect("can always serialize a wire value"))
+}
+
+/// Parses a value from the hex of its bytes on the wire, see [wire_to_hex].
+pub(crate) fn wire_from_hex<T: DeserializeOwned>(s: &str) -> Result<T, HexError> {
+    bincode::deserialize(&bytes_from_hex(s)?).map_err(|_| HexError::Encoding)
+}
+
+/// Whether the hashes are equal, taking the same time wherever they differ. Use this instead
+/// of `==` where timing could leak secrets, e.g. when checking signatures or MACs.
+pub fn constant_time_eq(a: &Hash, b: &Hash) -> bool {
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    // keep the compiler from returning early once a difference is found
+    std::hint::black_box(diff) == 0
+}
+
+/// Whether the hash starts with at least `bits` zero bits, e.g. `9` means one zero byte
+/// followed by a byte below `0b1000_0000`.
+pub fn has_leading_zero_bits(s: &Hash, bits: usize) -> bool {
+    let (bytes, rest) = (bits / 8, bits % 8);
+    bits <= HASH_LENGTH * 8
+        && s[..bytes].iter().all(|b| *b == 0)
+        && (rest == 0 || s[bytes].leading_zeros() as usize >= rest)
 }
 
 /// Trait making [Sha256] hashing available on the implementor.
@@ -43,24 +116,101 @@ pub trait Hashable {
 
 #[cfg(test)]
 mod test {
-    use crate::hash::{has_leading_zeros, Hash, HASH_LENGTH};
+    use crate::hash::{
+        constant_time_eq, has_leading_zero_bits, Hash, Hashable, HexEncode, HexError, HASH_LENGTH,
+    };
+    use crate::tx::{Transaction, Transactions};
+    use crate::{Block, ChainParams};
+
+    #[test]
+    fn test_check_leading_zero_bits() {
+        assert!(has_leading_zero_bits(&[0; HASH_LENGTH], 0));
+        assert!(has_leading_zero_bits(&[0; HASH_LENGTH], 8));
+        assert!(has_leading_zero_bits(&[0; HASH_LENGTH], 256));
+        assert!(!has_leading_zero_bits(&[0; HASH_LENGTH], 257));
+
+        assert!(has_leading_zero_bits(&[1; HASH_LENGTH], 0));
+        assert!(has_leading_zero_bits(&[1; HASH_LENGTH], 7));
+        assert!(!has_leading_zero_bits(&[1; HASH_LENGTH], 8));
+        assert!(!has_leading_zero_bits(&[1; HASH_LENGTH], 256));
+
+        // bit boundaries around the first byte
+        let mut hash: Hash = [0xff; HASH_LENGTH];
+        hash[0] = 0;
+        hash[1] = 0b0100_0000;
+        assert!(has_leading_zero_bits(&hash, 7));
+        assert!(has_leading_zero_bits(&hash, 8));
+        assert!(has_leading_zero_bits(&hash, 9));
+        assert!(!has_leading_zero_bits(&hash, 10));
+        hash[0] = 0b0000_0001;
+        assert!(has_leading_zero_bits(&hash, 7));
+        assert!(!has_leading_zero_bits(&hash, 8));
+        assert!(!has_leading_zero_bits(&hash, 9));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        // the SHA-256 test vector of "abc"
+        let hex = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let hash = Transactions::hash_bytes(b"abc");
+        assert_eq!(hash.to_hex(), hex);
+        assert_eq!(Hash::from_hex(hex), Ok(hash));
+        assert_eq!(Hash::from_hex(&hex.to_uppercase()), Ok(hash));
+        assert_eq!([0; HASH_LENGTH].to_hex(), "0".repeat(64));
+    }
+
+    #[test]
+    fn hex_rejects_bad_input() {
+        let hex = [0xab; HASH_LENGTH].to_hex();
+        assert_eq!(Hash::from_hex(""), Err(HexError::Length(0)));
+        assert_eq!(Hash::from_hex(&hex[1..]), Err(HexError::Length(63)));
+        assert_eq!(
+            Hash::from_hex(&(hex.clone() + "ab")),
+            Err(HexError::Length(66))
+        );
+        assert_eq!(Hash::from_hex(&hex[..62]), Err(HexError::Length(62)));
+        let bad = hex.replacen('a', "g", 1);
+        assert_eq!(Hash::from_hex(&bad), Err(HexError::Char('g')));
+        let bad = hex.replacen('a', "é", 1);
+        assert_eq!(Hash::from_hex(&bad), Err(HexError::Char('é')));
+        assert_eq!(
+            Hash::from_hex(&format!("0x{}", &hex[2..])),
+            Err(HexError::Char('x'))
+        );
+    }
+
+    #[test]
+    fn raw_hex_round_trip() {
+        let tx = Transaction::signed_dummy_txs(3).pop().unwrap();
+        assert_eq!(Transaction::from_hex(&tx.to_hex()), Ok(tx.clone()));
+        let block = Block::genesis(&ChainParams::MAINNET);
+        assert_eq!(Block::from_hex(&block.to_hex()), Ok(block));
+
+        // valid hex of bytes that don't encode a transaction
+        assert_eq!(Transaction::from_hex("00ff"), Err(HexError::Encoding));
+        assert_eq!(
+            Transaction::from_hex(&tx.to_hex()[..40]),
+            Err(HexError::Encoding)
+        );
+        assert_eq!(Transaction::from_hex("0"), Err(HexError::Length(1)));
+    }
 
     #[test]
-    fn test_check_leading_zeros() {
-        assert!(has_leading_zeros(&[0; HASH_LENGTH], 0));
-        assert!(has_leading_zeros(&[0; HASH_LENGTH], 1));
-        assert!(has_leading_zeros(&[0; HASH_LENGTH], 0));
-        assert!(has_leading_zeros(&[0; HASH_LENGTH], 32));
-
-        assert!(has_leading_zeros(&[1; HASH_LENGTH], 0));
-        assert!(!has_leading_zeros(&[1; HASH_LENGTH], 1));
-        assert!(!has_leading_zeros(&[1; HASH_LENGTH], 32));
-
-        let from_zero: Hash = core::array::from_fn(|i| i as u8);
-        let from_one: Hash = core::array::from_fn(|i| i as u8 + 1);
-        assert!(has_leading_zeros(&from_zero, 1));
-        assert!(!has_leading_zeros(&from_zero, 2));
-        assert!(has_leading_zeros(&from_one, 0));
-        assert!(!has_leading_zeros(&from_one, 1));
+    fn constant_time_eq_agrees_with_eq() {
+        let hashes: Vec<Hash> = (0u32..20)
+            .map(|i| Transactions::hash_bytes(&i.to_le_bytes()))
+            .collect();
+        for a in &hashes {
+            for b in &hashes {
+                assert_eq!(constant_time_eq(a, b), a == b);
+            }
+            // differing in the first or last byte only
+            for i in [0, HASH_LENGTH - 1] {
+                let mut b = *a;
+                b[i] ^= 1;
+                assert!(!constant_time_eq(a, &b));
+                assert!(constant_time_eq(&b, &b));
+            }
+        }
     }
 }
@@ -1,15 +1,26 @@
-pub use crate::block::{Block, MAX_TXS};
-pub use crate::chain::BlockChain;
-pub use crate::hash::{Hash, Hashable};
-pub use crate::msg::Message;
-pub use crate::tx::{Transaction, Transactions};
+pub use crate::block::{
+    block_reward, difficulty_from_bytes, difficulty_to_bytes, AbsenceProof, Block, BlockHeader,
+    BLOCK_REWARD, MAX_TXS,
+};
+pub use crate::chain::{
+    AddResult, BlockChain, BlockError, ChainStats, DryRun, Pruned, MAX_ORPHANS,
+};
+pub use crate::hash::{constant_time_eq, B58Encode, Hash, Hashable, HexEncode, HexError};
+pub use crate::msg::{Message, MessageTooLong, SyncProgress, MAX_MESSAGE_LEN, SEND_TIMEOUT};
+pub use crate::params::{ChainParams, GenesisConfig};
+pub use crate::tx::{
+    address_of, merkle_commitment, verify_merkle_proof, Address, Batch, MerkleAccumulator,
+    Transaction, Transactions, GENESIS_TX, MAX_COINBASE_DATA, MINT_ADDRESS,
+};
+pub use crate::wallet::Wallet;
 
 mod block;
 mod chain;
 mod hash;
 mod msg;
+mod params;
 mod tx;
+mod wallet;
 
-// TODO: Implement a difficulty based on the block height and take it into account when verifying
-//   new blocks.
-pub const GLOBAL_DIFFICULTY: u32 = 2;
+/// The number of leading zero bits the first blocks' hashes must have, see [ChainParams].
+pub const GLOBAL_DIFFICULTY: u32 = 16;
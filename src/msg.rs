@@ -1,17 +1,58 @@
-use crate::{Block, Transactions};
+use crate::{Address, Block, Hash, Pruned, Transaction, Transactions};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::io;
 use std::io::ErrorKind;
 use std::net::SocketAddr;
-use tokio::io::AsyncWriteExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time;
+
+/// The maximum length of a message on the wire (without its length prefix). Longer frames
+/// are rejected before reading their payload.
+pub const MAX_MESSAGE_LEN: usize = 4 * 1024 * 1024;
+
+/// The error of a frame longer than [MAX_MESSAGE_LEN], wrapped in an [ErrorKind::InvalidData]
+/// error by [Message::read_from]. Contains the frame's length.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MessageTooLong(pub usize);
+
+impl MessageTooLong {
+    /// Whether the error is due to a frame longer than [MAX_MESSAGE_LEN].
+    pub fn is_cause_of(e: &io::Error) -> bool {
+        e.get_ref()
+            .is_some_and(|inner| inner.is::<MessageTooLong>())
+    }
+}
+
+impl Display for MessageTooLong {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "message of {} bytes exceeds {}", self.0, MAX_MESSAGE_LEN)
+    }
+}
+
+impl Error for MessageTooLong {}
+
+/// How long sending a message to a peer may take by default, i.e. connecting and writing
+/// each, see [Message::broadcast_with_timeout].
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// The blockchain protocol™️
 ///
 /// I.e. all the possible messages that a full node accepts and sends.
-// TODO: for now, a node needs to have been around from the first mining to participate
-//  because there is no way of synchronising past blocks (and the blockchain rejects
-//  orphans). Implement query/sync message to get old blocks and put them into the chain.
+// TODO: peers don't announce their height (and messages don't carry their sender). With a
+//  handshake (e.g. `Version { height }`), a node could track the height of each peer, use the
+//  best known height as its sync target and tell whether it is behind.
+// TODO: tools only get the few requests answered that are listed by [Message::is_request].
+//  Explorers would also want the funded accounts, see [crate::BlockChain::accounts].
+//  Adding a peer by request should go through the node's `would_accept_peer`, like `Connect`.
+//  Dashboards would get the chain's height, work and block rate from `GET /stats`, see
+//  [crate::BlockChain::stats].
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
     /// A new node joins the network and announces its address.
@@ -25,22 +66,286 @@ pub enum Message {
     Tx(Transactions),
 
     /// Announces the mining of a new block
+    // TODO: blocks are always relayed in full. If compact relay (header + tx hashes, filled in
+    //  from the mempool) is ever added, the coinbase must still be sent in full and checked
+    //  against the header, so that a relay cannot swap in its own coinbase and steal the reward.
     NewBlock(Block),
+
+    /// Asks a peer for its main-chain blocks from the given height on, e.g. to catch up
+    /// after joining late or receiving an orphan. Answered with [Message::Blocks] on the same
+    /// connection, see [Message::request].
+    GetBlocks { from_height: usize },
+
+    /// Main-chain blocks in ascending order of height, in response to [Message::GetBlocks].
+    /// A batch may end before the tip, to fit into [MAX_MESSAGE_LEN]: the requester asks again
+    /// for the blocks after it, until the answer is empty.
+    Blocks(Vec<Block>),
+
+    /// Pins the chain at the given height to the block with the given hash, see
+    /// [crate::BlockChain::add_checkpoint]. Nodes only adopt (and relay) checkpoints signed by
+    /// a key they trust, see [Message::checkpoint].
+    Checkpoint {
+        height: usize,
+        hash: Hash,
+        #[serde(with = "BigArray")]
+        signature: [u8; 64],
+    },
+
+    /// Asks a node to import a chain saved by [crate::BlockChain::save_to_path] at the given
+    /// path on the node's machine. Only answered for tools on that machine, with
+    /// [Message::Imported] on the same connection, see [Message::request].
+    Import(PathBuf),
+
+    /// The highest block and the main chain length after a [Message::Import], or why it failed.
+    Imported(Result<(Hash, usize), String>),
+
+    /// Asks a node for the block with the given hash, on the main chain or a fork.
+    /// Answered with [Message::BlockFound] on the same connection, see [Message::request].
+    GetBlock(Hash),
+
+    /// The block requested by [Message::GetBlock], if the node knows it and didn't prune it.
+    BlockFound(Result<Option<Block>, Pruned>),
+
+    /// Asks a node for the transaction with the given hash, committed or pending.
+    /// Answered with [Message::TransactionFound] on the same connection, see [Message::request].
+    GetTransaction(Hash),
+
+    /// The transaction requested by [Message::GetTransaction], if the node knows it, along with
+    /// the height of the main-chain block committing it (`None` while pending).
+    TransactionFound(Option<(Transaction, Option<usize>)>),
+
+    /// Asks a node for the highest block of its main chain, e.g. to mine on top of it.
+    /// Answered with [Message::Tip] on the same connection, see [Message::request].
+    GetTip,
+
+    /// The highest block requested by [Message::GetTip], along with the difficulty the next
+    /// block must be mined at, see [crate::BlockChain::difficulty_for_height].
+    Tip {
+        hash: Hash,
+        height: usize,
+        timestamp: u64,
+        difficulty: u32,
+    },
+
+    /// Asks a node for the difficulty of its main-chain blocks, see
+    /// [crate::BlockChain::difficulty_history].
+    /// Answered with [Message::DifficultyHistory] on the same connection, see [Message::request].
+    GetDifficultyHistory,
+
+    /// The height and difficulty of each main-chain block, requested by
+    /// [Message::GetDifficultyHistory].
+    DifficultyHistory(Vec<(usize, u32)>),
+
+    /// Asks a node for the current balances of the addresses, see [crate::BlockChain::balances].
+    /// Answered with [Message::Balances] on the same connection, see [Message::request].
+    GetBalances(Vec<Address>),
+
+    /// The balances requested by [Message::GetBalances], in the order of the addresses.
+    Balances(Vec<u32>),
+
+    /// Asks a node how far it got adding the last batch of synchronised blocks.
+    /// Answered with [Message::SyncProgress] on the same connection, see [Message::request].
+    GetSyncProgress,
+
+    /// The progress requested by [Message::GetSyncProgress].
+    SyncProgress(SyncProgress),
+
+    /// Asks a node for the blocks it buffers while waiting for their parent, see
+    /// [crate::BlockChain::orphan_blocks]. Answered with [Message::Orphans] on the same
+    /// connection, see [Message::request].
+    GetOrphans,
+
+    /// The orphan blocks of a node, in response to [Message::GetOrphans].
+    Orphans(Vec<Block>),
+
+    /// Asks a node for its pending transactions. Answered with [Message::Mempool] on the same
+    /// connection, see [Message::request].
+    GetMempool,
+
+    /// The pending transactions of a node along with its commitment to them, such that e.g. a
+    /// light client can check it got all of them, see [Transactions::verify_commitment].
+    Mempool { txs: Transactions, commitment: Hash },
+}
+
+/// How far a node got adding a batch of synchronised (or imported) blocks, see
+/// [Message::GetSyncProgress]. Counts the blocks processed, whether they were new or not.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct SyncProgress {
+    /// The number of blocks of the batch processed so far
+    pub current: usize,
+    /// The number of blocks in the batch
+    pub target: usize,
+    /// How many blocks were processed per second so far
+    pub blocks_per_sec: f64,
+    /// The estimated number of seconds until the whole batch is processed, at the same pace.
+    /// `None` until the first block is.
+    pub eta_secs: Option<f64>,
+}
+
+impl SyncProgress {
+    /// The progress after processing `current` of `target` blocks in the given time.
+    pub fn new(current: usize, target: usize, elapsed: Duration) -> Self {
+        let blocks_per_sec = match current {
+            0 => 0.0,
+            _ => current as f64 / elapsed.as_secs_f64(),
+        };
+        let eta_secs = (current > 0).then(|| (target - current) as f64 / blocks_per_sec);
+        SyncProgress {
+            current,
+            target,
+            blocks_per_sec,
+            eta_secs,
+        }
+    }
+}
+
+/// The bytes signed for a [Message::Checkpoint]: `height` (u64 LE) | `hash` (32).
+fn checkpoint_bytes(height: usize, hash: &Hash) -> Vec<u8> {
+    [&(height as u64).to_le_bytes()[..], hash].concat()
 }
 
 impl Message {
-    /// Send this message over TCP to all the given addresses.
+    /// Whether this message asks for an answer on the same connection, see [Self::request].
+    pub fn is_request(&self) -> bool {
+        matches!(
+            self,
+            Message::GetBlocks { .. }
+                | Message::Import(_)
+                | Message::GetBlock(_)
+                | Message::GetTransaction(_)
+                | Message::GetTip
+                | Message::GetDifficultyHistory
+                | Message::GetBalances(_)
+                | Message::GetSyncProgress
+                | Message::GetOrphans
+                | Message::GetMempool
+        )
+    }
+
+    /// Sends this request to the node and reads its answer from the same connection.
+    /// Connecting and writing may each take at most the [SEND_TIMEOUT], while the answer is
+    /// awaited as long as the node takes, e.g. to import a large chain.
+    pub async fn request(&self, node: &SocketAddr) -> io::Result<Message> {
+        let mut stream = time::timeout(SEND_TIMEOUT, TcpStream::connect(node))
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "connecting timed out"))??;
+        time::timeout(SEND_TIMEOUT, self.write_to(&mut stream))
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "writing timed out"))??;
+        Message::read_from(&mut stream).await
+    }
+
+    /// Creates a [Message::Checkpoint] signed with the given (trusted) key.
+    pub fn checkpoint(height: usize, hash: Hash, key: &SigningKey) -> Self {
+        let signature = key.sign(&checkpoint_bytes(height, &hash)).to_bytes();
+        Message::Checkpoint {
+            height,
+            hash,
+            signature,
+        }
+    }
+
+    /// Whether this is a [Message::Checkpoint] signed with the given key.
+    pub fn is_checkpoint_signed_by(&self, key: &VerifyingKey) -> bool {
+        let Message::Checkpoint {
+            height,
+            hash,
+            signature,
+        } = self
+        else {
+            return false;
+        };
+        key.verify_strict(
+            &checkpoint_bytes(*height, hash),
+            &Signature::from_bytes(signature),
+        )
+        .is_ok()
+    }
+
+    /// Send this message over TCP to all the given addresses. On the wire, a message is
+    /// prefixed by its length as 4 bytes in big-endian.
+    /// An unreachable address doesn't keep the message from the others: all of them are tried,
+    /// and those that failed are returned along with their error. Unresponsive peers fail after
+    /// the [SEND_TIMEOUT].
     pub async fn broadcast<'a, I: Iterator<Item = &'a SocketAddr>>(
         &self,
         addrs: I,
-    ) -> io::Result<()> {
+    ) -> Result<(), Vec<(SocketAddr, io::Error)>> {
+        self.broadcast_with_timeout(addrs, SEND_TIMEOUT).await
+    }
+
+    /// Like [Self::broadcast], but connecting to each peer and writing to it may each take
+    /// at most the given time, otherwise the peer fails with [ErrorKind::TimedOut]. As the
+    /// peers are tried in turn, unresponsive ones still delay the others.
+    pub async fn broadcast_with_timeout<'a, I: Iterator<Item = &'a SocketAddr>>(
+        &self,
+        addrs: I,
+        timeout: Duration,
+    ) -> Result<(), Vec<(SocketAddr, io::Error)>> {
         let bytes: Vec<u8> = self.into();
+        let len = u32::try_from(bytes.len()).expect("a message is shorter than 4 GiB");
+        let mut failures = Vec::new();
         for peer in addrs {
-            let mut stream = TcpStream::connect(peer).await?;
-            stream.write_all(&bytes).await?;
+            if let Err(e) = send_frame(peer, len, &bytes, timeout).await {
+                failures.push((*peer, e));
+            }
         }
-        Ok(())
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    /// Writes this message with its length prefix, like [Self::broadcast] does to each peer.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        let bytes: Vec<u8> = self.into();
+        let len = u32::try_from(bytes.len()).expect("a message is shorter than 4 GiB");
+        write_frame(writer, len, &bytes).await
     }
+
+    /// Reads one length-prefixed message as sent by [Self::broadcast]. Fails with
+    /// [ErrorKind::InvalidData] for frames longer than [MAX_MESSAGE_LEN] (see [MessageTooLong])
+    /// or invalid messages.
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let len = reader.read_u32().await? as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidData, MessageTooLong(len)));
+        }
+        let mut buf = vec![0; len];
+        reader.read_exact(&mut buf).await?;
+        Message::try_from(buf.as_slice())
+    }
+}
+
+/// Sends the bytes of a message with their length prefix over a new connection to the peer.
+/// Connecting and writing may each take at most the timeout.
+async fn send_frame(
+    peer: &SocketAddr,
+    len: u32,
+    bytes: &[u8],
+    timeout: Duration,
+) -> io::Result<()> {
+    let timed_out = |step: &str| {
+        let reason = format!("{} {} timed out after {:?}", step, peer, timeout);
+        io::Error::new(ErrorKind::TimedOut, reason)
+    };
+    let mut stream = time::timeout(timeout, TcpStream::connect(peer))
+        .await
+        .map_err(|_| timed_out("connecting to"))??;
+    time::timeout(timeout, write_frame(&mut stream, len, bytes))
+        .await
+        .map_err(|_| timed_out("writing to"))?
+}
+
+/// Writes the bytes of a message with their length prefix.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    len: u32,
+    bytes: &[u8],
+) -> io::Result<()> {
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(bytes).await
 }
 
 impl TryFrom<&[u8]> for Message {
@@ -0,0 +1,120 @@
+use crate::block::{Block, GENESIS_NONCE};
+use crate::tx::{address_of, Address, Transaction, GENESIS_ADDRESS, GENESIS_TIME};
+use crate::GLOBAL_DIFFICULTY;
+
+/// What the first block of a chain is made of, see [Block::genesis]. Its only transaction, the
+/// coinbase, mints the [crate::BLOCK_REWARD] for the mint address.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct GenesisConfig {
+    /// The address the genesis block mints its "coin" for.
+    pub mint_address: Address,
+    /// The timestamp of the genesis block (and its coinbase).
+    pub timestamp: u64,
+    /// The difficulty the genesis block is mined at.
+    pub difficulty: u32,
+    /// The nonce solving the genesis block, see [Block::compute_genesis].
+    pub nonce: u32,
+}
+
+/// The consensus parameters of a chain.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChainParams {
+    /// The first block of the chain. Nodes only accept blocks descending from it, so chains with
+    /// another genesis form separate networks.
+    pub genesis: GenesisConfig,
+    /// The difficulty of the first blocks (but genesis), until it is first retargeted.
+    pub difficulty: u32,
+    /// The expected number of seconds between two blocks.
+    pub target_block_interval: u64,
+    /// Every this many blocks, the difficulty is retargeted towards the target block interval.
+    /// `None` keeps the difficulty fixed.
+    pub retarget_window: Option<usize>,
+    /// How many seconds a block's timestamp may be ahead of the local clock, to allow for
+    /// clock skew between nodes. Blocks further in the future are rejected.
+    pub max_future_drift: u64,
+    /// The fee below which nodes neither accept transactions into their mempool nor relay
+    /// them. Blocks may still include such transactions.
+    pub min_relay_fee: u32,
+    /// How many blocks must be mined on top of a block (itself included) before the "coin" its
+    /// coinbase minted may be spent, so that a reorg dropping the block can't take back "coin"
+    /// that was already passed on. The genesis block's coinbase is spendable right away.
+    pub coinbase_maturity: usize,
+}
+
+impl ChainParams {
+    /// The parameters of the main network.
+    pub const MAINNET: ChainParams = ChainParams {
+        genesis: GenesisConfig {
+            mint_address: GENESIS_ADDRESS,
+            timestamp: GENESIS_TIME,
+            difficulty: 8,
+            nonce: GENESIS_NONCE,
+        },
+        difficulty: GLOBAL_DIFFICULTY,
+        target_block_interval: 10,
+        retarget_window: Some(10),
+        max_future_drift: 2 * 60 * 60,
+        min_relay_fee: 0,
+        coinbase_maturity: 100,
+    };
+
+    /// Parameters for tests: blocks at (fixed) difficulty 0 need no proof-of-work and are thus
+    /// mined instantly and deterministically.
+    pub const TESTING: ChainParams = ChainParams {
+        difficulty: 0,
+        retarget_window: None,
+        ..ChainParams::MAINNET
+    };
+
+    /// Like [Self::TESTING], but with a genesis derived from the seed, e.g. to spin up isolated
+    /// test networks. It mints for the address of [Transaction::dummy_key] `seed`, `seed`
+    /// seconds after the main network's genesis (so seed `0` gives the same genesis), and its
+    /// nonce is mined here.
+    pub fn seeded(seed: u32) -> ChainParams {
+        let key = Transaction::dummy_key(seed);
+        let unsolved = GenesisConfig {
+            mint_address: address_of(&key.verifying_key()),
+            timestamp: GENESIS_TIME + seed as u64,
+            nonce: 0,
+            ..ChainParams::MAINNET.genesis
+        };
+        let nonce = Block::compute_genesis(&unsolved).header.nonce;
+        ChainParams {
+            genesis: GenesisConfig { nonce, ..unsolved },
+            ..ChainParams::TESTING
+        }
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams::MAINNET
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::block::Block;
+    use crate::chain::BlockChain;
+    use crate::hash::Hashable;
+    use crate::params::ChainParams;
+    use crate::tx::{address_of, Transaction};
+
+    #[test]
+    fn seeded_genesis() {
+        let genesis = |seed| Block::genesis(&ChainParams::seeded(seed));
+        assert_eq!(genesis(1).hash(), genesis(1).hash());
+        assert_ne!(genesis(1).hash(), genesis(2).hash());
+        assert_ne!(genesis(1).hash(), genesis(0).hash());
+        assert_eq!(genesis(0), Block::genesis(&ChainParams::MAINNET));
+        assert!(genesis(1).is_valid(0));
+
+        // an isolated chain starts from its own genesis, minting for the seed's key
+        let params = ChainParams::seeded(1);
+        let chain = BlockChain::with_params(params.clone());
+        assert_eq!(chain.highest_block(), &genesis(1));
+        let minter = address_of(&Transaction::dummy_key(1).verifying_key());
+        assert_eq!(chain.balance_of(&minter), 100);
+        assert_eq!(chain.stats().oldest_timestamp, params.genesis.timestamp);
+    }
+}
@@ -1,80 +1,424 @@
-use crate::hash::{B58Encode, Hash, Hashable, HASH_LENGTH};
+use crate::block::block_reward;
+use crate::hash::{
+    constant_time_eq, wire_from_hex, wire_to_hex, B58Encode, Hash, Hashable, HexEncode, HexError,
+    HASH_LENGTH,
+};
+use crate::params::GenesisConfig;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-/// An address is just a hash.
-///
-/// For now, we use any hash but the idea is that this could be the hash of a public key
-/// from the account owner.
-type Address = Hash;
+/// An address is just a hash, namely the hash of the account owner's public key,
+/// see [address_of].
+pub type Address = Hash;
+
+/// Returns the address of the account owning the given (ed25519) public key.
+pub fn address_of(public_key: &VerifyingKey) -> Address {
+    Transaction::hash_bytes(public_key.as_bytes())
+}
 
 /// A transaction for an amount of "coin" from a sender to a receiver address.
-// TODO: there is integrity on address/account balances currently. Transactions
-//   are plain/unsigned and everyone can propose/make up amounts/txs.
+/// The spender authorizes it by signing it, see [Transaction::verify_signature].
+/// Only coinbase transactions (see [Transaction::coinbase]) need no signature.
+/// On top of the amount, the spender pays a fee to the miner including the transaction.
+/// The spender numbers their transactions with increasing nonces, so none can be replayed.
+/// A block must not spend more than the spender has, see [crate::BlockError::Overspent].
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct Transaction {
     spender: Address,
     receiver: Address,
     amount: u32,
+    /// Paid by the spender to the miner, on top of the amount.
+    fee: u32,
+    /// The spender's count of transactions including this one, i.e. `1` for their first.
+    nonce: u64,
     timestamp: u64,
+    batch: Option<Batch>,
+    /// A message of the miner, only allowed in coinbases, see [Transaction::with_data].
+    data: Vec<u8>,
+    /// The spender's public key, whose hash must be the spender address.
+    public_key: [u8; 32],
+    /// The spender's signature over all other fields.
+    #[serde(with = "BigArray")]
+    signature: [u8; 64],
+}
+
+/// Groups transactions that must be included in a block together or not at all,
+/// e.g. both legs of an atomic swap.
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct Batch {
+    /// Identifies the batch, shared by all its transactions.
+    pub id: Hash,
+    /// The number of transactions in the batch.
+    pub size: u32,
+}
+
+impl Debug for Batch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Batch {{ id: {}, size: {} }}",
+            self.id.encode(),
+            self.size
+        )
+    }
+}
+
+/// The current UNIX timestamp (in seconds).
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Current time is after unix epoch")
+        .as_secs()
 }
 
 impl Transaction {
+    /// Creates a transaction of `amount` "coin" from the spender to the receiver, created at the
+    /// given UNIX timestamp (in seconds). It pays no fee and is the spender's first transaction,
+    /// change that with [Self::with_fee] and [Self::with_nonce] before signing it with the
+    /// spender's key, see [Self::sign].
+    ///
+    /// ```
+    /// use repyh_proof_of_work::{address_of, Transaction};
+    ///
+    /// let key = Transaction::dummy_key(0);
+    /// let spender = address_of(&key.verifying_key());
+    /// let receiver = address_of(&Transaction::dummy_key(1).verifying_key());
+    /// let mut tx = Transaction::new(spender, receiver, 5, 1_700_000_000).with_fee(1);
+    /// tx.sign(&key);
+    ///
+    /// assert_eq!(tx.spender(), &spender);
+    /// assert_eq!(tx.receiver(), &receiver);
+    /// assert_eq!(tx.amount(), 5);
+    /// assert_eq!(tx.timestamp(), 1_700_000_000);
+    /// assert_eq!((tx.fee(), tx.nonce()), (1, 1));
+    /// assert!(tx.verify_signature());
+    /// ```
+    pub fn new(spender: Address, receiver: Address, amount: u32, timestamp: u64) -> Self {
+        Transaction {
+            spender,
+            receiver,
+            amount,
+            fee: 0,
+            nonce: 1,
+            timestamp,
+            batch: None,
+            data: Vec::new(),
+            public_key: [0; 32],
+            signature: [0; 64],
+        }
+    }
+
     /// Quickly, easily create the requested number of dummy transactions.
-    /// Mostly for testing purposes.
+    /// Mostly for testing purposes. They are unsigned, so no valid block includes them,
+    /// see [Self::signed_dummy_txs].
     pub fn dummy_txs(len: u32) -> Vec<Self> {
+        Self::dummy_txs_at(len, now())
+    }
+
+    /// Like [Self::dummy_txs] but at a fixed time, i.e. deterministic.
+    /// The `i`-th transaction (from 1) sends `i` "coin" from address `[i + 1; 32]` to
+    /// `[i + 2; 32]`, such that none is spent by the [MINT_ADDRESS], and has nonce `i`.
+    pub fn dummy_txs_at(len: u32, timestamp: u64) -> Vec<Self> {
         (1..=len)
             .map(|i: u32| Transaction {
-                spender: [i as u8; HASH_LENGTH],
-                receiver: [(i + 1) as u8; HASH_LENGTH],
+                spender: [(i + 1) as u8; HASH_LENGTH],
+                receiver: [(i + 2) as u8; HASH_LENGTH],
                 amount: i,
-                timestamp: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Current time is after unix epoch")
-                    .as_secs(),
+                fee: 0,
+                nonce: i as u64,
+                timestamp,
+                batch: None,
+                data: Vec::new(),
+                public_key: [0; 32],
+                signature: [0; 64],
             })
             .collect::<Vec<_>>()
     }
+
+    /// Like [Self::dummy_txs] but properly signed and funded, i.e. acceptable for nodes.
+    /// The `i`-th transaction (from 1) sends `i` "coin" from [Self::dummy_key] `0`, which
+    /// receives the [GENESIS_TX], to the one of `i`. So at most 13 of them are affordable.
+    /// Its nonce is `i`, i.e. they are the key's first transactions in order.
+    pub fn signed_dummy_txs(len: u32) -> Vec<Self> {
+        Self::signed_dummy_txs_from(0, len)
+    }
+
+    /// Like [Self::signed_dummy_txs] but spent by [Self::dummy_key] `spender`, which needs to be
+    /// funded first, e.g. by the `spender`-th of the [Self::signed_dummy_txs].
+    pub fn signed_dummy_txs_from(spender: u32, len: u32) -> Vec<Self> {
+        let timestamp = now();
+        let key = Self::dummy_key(spender);
+        (1..=len)
+            .map(|i: u32| {
+                let mut tx = Transaction {
+                    spender: address_of(&key.verifying_key()),
+                    receiver: address_of(&Self::dummy_key(i).verifying_key()),
+                    amount: i,
+                    fee: 0,
+                    nonce: i as u64,
+                    timestamp,
+                    batch: None,
+                    data: Vec::new(),
+                    public_key: [0; 32],
+                    signature: [0; 64],
+                };
+                tx.sign(&key);
+                tx
+            })
+            .collect::<Vec<_>>()
+    }
+
+    /// Creates the transaction minting `amount` "coin" for the miner of a block with the given
+    /// timestamp. It must be the first transaction of the block, see [crate::Block::is_valid].
+    /// Minting is not numbered, so its nonce is always `0`.
+    pub fn coinbase(miner: Address, amount: u32, timestamp: u64) -> Self {
+        Transaction {
+            spender: MINT_ADDRESS,
+            receiver: miner,
+            amount,
+            fee: 0,
+            nonce: 0,
+            timestamp,
+            batch: None,
+            data: Vec::new(),
+            // minting needs no signature
+            public_key: [0; 32],
+            signature: [0; 64],
+        }
+    }
+
+    /// Whether this transaction mints "coin", i.e. is spent by the [MINT_ADDRESS].
+    pub fn is_coinbase(&self) -> bool {
+        self.spender == MINT_ADDRESS
+    }
+
+    /// A well-known, deterministic signing key, only for testing purposes.
+    pub fn dummy_key(i: u32) -> SigningKey {
+        SigningKey::from_bytes(&Self::hash_bytes(&i.to_le_bytes()))
+    }
+
+    /// Signs this transaction with the spender's key.
+    /// Any later change to the transaction invalidates the signature.
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.public_key = key.verifying_key().to_bytes();
+        self.signature = key.sign(&self.signed_bytes()).to_bytes();
+    }
+
+    /// Whether this transaction is signed by the owner of the spender address.
+    pub fn verify_signature(&self) -> bool {
+        let Ok(public_key) = VerifyingKey::from_bytes(&self.public_key) else {
+            return false;
+        };
+        constant_time_eq(&address_of(&public_key), &self.spender)
+            && public_key
+                .verify_strict(
+                    &self.signed_bytes(),
+                    &Signature::from_bytes(&self.signature),
+                )
+                .is_ok()
+    }
+
+    /// The bytes covered by the signature, i.e. everything but the signature itself.
+    fn signed_bytes(&self) -> Vec<u8> {
+        self.canonical_bytes()[..TX_LENGTH - self.signature.len()].to_vec()
+    }
+
+    /// Sets the fee paid to the miner, e.g. to be mined sooner.
+    /// Like any change, this invalidates the signature, so sign the transaction afterwards.
+    pub fn with_fee(self, fee: u32) -> Self {
+        Transaction { fee, ..self }
+    }
+
+    /// Sets the nonce, i.e. numbers this transaction among the spender's ones.
+    /// Like any change, this invalidates the signature, so sign the transaction afterwards.
+    pub fn with_nonce(self, nonce: u64) -> Self {
+        Transaction { nonce, ..self }
+    }
+
+    /// Makes this transaction part of the given batch.
+    /// Like any change, this invalidates the signature, so sign the transaction afterwards.
+    pub fn with_batch(self, batch: Batch) -> Self {
+        Transaction {
+            batch: Some(batch),
+            ..self
+        }
+    }
+
+    /// Sets the message a miner puts into their coinbase (like Bitcoin's scriptSig), e.g. to tag
+    /// their blocks. Changing it also changes the block's merkle root, so it gives miners
+    /// extra-nonce space. At most [MAX_COINBASE_DATA] bytes, and only coinbases may carry any,
+    /// see [Self::has_valid_data].
+    pub fn with_data(self, data: Vec<u8>) -> Self {
+        Transaction { data, ..self }
+    }
+
+    /// The address the amount is taken from.
+    pub fn spender(&self) -> &Address {
+        &self.spender
+    }
+
+    /// The address the amount goes to.
+    pub fn receiver(&self) -> &Address {
+        &self.receiver
+    }
+
+    /// The amount of "coin" transferred.
+    pub fn amount(&self) -> u32 {
+        self.amount
+    }
+
+    /// The "coin" paid to the miner, see [Self::with_fee].
+    pub fn fee(&self) -> u32 {
+        self.fee
+    }
+
+    /// The spender's count of transactions including this one, see [crate::BlockChain::nonce_of].
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// The "coin" the spender pays in total, i.e. the amount plus the fee.
+    pub fn cost(&self) -> u64 {
+        self.amount as u64 + self.fee as u64
+    }
+
+    /// The UNIX timestamp (in seconds) at which this transaction was created.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// The batch this transaction is part of, if any.
+    pub fn batch(&self) -> Option<&Batch> {
+        self.batch.as_ref()
+    }
+
+    /// The message of the miner, see [Self::with_data]. Empty unless this is a coinbase.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Whether the [Self::data] is allowed, i.e. at most [MAX_COINBASE_DATA] bytes in a coinbase
+    /// and none otherwise.
+    pub fn has_valid_data(&self) -> bool {
+        match self.is_coinbase() {
+            true => self.data.len() <= MAX_COINBASE_DATA,
+            false => self.data.is_empty(),
+        }
+    }
+
+    /// The fixed byte layout of this transaction for implementations outside Rust ([TX_LENGTH]
+    /// bytes), with all integers little-endian:
+    /// `spender` (32) | `receiver` (32) | `amount` (u32) | `fee` (u32) | `nonce` (u64)
+    /// | `timestamp` (u64) | batch `id` (32) | batch `size` (u32) | `data` length (u8)
+    /// | `data` (64, zero-padded) | `public_key` (32) | `signature` (64).
+    /// Without a batch, its id and size are zero, as a batch of size `0` is never complete.
+    /// Only the first [MAX_COINBASE_DATA] bytes of the `data` fit, more is invalid anyway, see
+    /// [Self::has_valid_data]. Unlike the bincode encoding, no field is tagged or prefixed by
+    /// its length.
+    pub fn canonical_bytes(&self) -> [u8; TX_LENGTH] {
+        let (id, size) = (self.batch.as_ref()).map_or(([0; HASH_LENGTH], 0), |b| (b.id, b.size));
+        let data_len = self.data.len().min(MAX_COINBASE_DATA);
+        let mut data = [0; MAX_COINBASE_DATA];
+        data[..data_len].copy_from_slice(&self.data[..data_len]);
+        let bytes = [
+            &self.spender[..],
+            &self.receiver,
+            &self.amount.to_le_bytes(),
+            &self.fee.to_le_bytes(),
+            &self.nonce.to_le_bytes(),
+            &self.timestamp.to_le_bytes(),
+            &id,
+            &size.to_le_bytes(),
+            &[data_len as u8],
+            &data,
+            &self.public_key,
+            &self.signature,
+        ]
+        .concat();
+        bytes.try_into().expect("the fields add up to the length")
+    }
 }
 
 impl Debug for Transaction {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Transaction {{ spender: {}, receiver: {}, amount: {}, timestamp: {} }}",
+            "Transaction {{ spender: {}, receiver: {}, amount: {}, fee: {}, nonce: {}, timestamp: {}, batch: {:?}, data: {:?} }}",
             self.spender.encode(),
             self.receiver.encode(),
             self.amount,
-            self.timestamp
+            self.fee,
+            self.nonce,
+            self.timestamp,
+            self.batch,
+            String::from_utf8_lossy(&self.data)
         )
     }
 }
 
-/// Address used in the genesis block to mint 100 "coin".
+/// The maximum length of the message a miner puts into a coinbase, see [Transaction::with_data].
+pub const MAX_COINBASE_DATA: usize = 64;
+
+/// The length of the bytes of a transaction, see [Transaction::canonical_bytes].
+pub const TX_LENGTH: usize = 285;
+
+/// The spender of coinbase transactions, which mint new "coin", see [Transaction::coinbase].
 ///
-/// The idea would be to forbid this address for public use and take it as "origin" for
-/// newly minted "coin" in future implementations of "coinbase" transactions.
+/// No key is known to hash to this address, so nobody can sign a transaction spending from it.
 pub const MINT_ADDRESS: Address = [1; HASH_LENGTH];
 
+/// The address test blocks pay their reward to.
+#[cfg(test)]
+pub(crate) const DUMMY_MINER: Address = [0; HASH_LENGTH];
+
 /// UNIX timestamp of UTC 2024/02/10 00:00:00
 pub const GENESIS_TIME: u64 = 1707519600;
 
-/// The only transaction in the genesis block of this chain.
-/// Grants 100 "coin" to the address `0x100000...`
+/// The address of [Transaction::dummy_key] `0`, which the main network's genesis block mints
+/// for, such that there is some "coin" to spend in tests and from the client.
+pub(crate) const GENESIS_ADDRESS: Address = [
+    164, 178, 109, 172, 241, 163, 203, 235, 47, 81, 171, 193, 88, 181, 18, 178, 197, 191, 164, 201,
+    40, 14, 119, 37, 106, 46, 140, 57, 140, 178, 20, 176,
+];
+
+/// The only transaction in the genesis block of the main network, i.e. its coinbase.
+/// Grants the block reward of 100 "coin" to the [GENESIS_ADDRESS].
 pub const GENESIS_TX: Transaction = Transaction {
     spender: MINT_ADDRESS,
-    receiver: [
-        1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0,
-    ],
+    receiver: GENESIS_ADDRESS,
     amount: 100,
+    fee: 0,
+    nonce: 0,
     timestamp: GENESIS_TIME,
+    batch: None,
+    data: Vec::new(),
+    // minting needs no signature
+    public_key: [0; 32],
+    signature: [0; 64],
 };
 
 impl Hashable for Transaction {
     fn hash(&self) -> Hash {
-        Self::hash_bytes(&bincode::serialize(self).unwrap())
+        #[cfg(feature = "canonical")]
+        let bytes = self.canonical_bytes();
+        #[cfg(not(feature = "canonical"))]
+        let bytes = bincode::serialize(self).unwrap();
+        Self::hash_bytes(&bytes)
+    }
+}
+
+/// A raw transaction, i.e. the hex of its bytes on the wire.
+impl HexEncode for Transaction {
+    fn to_hex(&self) -> String {
+        wire_to_hex(self)
+    }
+
+    fn from_hex(s: &str) -> Result<Self, HexError> {
+        wire_from_hex(s)
     }
 }
 
@@ -84,15 +428,41 @@ impl Hashable for Transaction {
 pub struct Transactions(pub Vec<Transaction>);
 
 impl Transactions {
-    pub fn genesis() -> Self {
-        Transactions(vec![GENESIS_TX])
+    /// The transactions of the genesis block with the given config, i.e. only its coinbase,
+    /// see [GENESIS_TX].
+    pub fn genesis(config: &GenesisConfig) -> Self {
+        let coinbase =
+            Transaction::coinbase(config.mint_address, block_reward(0), config.timestamp);
+        Transactions(vec![coinbase])
+    }
+
+    /// Checks that every batch is either contained completely or not at all.
+    pub fn has_complete_batches(&self) -> bool {
+        let mut batches: HashMap<Hash, (u32, u32)> = HashMap::new();
+        for batch in self.0.iter().filter_map(Transaction::batch) {
+            let (size, count) = batches.entry(batch.id).or_insert((batch.size, 0));
+            if *size != batch.size {
+                return false;
+            }
+            *count += 1;
+        }
+        batches.values().all(|(size, count)| size == count)
     }
 }
 
-/// The merkle tree hash of [`Transactions::genesis()`] for inclusion in the genesis block.
-pub const GENESIS_TXS_HASH: Hash = [
-    82, 124, 234, 124, 91, 88, 141, 159, 176, 214, 86, 126, 142, 46, 16, 73, 125, 96, 127, 71, 253,
-    45, 55, 100, 237, 182, 18, 51, 33, 131, 181, 243,
+/// The merkle tree hash of the main network's [`Transactions::genesis()`], see
+/// [crate::block::GENESIS_HEADER].
+#[cfg(test)]
+#[cfg(not(feature = "canonical"))]
+pub(crate) const GENESIS_TXS_HASH: Hash = [
+    35, 47, 179, 6, 253, 177, 180, 252, 199, 193, 100, 143, 93, 157, 20, 87, 216, 40, 92, 202, 136,
+    217, 226, 112, 252, 68, 181, 238, 242, 168, 244, 99,
+];
+#[cfg(test)]
+#[cfg(feature = "canonical")]
+pub(crate) const GENESIS_TXS_HASH: Hash = [
+    11, 219, 152, 226, 242, 122, 234, 11, 234, 185, 109, 211, 149, 42, 129, 15, 195, 235, 108, 137,
+    22, 247, 159, 137, 41, 54, 54, 196, 110, 144, 98, 37,
 ];
 
 /// Merkle tree hashing implementation as per Bitcoin:
@@ -100,33 +470,176 @@ pub const GENESIS_TXS_HASH: Hash = [
 ///  - hashes of leaves are concatenated and rehashed
 ///  - the last transaction is hashed twice and concatenated if the number is odd
 #[inline]
-fn hash(txs: &[Transaction]) -> Hash {
-    match txs {
+pub(crate) fn merkle_root(leaves: &[Hash]) -> Hash {
+    match leaves {
         // leaf of tree with two txs => concat their hashes and hash
-        [tx1, tx2] => Transaction::hash_bytes(&[tx1.hash(), tx2.hash()].concat()),
+        [h1, h2] => Transaction::hash_bytes(&[*h1, *h2].concat()),
         // leaf with single tx, i.e. last tx => concat hash with itself
-        [tx] => {
-            let hash = tx.hash();
-            Transaction::hash_bytes(&[hash, hash].concat())
-        }
+        [h] => Transaction::hash_bytes(&[*h, *h].concat()),
         [] => panic!("cannot hash an empty merkle tree"),
-        more_txs => {
-            let (a, b) = more_txs.split_at(more_txs.len() / 2);
-            Transaction::hash_bytes(&[hash(a), hash(b)].concat())
+        more_leaves => {
+            let (a, b) = more_leaves.split_at(more_leaves.len() / 2);
+            Transaction::hash_bytes(&[merkle_root(a), merkle_root(b)].concat())
         }
     }
 }
 
+/// The merkle root of the transactions.
+///
+/// Panics if there are none, so callers must guarantee there is at least one, e.g. blocks
+/// always contain their coinbase (see [crate::Block::is_valid]). Use a [MerkleAccumulator] to
+/// handle no transactions.
 impl Hashable for Transactions {
     fn hash(&self) -> Hash {
-        hash(&self.0)
+        merkle_root(&self.0.iter().map(|t| t.hash()).collect::<Vec<_>>())
+    }
+}
+
+/// Returns the sibling hashes on the path from the leaf at `index` up to the merkle root,
+/// each with whether the sibling is on the left. The caller guarantees `index < leaves.len()`.
+fn merkle_proof(leaves: &[Hash], index: usize) -> Vec<(Hash, bool)> {
+    match leaves {
+        // the single last leaf is concatenated with itself
+        [h] => vec![(*h, false)],
+        [h1, h2] if index == 0 => vec![(*h2, false)],
+        [h1, _] => vec![(*h1, true)],
+        more_leaves => {
+            let (a, b) = more_leaves.split_at(more_leaves.len() / 2);
+            let (mut proof, sibling) = if index < a.len() {
+                (merkle_proof(a, index), (merkle_root(b), false))
+            } else {
+                (merkle_proof(b, index - a.len()), (merkle_root(a), true))
+            };
+            proof.push(sibling);
+            proof
+        }
+    }
+}
+
+/// Checks a merkle inclusion proof, i.e. that hashing the `leaf` up the tree with the
+/// sibling hashes of the `proof` (each with whether it is on the left) results in `root`.
+pub fn verify_merkle_proof(leaf: Hash, proof: &[(Hash, bool)], root: Hash) -> bool {
+    let computed = proof.iter().fold(leaf, |hash, (sibling, is_left)| {
+        if *is_left {
+            Transaction::hash_bytes(&[*sibling, hash].concat())
+        } else {
+            Transaction::hash_bytes(&[hash, *sibling].concat())
+        }
+    });
+    !proof.is_empty() && computed == root
+}
+
+/// Builds the merkle tree of a growing sequence of transactions, e.g. of a block template.
+///
+/// Pushing only hashes the new transaction. The root and proofs are then computed from the
+/// cached transaction hashes without serializing and hashing all transactions again.
+#[derive(Clone, Debug, Default)]
+pub struct MerkleAccumulator {
+    leaves: Vec<Hash>,
+}
+
+impl MerkleAccumulator {
+    pub fn new() -> Self {
+        MerkleAccumulator::default()
+    }
+
+    pub fn push(&mut self, tx: &Transaction) {
+        self.leaves.push(tx.hash());
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// The merkle root of all transactions pushed so far, if any.
+    pub fn root(&self) -> Option<Hash> {
+        (!self.is_empty()).then(|| merkle_root(&self.leaves))
+    }
+
+    /// The inclusion proof for the transaction pushed at `index`, see [verify_merkle_proof].
+    pub fn proof(&self, index: usize) -> Option<Vec<(Hash, bool)>> {
+        (index < self.len()).then(|| merkle_proof(&self.leaves, index))
+    }
+}
+
+/// Order-independent commitment to a set of transactions given by their hashes, i.e. the
+/// merkle root of the sorted hashes. The empty set commits to the zero hash.
+pub fn merkle_commitment<I: IntoIterator<Item = Hash>>(hashes: I) -> Hash {
+    let mut hashes = hashes.into_iter().collect::<Vec<_>>();
+    if hashes.is_empty() {
+        return [0; HASH_LENGTH];
+    }
+    hashes.sort_unstable();
+    merkle_root(&hashes)
+}
+
+impl Transactions {
+    /// Checks that these transactions are exactly the ones committed to by `commitment`,
+    /// see [merkle_commitment]. This lets e.g. a light client verify a node's mempool.
+    pub fn verify_commitment(&self, commitment: &Hash) -> bool {
+        merkle_commitment(self.0.iter().map(|t| t.hash())) == *commitment
+    }
+
+    /// The inclusion proof for the transaction at `index` against [Self::hash], i.e. the
+    /// merkle root in the block header. See [verify_merkle_proof].
+    pub fn merkle_proof(&self, index: usize) -> Option<Vec<(Hash, bool)>> {
+        let leaves = self.0.iter().map(|t| t.hash()).collect::<Vec<_>>();
+        (index < leaves.len()).then(|| merkle_proof(&leaves, index))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::hash::{Hashable, HASH_LENGTH};
-    use crate::tx::{Transaction, Transactions, GENESIS_TXS_HASH};
+    use crate::hash::{bytes_to_hex, Hashable, HASH_LENGTH};
+    use crate::params::ChainParams;
+    use crate::tx::{
+        merkle_commitment, verify_merkle_proof, Batch, MerkleAccumulator, Transaction,
+        Transactions, GENESIS_TIME, GENESIS_TX, GENESIS_TXS_HASH, MAX_COINBASE_DATA, TX_LENGTH,
+    };
+
+    #[test]
+    fn canonical_tx_bytes() {
+        let tx = (Transaction::dummy_txs_at(1, GENESIS_TIME).remove(0))
+            .with_fee(5)
+            .with_batch(Batch {
+                id: [3; HASH_LENGTH],
+                size: 2,
+            });
+        let expected = concat!(
+            "0202020202020202020202020202020202020202020202020202020202020202", // spender
+            "0303030303030303030303030303030303030303030303030303030303030303", // receiver
+            "01000000",                                                         // amount
+            "05000000",                                                         // fee
+            "0100000000000000",                                                 // nonce
+            "70aec66500000000",                                                 // timestamp
+            "0303030303030303030303030303030303030303030303030303030303030303", // batch id
+            "02000000",                                                         // batch size
+        );
+        // no data, public key or signature
+        let expected = expected.to_owned() + &"00".repeat(1 + MAX_COINBASE_DATA + 32 + 64);
+        assert_eq!(bytes_to_hex(&tx.canonical_bytes()), expected);
+        assert_ne!(tx.canonical_bytes()[..], bincode::serialize(&tx).unwrap());
+
+        // no batch is all zeros
+        let unbatched = Transaction::dummy_txs_at(1, GENESIS_TIME).remove(0);
+        let batch = HASH_LENGTH * 2 + 24..HASH_LENGTH * 3 + 28;
+        assert!(unbatched.canonical_bytes()[batch].iter().all(|b| *b == 0));
+
+        // a coinbase's message is padded, after its length
+        let tagged = GENESIS_TX.with_data(b"hi".to_vec());
+        let bytes = tagged.canonical_bytes();
+        let data = HASH_LENGTH * 3 + 29;
+        assert_eq!(bytes.len(), TX_LENGTH);
+        assert_eq!(bytes[data - 1], 2);
+        assert_eq!(&bytes[data..data + 2], b"hi");
+        assert!(bytes[data + 2..data + MAX_COINBASE_DATA]
+            .iter()
+            .all(|b| *b == 0));
+    }
 
     #[test]
     #[should_panic]
@@ -140,7 +653,13 @@ mod test {
             spender: [0; HASH_LENGTH],
             receiver: [1; HASH_LENGTH],
             amount: 100,
+            fee: 0,
+            nonce: 1,
             timestamp: 1,
+            batch: None,
+            data: Vec::new(),
+            public_key: [0; 32],
+            signature: [0; 64],
         };
         assert_eq!(
             Transactions(vec![tx.clone(), tx.clone()]).hash(),
@@ -148,13 +667,99 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_signature() {
+        let txs = Transaction::signed_dummy_txs(3);
+        assert!(txs.iter().all(|tx| tx.verify_signature()));
+        assert_eq!(txs[1].spender(), GENESIS_TX.receiver());
+        assert!(!Transaction::dummy_txs(1)[0].verify_signature());
+
+        let mut tampered = txs[0].clone();
+        tampered.amount += 1;
+        assert!(!tampered.verify_signature());
+
+        // signing with another key than the spender's doesn't help
+        tampered.sign(&Transaction::dummy_key(2));
+        assert!(!tampered.verify_signature());
+        tampered.sign(&Transaction::dummy_key(0));
+        assert!(tampered.verify_signature());
+    }
+
     #[test]
     fn test_genesis() {
-        assert_eq!(Transactions::genesis().hash(), GENESIS_TXS_HASH);
+        let genesis = Transactions::genesis(&ChainParams::MAINNET.genesis);
+        assert_eq!(genesis.0, vec![GENESIS_TX]);
+        assert_eq!(genesis.hash(), GENESIS_TXS_HASH);
     }
 
     #[test]
     fn test_many() {
         Transactions(Transaction::dummy_txs(10000)).hash();
     }
+
+    #[test]
+    fn test_commitment() {
+        let txs = Transaction::dummy_txs(5);
+        let commitment = merkle_commitment(txs.iter().map(|t| t.hash()));
+        assert!(Transactions(txs.iter().rev().cloned().collect()).verify_commitment(&commitment));
+        assert!(Transactions(vec![]).verify_commitment(&merkle_commitment([])));
+
+        let mut tampered = txs.clone();
+        tampered[2].amount += 1;
+        assert!(!Transactions(tampered).verify_commitment(&commitment));
+        assert!(!Transactions(txs[1..].to_vec()).verify_commitment(&commitment));
+    }
+
+    #[test]
+    fn test_complete_batches() {
+        let batch = Batch {
+            id: [3; HASH_LENGTH],
+            size: 3,
+        };
+        let mut txs: Vec<Transaction> = Transaction::dummy_txs(5);
+        for tx in txs.iter_mut().take(3) {
+            *tx = tx.clone().with_batch(batch);
+        }
+        assert!(Transactions(txs.clone()).has_complete_batches());
+        assert!(Transactions(txs[3..].to_vec()).has_complete_batches());
+        assert!(!Transactions(txs[1..].to_vec()).has_complete_batches());
+
+        // all transactions of a batch must agree on its size
+        txs[0] = txs[0].clone().with_batch(Batch { size: 2, ..batch });
+        assert!(!Transactions(txs).has_complete_batches());
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let mut txs = Transaction::dummy_txs(14);
+        let outsider = txs.pop().unwrap();
+        let mut accumulator = MerkleAccumulator::new();
+        assert_eq!(accumulator.root(), None);
+        for n in 1..=txs.len() {
+            accumulator.push(&txs[n - 1]);
+            let root = Transactions(txs[..n].to_vec()).hash();
+            assert_eq!(accumulator.root(), Some(root));
+            for (i, tx) in txs[..n].iter().enumerate() {
+                let proof = accumulator.proof(i).unwrap();
+                assert!(verify_merkle_proof(tx.hash(), &proof, root));
+                assert!(!verify_merkle_proof(outsider.hash(), &proof, root));
+            }
+            assert_eq!(accumulator.proof(n), None);
+        }
+    }
+
+    #[test]
+    fn transactions_merkle_proof() {
+        let txs = Transactions(Transaction::dummy_txs(5));
+        let root = txs.hash();
+        for (i, tx) in txs.0.iter().enumerate() {
+            let proof = txs.merkle_proof(i).unwrap();
+            assert!(verify_merkle_proof(tx.hash(), &proof, root));
+            assert!(!verify_merkle_proof(tx.hash(), &proof, GENESIS_TXS_HASH));
+        }
+        // splitting 5 leaves into 2 + (1 + 2) leaves the third transaction paired with itself
+        let odd = txs.0[2].hash();
+        assert_eq!(txs.merkle_proof(2).unwrap()[0], (odd, false));
+        assert_eq!(txs.merkle_proof(5), None);
+    }
 }
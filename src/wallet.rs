@@ -0,0 +1,131 @@
+use crate::tx::now;
+use crate::{address_of, Address, Block, Hashable, Transaction};
+use ed25519_dalek::SigningKey;
+
+/// A key along with the balance and nonce of its address, as followed from the blocks
+/// committed to the main chain, see [Wallet::apply_block]. Blocks taken off the main chain by a
+/// reorganisation are not undone, so after one, the wallet needs to start over.
+pub struct Wallet {
+    key: SigningKey,
+    address: Address,
+    /// The committed balance
+    balance: u64,
+    /// The nonce of the last committed transaction spending from the address
+    nonce: u64,
+    /// The transactions sent but not yet committed, in the order of their nonces
+    pending: Vec<Transaction>,
+}
+
+impl Wallet {
+    /// Creates a wallet for the given key, starting without any "coin".
+    pub fn new(key: SigningKey) -> Self {
+        Wallet {
+            address: address_of(&key.verifying_key()),
+            key,
+            balance: 0,
+            nonce: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The address receiving and spending this wallet's "coin".
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// The committed balance, regardless of the pending transactions.
+    pub fn balance(&self) -> u64 {
+        self.balance
+    }
+
+    /// The balance left to spend, i.e. the committed one without the pending transactions.
+    pub fn spendable(&self) -> u64 {
+        let pending: u64 = self.pending.iter().map(Transaction::cost).sum();
+        self.balance.saturating_sub(pending)
+    }
+
+    /// Follows a block committed to the main chain, which must be the one after the last
+    /// applied block: credits what the address received and debits what it spent.
+    pub fn apply_block(&mut self, block: &Block) {
+        for tx in &block.transactions.0 {
+            if tx.receiver() == &self.address {
+                self.balance += tx.amount() as u64;
+            }
+            if tx.spender() == &self.address && !tx.is_coinbase() {
+                self.balance = self.balance.saturating_sub(tx.cost());
+                self.nonce = self.nonce.max(tx.nonce());
+            }
+        }
+        // also drops pending transactions replaced by another one with their nonce
+        let nonce = self.nonce;
+        self.pending.retain(|tx| tx.nonce() > nonce);
+    }
+
+    /// Builds a signed transaction sending the amount to the receiver and paying the fee, with
+    /// the address's next nonce. Returns `None` if the wallet can't afford it on top of the
+    /// pending transactions.
+    pub fn send(&mut self, receiver: Address, amount: u32, fee: u32) -> Option<Transaction> {
+        let nonce = self.pending.last().map_or(self.nonce, Transaction::nonce) + 1;
+        let mut tx = Transaction::new(self.address, receiver, amount, now())
+            .with_fee(fee)
+            .with_nonce(nonce);
+        if tx.cost() > self.spendable() {
+            return None;
+        }
+        tx.sign(&self.key);
+        self.pending.push(tx.clone());
+        Some(tx)
+    }
+
+    /// Whether the transaction was sent by this wallet but isn't committed yet.
+    pub fn is_pending(&self, tx: &Transaction) -> bool {
+        let hash = tx.hash();
+        self.pending.iter().any(|pending| pending.hash() == hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::chain::BlockChain;
+    use crate::params::ChainParams;
+    use crate::tx::DUMMY_MINER;
+    use crate::wallet::Wallet;
+    use crate::{address_of, Block, Hashable, Transaction, Transactions};
+
+    #[test]
+    fn wallet_follows_chain() {
+        let mut chain = BlockChain::with_params(ChainParams::TESTING);
+        let mut wallet = Wallet::new(Transaction::dummy_key(4));
+        let receiver = address_of(&Transaction::dummy_key(1).verifying_key());
+        let mine = |chain: &mut BlockChain, txs: Vec<Transaction>| {
+            let tip = chain.highest_block();
+            let time = tip.header.timestamp() + 1;
+            let block = Block::mine_at(tip.hash(), 0, Transactions(txs), DUMMY_MINER, time);
+            chain.add_block(&block).unwrap();
+            block
+        };
+
+        // the fourth dummy transaction funds the wallet with 4 "coin"
+        let funding = mine(&mut chain, Transaction::signed_dummy_txs(4));
+        wallet.apply_block(&funding);
+        assert_eq!(wallet.balance(), 4);
+        assert_eq!(wallet.balance(), chain.balance_of(wallet.address()) as u64);
+
+        let first = wallet.send(receiver, 2, 1).unwrap();
+        assert_eq!(first.nonce(), 1);
+        assert!(first.verify_signature());
+        assert_eq!(wallet.spendable(), 1);
+        // the pending transaction is accounted for
+        assert!(wallet.send(receiver, 1, 1).is_none());
+        let second = wallet.send(receiver, 1, 0).unwrap();
+        assert_eq!(second.nonce(), 2);
+        assert_eq!(wallet.spendable(), 0);
+
+        let spending = mine(&mut chain, vec![first.clone(), second.clone()]);
+        wallet.apply_block(&spending);
+        assert!(!wallet.is_pending(&first) && !wallet.is_pending(&second));
+        assert_eq!(wallet.balance(), 0);
+        assert_eq!(wallet.balance(), chain.balance_of(wallet.address()) as u64);
+        assert_eq!(chain.nonce_of(wallet.address()), 2);
+    }
+}